@@ -1,45 +1,342 @@
-use anyhow::anyhow;
-use futures::StreamExt;
-use reqwest::Client;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use reqwest::header::{HeaderMap, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_RANGE, LAST_MODIFIED, LOCATION, RANGE};
+use reqwest::redirect::Policy;
+use reqwest::{Client, Response, StatusCode, Url};
 use tokio::fs::OpenOptions;
 use tokio::io::AsyncWriteExt;
-use tokio_util::io::ReaderStream;
-
-pub async fn download_image(url: &str, file_path: &str) -> Result<(), anyhow::Error> {
-    // Create a reqwest Client
-    let client = Client::new();
-
-    // Send a GET request to the image URL
-    match client.get(url).send().await{
-        Ok(response) => {
-            // Ensure the response is successful (status code in the 200 range)
-            if !response.status().is_success() {
-                return Err(anyhow!(
-                    format!("Failed to download image: {}", response.status()),
-                ));
-            }
 
-            // Create a File to save the image data
-            let mut file = OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(file_path)
-                .await?;
-
-            // Create a ReaderStream from the response body
-            let bytes = response.bytes().await?;
-            let mut stream = ReaderStream::new(bytes.as_ref());
-            // Copy the stream data to the file
-            while let Some(chunk) = stream.next().await {
-                let chunk = chunk?;
-                file.write_all(&chunk).await?;
+use crate::ApiError;
+
+const DEFAULT_DOWNLOAD_MAX_BYTES: u64 = 20 * 1024 * 1024;
+const DEFAULT_DOWNLOAD_CONTENT_TYPES: &str = "image/";
+const DEFAULT_DOWNLOAD_TIMEOUT_SECS: u64 = 120;
+const DEFAULT_DOWNLOAD_MAX_REDIRECTS: usize = 5;
+const MAX_FILE_NAME_LEN: usize = 128;
+
+/// Limits applied to every remote download.
+#[derive(Debug, Clone)]
+pub struct DownloadConfig {
+    pub max_bytes: u64,
+    /// Accepted `Content-Type`s; an entry ending in `/` accepts the whole family, e.g. `image/`.
+    pub content_types: Vec<String>,
+    pub timeout: Duration,
+    pub max_redirects: usize,
+    /// Lets downloads reach loopback and private networks, for local development only.
+    pub allow_private: bool,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        DownloadConfig {
+            max_bytes: DEFAULT_DOWNLOAD_MAX_BYTES,
+            content_types: vec![DEFAULT_DOWNLOAD_CONTENT_TYPES.to_string()],
+            timeout: Duration::from_secs(DEFAULT_DOWNLOAD_TIMEOUT_SECS),
+            max_redirects: DEFAULT_DOWNLOAD_MAX_REDIRECTS,
+            allow_private: false,
+        }
+    }
+}
+
+impl DownloadConfig {
+    /// `DOWNLOAD_CONTENT_TYPES` takes a list like `image/,application/pdf`.
+    pub fn from_env() -> Self {
+        let mut config = DownloadConfig::default();
+
+        if let Some(bytes) = std::env::var("DOWNLOAD_MAX_BYTES").ok().and_then(|s| s.trim().parse::<u64>().ok()) {
+            config.max_bytes = bytes;
+        }
+        if let Ok(types) = std::env::var("DOWNLOAD_CONTENT_TYPES") {
+            config.content_types =
+                types.split(',').map(|t| t.trim().to_lowercase()).filter(|t| !t.is_empty()).collect();
+        }
+        if let Some(secs) = std::env::var("DOWNLOAD_TIMEOUT_SECS").ok().and_then(|s| s.trim().parse::<u64>().ok()) {
+            config.timeout = Duration::from_secs(secs);
+        }
+        if let Some(hops) = std::env::var("DOWNLOAD_MAX_REDIRECTS").ok().and_then(|s| s.trim().parse::<usize>().ok()) {
+            config.max_redirects = hops;
+        }
+        if let Ok(allow) = std::env::var("DOWNLOAD_ALLOW_PRIVATE") {
+            config.allow_private = allow == "1" || allow.eq_ignore_ascii_case("true");
+        }
+
+        config
+    }
+
+    fn accepts(&self, content_type: &str) -> bool {
+        let essence = content_type.split(';').next().unwrap_or_default().trim().to_lowercase();
+        self.content_types.iter().any(|allowed| {
+            if allowed.ends_with('/') {
+                essence.starts_with(allowed.as_str())
+            } else {
+                essence == *allowed
             }
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum DownloadError {
+    InvalidUrl(String),
+    InvalidFileName(String),
+    ForbiddenAddress(String),
+    TooLarge { max: u64 },
+    UnsupportedType(String),
+    Upstream(String),
+}
+
+impl ApiError for DownloadError {
+    fn status_code(&self) -> u16 {
+        match self {
+            DownloadError::InvalidUrl(_) | DownloadError::InvalidFileName(_) => 400,
+            DownloadError::ForbiddenAddress(_) => 403,
+            DownloadError::TooLarge { .. } => 413,
+            DownloadError::UnsupportedType(_) => 415,
+            DownloadError::Upstream(_) => 502,
+        }
+    }
+
+    fn error_code(&self) -> &'static str {
+        match self {
+            DownloadError::InvalidUrl(_) => "invalid_url",
+            DownloadError::InvalidFileName(_) => "invalid_file_name",
+            DownloadError::ForbiddenAddress(_) => "forbidden_address",
+            DownloadError::TooLarge { .. } => "download_too_large",
+            DownloadError::UnsupportedType(_) => "unsupported_content_type",
+            DownloadError::Upstream(_) => "download_failed",
+        }
+    }
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadError::InvalidUrl(url) => write!(f, "invalid download url: {}", url),
+            DownloadError::InvalidFileName(name) => write!(f, "invalid file name: {}", name),
+            DownloadError::ForbiddenAddress(host) => write!(f, "refusing to download from {}", host),
+            DownloadError::TooLarge { max } => write!(f, "download is larger than {} bytes", max),
+            DownloadError::UnsupportedType(content_type) => write!(f, "unsupported content type: {}", content_type),
+            DownloadError::Upstream(message) => write!(f, "download failed: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for DownloadError {}
+
+/// Reduces a client supplied name to a single plain file name, dropping any directories.
+pub fn safe_file_name(name: &str) -> Result<String, DownloadError> {
+    let last = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let cleaned: String = last
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    let cleaned = cleaned.trim_start_matches('.');
+
+    if cleaned.is_empty() || cleaned.len() > MAX_FILE_NAME_LEN {
+        return Err(DownloadError::InvalidFileName(name.to_string()));
+    }
+
+    Ok(cleaned.to_string())
+}
+
+/// Addresses on the public internet; loopback, private, link-local and reserved ranges are not.
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(mapped) => is_public_ipv4(mapped),
+            None => is_public_ipv6(ip),
         },
-        Err(e) => {
-            return Err(anyhow!(format!("Failed to send request: {}", e)));
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    let shared = a == 100 && (64..128).contains(&b);
+    let benchmarking = a == 198 && (b == 18 || b == 19);
+    let reserved = a == 0 || a >= 240;
+
+    !(ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || shared
+        || benchmarking
+        || reserved)
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    let unique_local = first & 0xfe00 == 0xfc00;
+    let link_local = first & 0xffc0 == 0xfe80;
+    let documentation = first == 0x2001 && ip.segments()[1] == 0x0db8;
+
+    !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || unique_local || link_local || documentation)
+}
+
+/// Resolves the host of `url` and checks every address it points at.
+async fn resolve(url: &Url, config: &DownloadConfig) -> Result<(String, Vec<SocketAddr>), DownloadError> {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(DownloadError::InvalidUrl(url.to_string()));
+    }
+    let host = url.host_str().ok_or_else(|| DownloadError::InvalidUrl(url.to_string()))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), port))
+        .await
+        .map_err(|e| DownloadError::Upstream(format!("resolve {}: {}", host, e)))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(DownloadError::Upstream(format!("resolve {}: no address", host)));
+    }
+    if !config.allow_private && addrs.iter().any(|addr| !is_public_ip(addr.ip())) {
+        return Err(DownloadError::ForbiddenAddress(host));
+    }
+
+    Ok((host, addrs))
+}
+
+/// Sends the request to the addresses `resolve` checked, so the host can not be re-resolved elsewhere,
+/// following redirects by hand to check every hop the same way.
+async fn fetch(url: &str, offset: u64, validator: Option<&str>, config: &DownloadConfig) -> Result<Response, DownloadError> {
+    let mut url = Url::parse(url).map_err(|_| DownloadError::InvalidUrl(url.to_string()))?;
+
+    for _ in 0..=config.max_redirects {
+        let (host, addrs) = resolve(&url, config).await?;
+        let client = Client::builder()
+            .redirect(Policy::none())
+            .timeout(config.timeout)
+            .resolve_to_addrs(&host, &addrs)
+            .build()
+            .map_err(|e| DownloadError::Upstream(e.to_string()))?;
+
+        let mut request = client.get(url.clone());
+        if let Some(validator) = validator.filter(|_| offset > 0) {
+            request = request.header(RANGE, format!("bytes={}-", offset)).header(IF_RANGE, validator);
+        }
+        let response = request.send().await.map_err(|e| DownloadError::Upstream(e.to_string()))?;
+        if !response.status().is_redirection() {
+            return Ok(response);
+        }
+
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .ok_or_else(|| DownloadError::Upstream(format!("redirect without location: {}", response.status())))?;
+        url = url.join(location).map_err(|_| DownloadError::InvalidUrl(location.to_string()))?;
+    }
+
+    Err(DownloadError::Upstream(format!("more than {} redirects", config.max_redirects)))
+}
+
+/// Where the validator (`ETag` or `Last-Modified`) of the response a partial file came from is kept.
+pub fn validator_path(file_path: &Path) -> PathBuf {
+    let mut path = file_path.as_os_str().to_owned();
+    path.push(".validator");
+    PathBuf::from(path)
+}
+
+/// A strong `ETag`, or else `Last-Modified`; weak tags can not be used in `If-Range`.
+fn response_validator(headers: &HeaderMap) -> Option<String> {
+    let etag = headers.get(ETAG).and_then(|v| v.to_str().ok()).filter(|v| !v.starts_with("W/"));
+    etag.or_else(|| headers.get(LAST_MODIFIED).and_then(|v| v.to_str().ok())).map(|v| v.to_string())
+}
+
+async fn remove_partial(file_path: &Path) {
+    let _ = tokio::fs::remove_file(file_path).await;
+    let _ = tokio::fs::remove_file(validator_path(file_path)).await;
+}
+
+/// Streams `url` into `file_path`, returning the file size. A partial file left by an interrupted download is
+/// resumed with a `Range` request guarded by `If-Range` with the validator stored next to it, so a changed
+/// resource comes back whole and the download restarts from zero. A partial file without a validator is
+/// downloaded again; one that went over the size limit is removed.
+pub async fn download_file(url: &str, file_path: &Path, config: &DownloadConfig) -> Result<u64, anyhow::Error> {
+    let existing = tokio::fs::metadata(file_path).await.map(|m| m.len()).unwrap_or(0);
+    if existing > config.max_bytes {
+        remove_partial(file_path).await;
+        return Err(DownloadError::TooLarge { max: config.max_bytes }.into());
+    }
+    let validator = match existing {
+        0 => None,
+        _ => tokio::fs::read_to_string(validator_path(file_path)).await.ok().filter(|v| !v.is_empty()),
+    };
+    let existing = if validator.is_some() { existing } else { 0 };
+
+    let mut response = fetch(url, existing, validator.as_deref(), config).await?;
+
+    let offset = match response.status() {
+        StatusCode::PARTIAL_CONTENT if existing > 0 => {
+            let expected = format!("bytes {}-", existing);
+            let range = response.headers().get(CONTENT_RANGE).and_then(|r| r.to_str().ok()).unwrap_or_default();
+            if !range.starts_with(&expected) {
+                remove_partial(file_path).await;
+                return Err(DownloadError::Upstream(format!("unexpected content range: {}", range)).into());
+            }
+            existing
+        }
+        StatusCode::RANGE_NOT_SATISFIABLE if existing > 0 => {
+            // The partial file already holds the whole body.
+            let range = response.headers().get(CONTENT_RANGE).and_then(|r| r.to_str().ok()).unwrap_or_default();
+            if range == format!("bytes */{}", existing) {
+                let _ = tokio::fs::remove_file(validator_path(file_path)).await;
+                return Ok(existing);
+            }
+            remove_partial(file_path).await;
+            return Err(DownloadError::Upstream(format!("range not satisfiable: {}", range)).into());
+        }
+        status if status.is_success() => 0,
+        status => return Err(DownloadError::Upstream(format!("status {}", status)).into()),
+    };
+
+    let content_type = response.headers().get(CONTENT_TYPE).and_then(|t| t.to_str().ok()).unwrap_or_default();
+    if !config.accepts(content_type) {
+        return Err(DownloadError::UnsupportedType(content_type.to_string()).into());
+    }
+    if let Some(length) = response.content_length() {
+        if offset + length > config.max_bytes {
+            remove_partial(file_path).await;
+            return Err(DownloadError::TooLarge { max: config.max_bytes }.into());
+        }
+    }
+
+    // A 200 replaces the partial file, so it also replaces its validator.
+    match response_validator(response.headers()) {
+        Some(validator) => tokio::fs::write(validator_path(file_path), validator).await?,
+        None => {
+            let _ = tokio::fs::remove_file(validator_path(file_path)).await;
         }
     }
 
-    Ok(())
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(offset > 0)
+        .truncate(offset == 0)
+        .open(file_path)
+        .await?;
+
+    let mut size = offset;
+    while let Some(chunk) = response.chunk().await.map_err(|e| DownloadError::Upstream(e.to_string()))? {
+        size += chunk.len() as u64;
+        if size > config.max_bytes {
+            drop(file);
+            remove_partial(file_path).await;
+            return Err(DownloadError::TooLarge { max: config.max_bytes }.into());
+        }
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    let _ = tokio::fs::remove_file(validator_path(file_path)).await;
+
+    Ok(size)
 }
+
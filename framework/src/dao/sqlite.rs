@@ -5,6 +5,7 @@ use std::{collections::HashMap, path::Path, str};
 
 use anyhow::Error;
 use rusqlite::{types::ValueRef, Connection, Result};
+pub use rusqlite::ToSql;
 
 use crate::log;
 
@@ -63,11 +64,21 @@ impl MetapowerSqlite3 {
     
         Ok(last_id)
     }
+    /// Runs a statement and returns the number of rows it changed.
+    pub fn execute(&self, sql: &str, parameters: &[&dyn rusqlite::ToSql]) -> Result<usize> {
+        let conn = Connection::open(Path::new(&self.db_file))?;
+
+        conn.execute(sql, parameters)
+    }
     pub fn query_db(db_name: &str, sql: &str, columns: Vec<&str>) -> Result<Vec<HashMap::<String, String>>> {
+        MetapowerSqlite3::new(db_name.to_string()).query(sql, &[], columns)
+    }
+    /// Like `query_db`, with `?N` placeholders bound to `parameters`.
+    pub fn query(&self, sql: &str, parameters: &[&dyn rusqlite::ToSql], columns: Vec<&str>) -> Result<Vec<HashMap::<String, String>>> {
         log!("sql: {}", sql);
-        let conn = Connection::open(db_name)?;
+        let conn = Connection::open(Path::new(&self.db_file))?;
         let mut stmt = conn.prepare(sql)?;
-        let rows = stmt.query_map([], |row| {
+        let rows = stmt.query_map(parameters, |row| {
             let mut values = HashMap::<String, String>::new();
             for col_name_ref in columns.iter() {
                 let col_name = *col_name_ref;
@@ -0,0 +1,141 @@
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use super::canister::NaisMatrixCanister;
+use super::error::CanisterError;
+use super::IcpClient;
+
+/// Describes a session asset stored as chunks. It is rewritten after every uploaded chunk so an interrupted upload
+/// resumes from the chunks it lists; only a manifest listing every chunk describes a readable file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AssetManifest {
+    pub size: u64,
+    pub sha1: String,
+    pub chunk_size: usize,
+    pub chunks: Vec<String>,
+}
+
+impl AssetManifest {
+    pub fn is_complete(&self) -> bool {
+        self.chunk_size > 0 && self.chunks.len() as u64 == self.size.div_ceil(self.chunk_size as u64)
+    }
+}
+
+pub fn manifest_name(file_name: &str) -> String {
+    format!("{}.manifest", file_name)
+}
+
+pub fn chunk_name(file_name: &str, index: usize) -> String {
+    format!("{}.part{}", file_name, index)
+}
+
+fn sha1_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha1::digest(data))
+}
+
+impl IcpClient {
+    /// Files up to the configured chunk size are stored as one asset, larger ones as hashed chunks plus a manifest.
+    /// Chunks the stored manifest already lists with the same hash are skipped, so a failed upload resumes where it stopped.
+    /// The canister cannot delete assets, so whichever representation was not written is emptied.
+    pub async fn save_session_asset(&self, id: &str, session: &str, file_name: &str, data: Vec<u8>) -> Result<(), CanisterError> {
+        let chunk_size = self.config().asset_chunk_bytes;
+        if data.len() <= chunk_size {
+            self.upload_session_assets(id.to_string(), session.to_string(), file_name.to_string(), data).await?;
+            if self.session_asset_manifest(id, session, file_name).await?.is_some() {
+                self.upload_session_assets(id.to_string(), session.to_string(), manifest_name(file_name), vec![]).await?;
+            }
+            return Ok(());
+        }
+
+        let mut stored = self.session_asset_manifest(id, session, file_name).await?;
+        let uploaded = match &stored {
+            Some(stored) if stored.chunk_size == chunk_size => stored.chunks.clone(),
+            _ => vec![],
+        };
+        let mut manifest = AssetManifest {
+            size: data.len() as u64,
+            sha1: sha1_hex(&data),
+            chunk_size,
+            chunks: vec![],
+        };
+
+        for (index, chunk) in data.chunks(chunk_size).enumerate() {
+            let hash = sha1_hex(chunk);
+            let skip = uploaded.get(index) == Some(&hash);
+            if !skip {
+                self.upload_session_assets(id.to_string(), session.to_string(), chunk_name(file_name, index), chunk.to_vec())
+                    .await?;
+            }
+            manifest.chunks.push(hash);
+            if !skip {
+                self.put_session_asset_manifest(id, session, file_name, &manifest).await?;
+                stored = Some(manifest.clone());
+            }
+        }
+
+        if stored.as_ref() != Some(&manifest) {
+            self.put_session_asset_manifest(id, session, file_name, &manifest).await?;
+        }
+        let (plain, _, _) = self.check_session_assets(id.to_string(), session.to_string(), file_name.to_string()).await?;
+        if plain {
+            self.upload_session_assets(id.to_string(), session.to_string(), file_name.to_string(), vec![]).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads a session asset, reassembling and verifying it when a complete manifest says it was stored in chunks.
+    pub async fn read_session_asset(&self, id: &str, session: &str, file_name: &str) -> Result<(Vec<u8>, u64), CanisterError> {
+        if let Some(manifest) = self.session_asset_manifest(id, session, file_name).await?.filter(AssetManifest::is_complete) {
+            let data = self.read_chunks(id, session, file_name, &manifest).await?;
+            return Ok((data, manifest.size));
+        }
+
+        self.query_session_assets(id.to_string(), session.to_string(), file_name.to_string()).await
+    }
+
+    /// Same answer as `check_session_assets`, but prefers the chunks of a complete manifest.
+    pub async fn check_session_asset(&self, id: &str, session: &str, file_name: &str) -> Result<(bool, Vec<u8>, u64), CanisterError> {
+        if let Some(manifest) = self.session_asset_manifest(id, session, file_name).await?.filter(AssetManifest::is_complete) {
+            let data = self.read_chunks(id, session, file_name, &manifest).await?;
+            return Ok((true, data, manifest.size));
+        }
+
+        self.check_session_assets(id.to_string(), session.to_string(), file_name.to_string()).await
+    }
+
+    /// The stored manifest of `file_name`; an emptied manifest means the file is no longer chunked.
+    async fn session_asset_manifest(&self, id: &str, session: &str, file_name: &str) -> Result<Option<AssetManifest>, CanisterError> {
+        let (exists, data, _) = self.check_session_assets(id.to_string(), session.to_string(), manifest_name(file_name)).await?;
+        if !exists || data.is_empty() {
+            return Ok(None);
+        }
+
+        let manifest = serde_json::from_slice::<AssetManifest>(&data).map_err(|e| CanisterError::Decode(e.to_string()))?;
+        Ok(Some(manifest))
+    }
+
+    async fn put_session_asset_manifest(&self, id: &str, session: &str, file_name: &str, manifest: &AssetManifest) -> Result<(), CanisterError> {
+        let manifest = serde_json::to_vec(manifest).map_err(|e| CanisterError::Decode(e.to_string()))?;
+        self.upload_session_assets(id.to_string(), session.to_string(), manifest_name(file_name), manifest).await
+    }
+
+    async fn read_chunks(&self, id: &str, session: &str, file_name: &str, manifest: &AssetManifest) -> Result<Vec<u8>, CanisterError> {
+        let mut data = Vec::with_capacity(manifest.size as usize);
+
+        for (index, hash) in manifest.chunks.iter().enumerate() {
+            let name = chunk_name(file_name, index);
+            let (chunk, _) = self.query_session_assets(id.to_string(), session.to_string(), name.clone()).await?;
+            if &sha1_hex(&chunk) != hash {
+                return Err(CanisterError::Integrity(format!("chunk {} of {} does not match its hash", name, file_name)));
+            }
+            data.extend_from_slice(&chunk);
+        }
+
+        if data.len() as u64 != manifest.size || sha1_hex(&data) != manifest.sha1 {
+            return Err(CanisterError::Integrity(format!("{} does not match its manifest", file_name)));
+        }
+
+        Ok(data)
+    }
+}
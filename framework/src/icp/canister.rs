@@ -0,0 +1,226 @@
+#![allow(async_fn_in_trait)]
+
+use candid::{CandidType, Decode, Encode};
+use serde::Serialize;
+
+use crate::{PatoInfoResp, SubmitTagsResponse};
+
+use super::error::CanisterError;
+use super::{
+    BecomeKolRequest, CreateResonse, HotTopicResponse, IcpClient, KolRelations, NameResponse, PatoInfoResponse,
+    PlainDoc, SharedKnowledgesResponse, SimpleResponse, SubmitTagsRequest, TokenResponse, VecDoc, VecQuery,
+};
+
+// Reads go through `call_method`, which sends only the methods the canisters declare as `query` as query calls.
+pub trait AgentSmithCanister {
+    async fn request_pato_info(&self, id: String) -> Result<PatoInfoResponse, CanisterError>;
+    async fn request_pato_by_name(&self, name: String) -> Result<NameResponse, CanisterError>;
+    async fn request_pato_name(&self, id: String) -> Result<String, CanisterError>;
+    async fn request_pato_names(&self, ids: Vec<String>) -> Result<Vec<(String, String)>, CanisterError>;
+    async fn request_predefined_tags(&self) -> Result<String, CanisterError>;
+    async fn refresh_battery_auth(&self, id: String) -> Result<String, CanisterError>;
+    async fn query_pato_kol_token(&self, id: String) -> Result<TokenResponse, CanisterError>;
+    async fn query_pato_by_kol_token(&self, token: String) -> Result<TokenResponse, CanisterError>;
+    async fn query_pato_by_auth_token(&self, token: String) -> Result<TokenResponse, CanisterError>;
+    async fn request_kol_list(&self) -> Result<Vec<KolRelations>, CanisterError>;
+}
+
+pub trait NaisMatrixCanister {
+    async fn request_pato_login(&self, id: String) -> Result<(), CanisterError>;
+    async fn request_hot_ai(&self) -> Result<Vec<PatoInfoResp>, CanisterError>;
+    async fn request_hot_topics(&self) -> Result<HotTopicResponse, CanisterError>;
+    async fn request_shared_knowledges(&self) -> Result<SharedKnowledgesResponse, CanisterError>;
+    async fn request_create_pato(&self, name: String) -> Result<CreateResonse, CanisterError>;
+    async fn check_session_assets(&self, id: String, session: String, file_name: String) -> Result<(bool, Vec<u8>, u64), CanisterError>;
+    async fn query_session_assets(&self, id: String, session: String, file_name: String) -> Result<(Vec<u8>, u64), CanisterError>;
+    async fn upload_session_assets(&self, id: String, session: String, file_name: String, data: Vec<u8>) -> Result<(), CanisterError>;
+}
+
+pub trait AgentBatteryCanister {
+    async fn request_submit_tags(&self, request: SubmitTagsRequest) -> Result<SubmitTagsResponse, CanisterError>;
+    async fn become_kol(&self, request: BecomeKolRequest) -> Result<SimpleResponse, CanisterError>;
+    async fn set_session_of(&self, id: String, session: String) -> Result<(), CanisterError>;
+    async fn set_tags_of(&self, id: String, tags: String) -> Result<(), CanisterError>;
+    async fn set_name_of(&self, id: String, name: String) -> Result<(), CanisterError>;
+    async fn set_character_of(&self, id: String, character: String) -> Result<(), CanisterError>;
+    async fn set_avatar_of(&self, id: String, avatar: String) -> Result<(), CanisterError>;
+    async fn set_cover_of(&self, id: String, cover: String) -> Result<(), CanisterError>;
+    async fn set_topics_of(&self, id: String, topic: (String, String)) -> Result<(), CanisterError>;
+    async fn set_follower_of(&self, id: String, follower: (String, String)) -> Result<(), CanisterError>;
+    async fn set_following_of(&self, id: String, following: (String, String)) -> Result<(), CanisterError>;
+    async fn set_sub_topics_of(&self, topic_id: String, comment: (String, String)) -> Result<(), CanisterError>;
+    async fn topics_of(&self, id: String) -> Result<String, CanisterError>;
+    async fn sub_topics_of(&self, topic_id: String) -> Result<String, CanisterError>;
+}
+
+pub trait NaisVectorCanister {
+    async fn add(&self, doc: VecDoc) -> Result<String, CanisterError>;
+    async fn search(&self, query: VecQuery, size: usize) -> Result<Option<Vec<PlainDoc>>, CanisterError>;
+}
+
+#[derive(Serialize, CandidType)]
+struct BatterCallParams {
+    id: String,
+    token: String,
+    sn: i64,
+    method_name: String,
+    arg: String,
+}
+
+fn prepare_battery_call_args<T: Serialize>(id: String, method_name: &str, arg: T) -> String {
+    serde_json::to_string(&BatterCallParams {
+        id,
+        token: "".to_string(),
+        sn: -1,
+        method_name: method_name.to_string(),
+        arg: serde_json::to_string(&arg).unwrap_or_default(),
+    })
+    .unwrap_or_default()
+}
+
+impl AgentSmithCanister for IcpClient {
+    async fn request_pato_info(&self, id: String) -> Result<PatoInfoResponse, CanisterError> {
+        let result = self.call_method(&self.canisters().agent_smith, "request_pato_info", id).await?;
+        Ok(Decode!(result.as_slice(), PatoInfoResponse)?)
+    }
+    async fn request_pato_by_name(&self, name: String) -> Result<NameResponse, CanisterError> {
+        let result = self.call_method(&self.canisters().agent_smith, "request_pato_by_name", name).await?;
+        Ok(Decode!(result.as_slice(), NameResponse)?)
+    }
+    async fn request_pato_name(&self, id: String) -> Result<String, CanisterError> {
+        let result = self.call_method(&self.canisters().agent_smith, "request_pato_name", id).await?;
+        Ok(Decode!(result.as_slice(), String)?)
+    }
+    async fn request_pato_names(&self, ids: Vec<String>) -> Result<Vec<(String, String)>, CanisterError> {
+        let result = self.call_method(&self.canisters().agent_smith, "request_pato_names", ids).await?;
+        Ok(Decode!(result.as_slice(), Vec<(String, String)>)?)
+    }
+    async fn request_predefined_tags(&self) -> Result<String, CanisterError> {
+        let result = self.call_method(&self.canisters().agent_smith, "request_predefined_tags", ()).await?;
+        Ok(Decode!(result.as_slice(), String)?)
+    }
+    async fn refresh_battery_auth(&self, id: String) -> Result<String, CanisterError> {
+        let result = self.call_update_method(&self.canisters().agent_smith, "refresh_battery_auth", id).await?;
+        Ok(Decode!(result.as_slice(), String)?)
+    }
+    async fn query_pato_kol_token(&self, id: String) -> Result<TokenResponse, CanisterError> {
+        let result = self.call_method(&self.canisters().agent_smith, "query_pato_kol_token", id).await?;
+        Ok(Decode!(result.as_slice(), TokenResponse)?)
+    }
+    async fn query_pato_by_kol_token(&self, token: String) -> Result<TokenResponse, CanisterError> {
+        let result = self.call_method(&self.canisters().agent_smith, "query_pato_by_kol_token", token).await?;
+        Ok(Decode!(result.as_slice(), TokenResponse)?)
+    }
+    async fn query_pato_by_auth_token(&self, token: String) -> Result<TokenResponse, CanisterError> {
+        let result = self.call_method(&self.canisters().agent_smith, "query_pato_by_auth_token", token).await?;
+        Ok(Decode!(result.as_slice(), TokenResponse)?)
+    }
+    async fn request_kol_list(&self) -> Result<Vec<KolRelations>, CanisterError> {
+        let result = self.call_method(&self.canisters().agent_smith, "request_kol_list", ()).await?;
+        Ok(Decode!(result.as_slice(), Vec<KolRelations>)?)
+    }
+}
+
+impl NaisMatrixCanister for IcpClient {
+    async fn request_pato_login(&self, id: String) -> Result<(), CanisterError> {
+        self.call_update_method(&self.canisters().nais_matrix, "request_pato_login", id).await?;
+        Ok(())
+    }
+    async fn request_hot_ai(&self) -> Result<Vec<PatoInfoResp>, CanisterError> {
+        let result = self.call_method(&self.canisters().nais_matrix, "request_hot_ai", ()).await?;
+        Ok(Decode!(result.as_slice(), Vec<PatoInfoResp>)?)
+    }
+    async fn request_hot_topics(&self) -> Result<HotTopicResponse, CanisterError> {
+        let result = self.call_method(&self.canisters().nais_matrix, "request_hot_topics", ()).await?;
+        Ok(Decode!(result.as_slice(), HotTopicResponse)?)
+    }
+    async fn request_shared_knowledges(&self) -> Result<SharedKnowledgesResponse, CanisterError> {
+        let result = self.call_method(&self.canisters().nais_matrix, "request_shared_knowledges", ()).await?;
+        Ok(Decode!(result.as_slice(), SharedKnowledgesResponse)?)
+    }
+    async fn request_create_pato(&self, name: String) -> Result<CreateResonse, CanisterError> {
+        let result = self.call_update_method(&self.canisters().nais_matrix, "request_create_pato", name).await?;
+        Ok(Decode!(result.as_slice(), CreateResonse)?)
+    }
+    async fn check_session_assets(&self, id: String, session: String, file_name: String) -> Result<(bool, Vec<u8>, u64), CanisterError> {
+        let result = self.call_raw(&self.canisters().nais_matrix, "check_session_assets", Encode!(&id, &session, &file_name)?).await?;
+        Ok(Decode!(result.as_slice(), bool, Vec<u8>, u64)?)
+    }
+    async fn query_session_assets(&self, id: String, session: String, file_name: String) -> Result<(Vec<u8>, u64), CanisterError> {
+        let result = self.call_raw(&self.canisters().nais_matrix, "query_session_assets", Encode!(&id, &session, &file_name)?).await?;
+        Ok(Decode!(result.as_slice(), Vec<u8>, u64)?)
+    }
+    async fn upload_session_assets(&self, id: String, session: String, file_name: String, data: Vec<u8>) -> Result<(), CanisterError> {
+        self.update_raw(&self.canisters().nais_matrix, "upload_session_assets", Encode!(&id, &session, &file_name, &data)?).await?;
+        Ok(())
+    }
+}
+
+impl IcpClient {
+    async fn set_battery_info<T: CandidType>(&self, method: &str, id: String, data: T) -> Result<(), CanisterError> {
+        self.update_raw(&self.canisters().agent_battery, method, Encode!(&id, &data)?).await?;
+        Ok(())
+    }
+}
+
+impl AgentBatteryCanister for IcpClient {
+    async fn request_submit_tags(&self, request: SubmitTagsRequest) -> Result<SubmitTagsResponse, CanisterError> {
+        let req = prepare_battery_call_args(request.id.clone(), "request_submit_tags", request);
+        let result = self.call_update_method(&self.canisters().agent_battery, "do_battery_service", req).await?;
+        Ok(Decode!(result.as_slice(), SubmitTagsResponse)?)
+    }
+    async fn become_kol(&self, request: BecomeKolRequest) -> Result<SimpleResponse, CanisterError> {
+        let req = prepare_battery_call_args(request.id.clone(), "become_kol", request);
+        let result = self.call_update_method(&self.canisters().agent_battery, "do_battery_service", req).await?;
+        Ok(Decode!(result.as_slice(), SimpleResponse)?)
+    }
+    async fn set_session_of(&self, id: String, session: String) -> Result<(), CanisterError> {
+        self.set_battery_info("set_session_of", id, session).await
+    }
+    async fn set_tags_of(&self, id: String, tags: String) -> Result<(), CanisterError> {
+        self.set_battery_info("set_tags_of", id, tags).await
+    }
+    async fn set_name_of(&self, id: String, name: String) -> Result<(), CanisterError> {
+        self.set_battery_info("set_name_of", id, name).await
+    }
+    async fn set_character_of(&self, id: String, character: String) -> Result<(), CanisterError> {
+        self.set_battery_info("set_character_of", id, character).await
+    }
+    async fn set_avatar_of(&self, id: String, avatar: String) -> Result<(), CanisterError> {
+        self.set_battery_info("set_avatar_of", id, avatar).await
+    }
+    async fn set_cover_of(&self, id: String, cover: String) -> Result<(), CanisterError> {
+        self.set_battery_info("set_cover_of", id, cover).await
+    }
+    async fn set_topics_of(&self, id: String, topic: (String, String)) -> Result<(), CanisterError> {
+        self.set_battery_info("set_topics_of", id, topic).await
+    }
+    async fn set_follower_of(&self, id: String, follower: (String, String)) -> Result<(), CanisterError> {
+        self.set_battery_info("set_follower_of", id, follower).await
+    }
+    async fn set_following_of(&self, id: String, following: (String, String)) -> Result<(), CanisterError> {
+        self.set_battery_info("set_following_of", id, following).await
+    }
+    async fn set_sub_topics_of(&self, topic_id: String, comment: (String, String)) -> Result<(), CanisterError> {
+        self.set_battery_info("set_sub_topics_of", topic_id, comment).await
+    }
+    async fn topics_of(&self, id: String) -> Result<String, CanisterError> {
+        let result = self.call_method(&self.canisters().agent_battery, "topics_of", id).await?;
+        Ok(Decode!(result.as_slice(), String)?)
+    }
+    async fn sub_topics_of(&self, topic_id: String) -> Result<String, CanisterError> {
+        let result = self.call_method(&self.canisters().agent_battery, "sub_topics_of", topic_id).await?;
+        Ok(Decode!(result.as_slice(), String)?)
+    }
+}
+
+impl NaisVectorCanister for IcpClient {
+    async fn add(&self, doc: VecDoc) -> Result<String, CanisterError> {
+        let result = self.call_update_method(&self.canisters().nais_vector, "add", doc).await?;
+        Ok(Decode!(result.as_slice(), String)?)
+    }
+    async fn search(&self, query: VecQuery, size: usize) -> Result<Option<Vec<PlainDoc>>, CanisterError> {
+        let result = self.call_raw(&self.canisters().nais_vector, "search", Encode!(&query, &size)?).await?;
+        Ok(Decode!(result.as_slice(), Option<Vec<PlainDoc>>)?)
+    }
+}
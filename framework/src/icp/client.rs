@@ -0,0 +1,354 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::anyhow;
+use candid::{CandidType, Encode, Principal};
+use ic_agent::{Agent, AgentError};
+use serde::Serialize;
+use tokio::sync::OnceCell;
+
+use super::error::CanisterError;
+use super::identity::{load_identity, IdentitySource};
+use super::mock::MockCanisters;
+use super::network::{CanisterIds, NetworkProfile};
+use super::policy::{BreakerState, CallPolicy, CircuitBreaker};
+
+/// Stays below the 2 MB ingress message limit with room for the candid envelope.
+const DEFAULT_ASSET_CHUNK_BYTES: usize = 1024 * 1024;
+
+/// Methods the canisters' .did files declare as `query`; the typed client sends every other method as an update.
+pub const QUERY_METHODS: &[&str] = &[
+    "request_pato_info",
+    "request_pato_name",
+    "request_pato_names",
+    "request_predefined_tags",
+    "request_hot_ai",
+    "query_session_assets",
+];
+
+#[derive(Debug, Clone)]
+pub struct IcpClientConfig {
+    pub network: NetworkProfile,
+    pub gateway: String,
+    pub fetch_root_key: bool,
+    /// Check the replica node signatures on query responses.
+    pub verify_query_signatures: bool,
+    /// Methods the typed client sends as query calls, `QUERY_METHODS` unless overridden.
+    pub query_methods: HashSet<String>,
+    pub policy: CallPolicy,
+    pub identity: IdentitySource,
+    pub canisters: CanisterIds,
+    /// Session assets above this size are stored in chunks of this size.
+    pub asset_chunk_bytes: usize,
+}
+
+impl Default for IcpClientConfig {
+    fn default() -> Self {
+        IcpClientConfig {
+            network: NetworkProfile::Mainnet,
+            gateway: NetworkProfile::Mainnet.gateway().to_string(),
+            fetch_root_key: NetworkProfile::Mainnet.fetch_root_key(),
+            verify_query_signatures: true,
+            query_methods: QUERY_METHODS.iter().map(|m| m.to_string()).collect(),
+            policy: CallPolicy::default(),
+            identity: IdentitySource::Random,
+            canisters: CanisterIds::default(),
+            asset_chunk_bytes: DEFAULT_ASSET_CHUNK_BYTES,
+        }
+    }
+}
+
+impl IcpClientConfig {
+    /// Gateway and root key handling of `network`, everything else at its default.
+    pub fn for_network(network: NetworkProfile) -> Self {
+        IcpClientConfig {
+            network,
+            gateway: network.gateway().to_string(),
+            fetch_root_key: network.fetch_root_key(),
+            ..Default::default()
+        }
+    }
+
+    pub fn from_env() -> anyhow::Result<Self> {
+        let network = NetworkProfile::from_env();
+        let mut config = IcpClientConfig {
+            identity: IdentitySource::from_env(),
+            canisters: CanisterIds::from_env(network)?,
+            policy: CallPolicy::from_env(),
+            ..IcpClientConfig::for_network(network)
+        };
+
+        if let Ok(gateway) = std::env::var("ICP_GATEWAY") {
+            config.gateway = gateway;
+        }
+        if let Ok(fetch) = std::env::var("ICP_FETCH_ROOT_KEY") {
+            config.fetch_root_key = fetch == "1" || fetch.eq_ignore_ascii_case("true");
+        }
+        if let Some(bytes) = std::env::var("ICP_ASSET_CHUNK_BYTES").ok().and_then(|s| s.parse::<usize>().ok()) {
+            config.asset_chunk_bytes = bytes.max(1);
+        }
+        if let Ok(verify) = std::env::var("ICP_VERIFY_QUERY_SIGNATURES") {
+            config.verify_query_signatures = !(verify == "0" || verify.eq_ignore_ascii_case("false"));
+        }
+        if let Ok(methods) = std::env::var("ICP_QUERY_METHODS") {
+            config.query_methods = methods
+                .split(',')
+                .map(|m| m.trim().to_string())
+                .filter(|m| !m.is_empty())
+                .collect();
+        }
+
+        Ok(config)
+    }
+
+    pub fn is_query(&self, method_name: &str) -> bool {
+        self.query_methods.contains(method_name)
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct CanisterCallStats {
+    pub calls: u64,
+    pub queries: u64,
+    pub failures: u64,
+    pub timeouts: u64,
+    pub total_latency_ms: u128,
+    pub last_latency_ms: u128,
+    pub retries: u64,
+    pub short_circuited: u64,
+    pub consecutive_failures: u32,
+    pub breaker: BreakerState,
+}
+
+enum Backend {
+    Agent(Agent),
+    Mock(Arc<MockCanisters>),
+}
+
+struct IcpClientInner {
+    backend: Backend,
+    config: IcpClientConfig,
+    root_key: OnceCell<()>,
+    metrics: Mutex<HashMap<String, CanisterCallStats>>,
+    breakers: Mutex<HashMap<String, CircuitBreaker>>,
+}
+
+/// Long-lived handle to the IC, cheap to clone and shared through the portal app state.
+#[derive(Clone)]
+pub struct IcpClient {
+    inner: Arc<IcpClientInner>,
+}
+
+impl IcpClient {
+    pub fn new(config: IcpClientConfig) -> Result<Self, anyhow::Error> {
+        let identity = load_identity(&config.identity)?;
+        let agent = Agent::builder()
+            .with_url(config.gateway.as_str())
+            .with_arc_identity(identity)
+            .with_verify_query_signatures(config.verify_query_signatures)
+            .build()?;
+
+        Ok(IcpClient {
+            inner: Arc::new(IcpClientInner {
+                backend: Backend::Agent(agent),
+                config,
+                root_key: OnceCell::new(),
+                metrics: Mutex::new(HashMap::new()),
+                breakers: Mutex::new(HashMap::new()),
+            }),
+        })
+    }
+
+    /// Serves every canister call from the in-memory `MockCanisters`, for tests.
+    pub fn with_mock(mock: Arc<MockCanisters>) -> Self {
+        IcpClient {
+            inner: Arc::new(IcpClientInner {
+                backend: Backend::Mock(mock),
+                config: IcpClientConfig {
+                    network: NetworkProfile::Test,
+                    fetch_root_key: false,
+                    ..Default::default()
+                },
+                root_key: OnceCell::new(),
+                metrics: Mutex::new(HashMap::new()),
+                breakers: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    pub fn config(&self) -> &IcpClientConfig {
+        &self.inner.config
+    }
+
+    pub fn canisters(&self) -> &CanisterIds {
+        &self.inner.config.canisters
+    }
+
+    pub fn agent(&self) -> Option<&Agent> {
+        match &self.inner.backend {
+            Backend::Agent(agent) => Some(agent),
+            Backend::Mock(_) => None,
+        }
+    }
+
+    pub fn principal(&self) -> Result<Principal, anyhow::Error> {
+        match &self.inner.backend {
+            Backend::Agent(agent) => agent.get_principal().map_err(|e| anyhow!(e)),
+            Backend::Mock(_) => Ok(Principal::anonymous()),
+        }
+    }
+
+    /// Per-canister call counters, with the breaker state as of now.
+    pub fn metrics(&self) -> HashMap<String, CanisterCallStats> {
+        let mut metrics = self.inner.metrics.lock().map(|m| m.clone()).unwrap_or_default();
+
+        if let Ok(breakers) = self.inner.breakers.lock() {
+            for (canister, breaker) in breakers.iter() {
+                let stats = metrics.entry(canister.clone()).or_default();
+                stats.breaker = breaker.state(&self.inner.config.policy);
+                stats.consecutive_failures = breaker.consecutive_failures();
+            }
+        }
+
+        metrics
+    }
+
+    async fn ensure_root_key(&self, agent: &Agent) -> Result<(), AgentError> {
+        if !self.inner.config.fetch_root_key {
+            return Ok(());
+        }
+
+        self.inner.root_key
+            .get_or_try_init(|| async { agent.fetch_root_key().await })
+            .await?;
+
+        Ok(())
+    }
+
+    fn record_call(&self, canister: &str, started: Instant, result: &Result<Vec<u8>, CanisterError>, is_query: bool, is_retry: bool) {
+        let elapsed = started.elapsed().as_millis();
+
+        if let Ok(mut metrics) = self.inner.metrics.lock() {
+            let stats = metrics.entry(canister.to_string()).or_default();
+            stats.calls += 1;
+            if is_query {
+                stats.queries += 1;
+            }
+            stats.total_latency_ms += elapsed;
+            stats.last_latency_ms = elapsed;
+            if is_retry {
+                stats.retries += 1;
+            }
+            match result {
+                Err(CanisterError::Timeout) => {
+                    stats.failures += 1;
+                    stats.timeouts += 1;
+                }
+                Err(_) => stats.failures += 1,
+                Ok(_) => {}
+            }
+        }
+
+        // A reject or decode failure still means the canister answered, so only transient errors count against it.
+        if let Ok(mut breakers) = self.inner.breakers.lock() {
+            let breaker = breakers.entry(canister.to_string()).or_default();
+            match result {
+                Err(e) if e.is_transient() => breaker.on_failure(&self.inner.config.policy),
+                _ => breaker.on_success(),
+            }
+        }
+    }
+
+    fn breaker_allows(&self, canister: &str) -> bool {
+        let allowed = match self.inner.breakers.lock() {
+            Ok(mut breakers) => breakers.entry(canister.to_string()).or_default().allow(&self.inner.config.policy),
+            Err(_) => true,
+        };
+
+        if !allowed {
+            if let Ok(mut metrics) = self.inner.metrics.lock() {
+                metrics.entry(canister.to_string()).or_default().short_circuited += 1;
+            }
+        }
+
+        allowed
+    }
+
+    async fn agent_call(&self, agent: &Agent, canister_called: &str, method_name: &str, arg: Vec<u8>, is_query: bool) -> Result<Vec<u8>, AgentError> {
+        self.ensure_root_key(agent).await?;
+        let effective_canister_id = Principal::from_text(canister_called)?;
+
+        let timeout = self.inner.config.policy.timeout_for(method_name);
+        let result = if is_query {
+            let call = agent.query(&effective_canister_id, method_name)
+                .with_effective_canister_id(effective_canister_id)
+                .with_arg(arg);
+            tokio::time::timeout(timeout, call).await
+        } else {
+            let call = agent.update(&effective_canister_id, method_name)
+                .with_effective_canister_id(effective_canister_id)
+                .with_arg(arg);
+            tokio::time::timeout(timeout, call).await
+        };
+
+        match result {
+            Ok(result) => result,
+            Err(_) => Err(AgentError::TimeoutWaitingForResponse()),
+        }
+    }
+
+    async fn call(&self, canister_called: &str, method_name: &str, arg: Vec<u8>, is_query: bool) -> Result<Vec<u8>, CanisterError> {
+        let policy = &self.inner.config.policy;
+        let retries = if is_query { policy.query_retries } else { 0 };
+        let mut attempt = 0;
+
+        loop {
+            if !self.breaker_allows(canister_called) {
+                return Err(CanisterError::CircuitOpen(canister_called.to_string()));
+            }
+
+            let started = Instant::now();
+            let result = match &self.inner.backend {
+                Backend::Agent(agent) => self.agent_call(agent, canister_called, method_name, arg.clone(), is_query).await.map_err(CanisterError::from),
+                Backend::Mock(mock) => mock.handle(method_name, &arg),
+            };
+            self.record_call(canister_called, started, &result, is_query, attempt > 0);
+
+            match result {
+                Err(e) if e.is_transient() && attempt < retries => {
+                    println!("{} on {} failed ({}), retrying", method_name, canister_called, e);
+                    tokio::time::sleep(policy.backoff(attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    pub async fn update_raw(&self, canister_called: &str, method_name: &str, arg: Vec<u8>) -> Result<Vec<u8>, CanisterError> {
+        self.call(canister_called, method_name, arg, false).await
+    }
+
+    pub async fn query_raw(&self, canister_called: &str, method_name: &str, arg: Vec<u8>) -> Result<Vec<u8>, CanisterError> {
+        self.call(canister_called, method_name, arg, true).await
+    }
+
+    pub async fn call_update_method<T: CandidType>(&self, canister_called: &str, method_name: &str, params: T) -> Result<Vec<u8>, CanisterError> {
+        self.update_raw(canister_called, method_name, Encode!(&params)?).await
+    }
+
+    pub async fn call_query_method<T: CandidType>(&self, canister_called: &str, method_name: &str, params: T) -> Result<Vec<u8>, CanisterError> {
+        self.query_raw(canister_called, method_name, Encode!(&params)?).await
+    }
+
+    /// Sent as a query call when `method_name` is one of the configured query methods, as an update otherwise.
+    pub async fn call_raw(&self, canister_called: &str, method_name: &str, arg: Vec<u8>) -> Result<Vec<u8>, CanisterError> {
+        let is_query = self.inner.config.is_query(method_name);
+        self.call(canister_called, method_name, arg, is_query).await
+    }
+
+    pub async fn call_method<T: CandidType>(&self, canister_called: &str, method_name: &str, params: T) -> Result<Vec<u8>, CanisterError> {
+        self.call_raw(canister_called, method_name, Encode!(&params)?).await
+    }
+}
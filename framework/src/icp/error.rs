@@ -0,0 +1,90 @@
+use std::fmt;
+
+use ic_agent::AgentError;
+use serde::Serialize;
+
+use crate::ApiError;
+
+#[derive(Debug, Clone, Serialize)]
+pub enum CanisterError {
+    Reject { code: String, message: String },
+    Timeout,
+    Decode(String),
+    Certificate(String),
+    Unreachable(String),
+    CircuitOpen(String),
+    Integrity(String),
+}
+
+impl ApiError for CanisterError {
+    fn status_code(&self) -> u16 {
+        match self {
+            CanisterError::Reject { .. } => 422,
+            CanisterError::Timeout => 504,
+            CanisterError::Decode(_) | CanisterError::Certificate(_) | CanisterError::Integrity(_) => 502,
+            CanisterError::Unreachable(_) | CanisterError::CircuitOpen(_) => 503,
+        }
+    }
+
+    fn error_code(&self) -> &'static str {
+        match self {
+            CanisterError::Reject { .. } => "canister_reject",
+            CanisterError::Timeout => "canister_timeout",
+            CanisterError::Decode(_) => "canister_decode",
+            CanisterError::Certificate(_) => "canister_certificate",
+            CanisterError::Unreachable(_) => "canister_unreachable",
+            CanisterError::CircuitOpen(_) => "canister_circuit_open",
+            CanisterError::Integrity(_) => "canister_integrity",
+        }
+    }
+}
+
+impl CanisterError {
+    /// Failures that say nothing about the request itself and may succeed on another attempt.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, CanisterError::Timeout | CanisterError::Unreachable(_))
+    }
+
+    pub fn reject(message: String) -> Self {
+        CanisterError::Reject { code: "CanisterReject".to_string(), message }
+    }
+}
+
+impl fmt::Display for CanisterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CanisterError::Reject { code, message } => write!(f, "canister rejected ({}): {}", code, message),
+            CanisterError::Timeout => write!(f, "canister call timed out"),
+            CanisterError::Decode(message) => write!(f, "candid decode failed: {}", message),
+            CanisterError::Certificate(message) => write!(f, "certificate verification failed: {}", message),
+            CanisterError::Unreachable(message) => write!(f, "canister unreachable: {}", message),
+            CanisterError::CircuitOpen(canister) => write!(f, "circuit open for canister {}", canister),
+            CanisterError::Integrity(message) => write!(f, "asset integrity check failed: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for CanisterError {}
+
+impl From<AgentError> for CanisterError {
+    fn from(e: AgentError) -> Self {
+        match e {
+            AgentError::CertifiedReject(reject) | AgentError::UncertifiedReject(reject) => CanisterError::Reject {
+                code: reject.error_code.unwrap_or_else(|| format!("{:?}", reject.reject_code)),
+                message: reject.reject_message,
+            },
+            AgentError::TimeoutWaitingForResponse() => CanisterError::Timeout,
+            AgentError::CandidError(e) => CanisterError::Decode(e.to_string()),
+            AgentError::CertificateVerificationFailed() | AgentError::CertificateNotAuthorized() => {
+                CanisterError::Certificate(e.to_string())
+            }
+            e => CanisterError::Unreachable(e.to_string()),
+        }
+    }
+}
+
+impl From<candid::Error> for CanisterError {
+    fn from(e: candid::Error) -> Self {
+        CanisterError::Decode(e.to_string())
+    }
+}
@@ -0,0 +1,96 @@
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Error};
+use ic_agent::identity::{BasicIdentity, Secp256k1Identity};
+use ic_agent::Identity;
+use ring::signature::Ed25519KeyPair;
+
+use super::PEM_FILE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdentityKind {
+    Ed25519,
+    Secp256k1,
+}
+
+impl IdentityKind {
+    fn from_env() -> Self {
+        match std::env::var("ICP_IDENTITY_KIND") {
+            Ok(kind) if kind.eq_ignore_ascii_case("secp256k1") => IdentityKind::Secp256k1,
+            _ => IdentityKind::Ed25519,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum IdentitySource {
+    PemFile(PathBuf, IdentityKind),
+    PemEnv(String, IdentityKind),
+    Random,
+}
+
+impl IdentitySource {
+    /// ICP_IDENTITY_PEM_FILE wins over ICP_IDENTITY_PEM, then `identity.pem` in the working directory.
+    pub fn from_env() -> Self {
+        let kind = IdentityKind::from_env();
+
+        if let Ok(path) = std::env::var("ICP_IDENTITY_PEM_FILE") {
+            return IdentitySource::PemFile(PathBuf::from(path), kind);
+        }
+        if std::env::var("ICP_IDENTITY_PEM").is_ok() {
+            return IdentitySource::PemEnv("ICP_IDENTITY_PEM".to_string(), kind);
+        }
+        if Path::new(PEM_FILE).exists() {
+            return IdentitySource::PemFile(PathBuf::from(PEM_FILE), kind);
+        }
+
+        IdentitySource::Random
+    }
+}
+
+fn identity_from_pem(pem: &[u8], kind: IdentityKind) -> Result<Arc<dyn Identity>, Error> {
+    match kind {
+        IdentityKind::Ed25519 => {
+            let identity = BasicIdentity::from_pem(Cursor::new(pem))
+                .map_err(|e| anyhow!("could not read ed25519 pem: {}", e))?;
+            Ok(Arc::new(identity))
+        }
+        IdentityKind::Secp256k1 => {
+            let identity = Secp256k1Identity::from_pem(Cursor::new(pem))
+                .map_err(|e| anyhow!("could not read secp256k1 pem: {}", e))?;
+            Ok(Arc::new(identity))
+        }
+    }
+}
+
+fn random_identity() -> Arc<dyn Identity> {
+    let rng = ring::rand::SystemRandom::new();
+    let pkcs8_bytes = ring::signature::Ed25519KeyPair::generate_pkcs8(&rng)
+        .expect("Could not generate a key pair.")
+        .as_ref()
+        .to_vec();
+
+    Arc::new(BasicIdentity::from_key_pair(
+        Ed25519KeyPair::from_pkcs8(&pkcs8_bytes).expect("Could not generate the key pair."),
+    ))
+}
+
+pub fn load_identity(source: &IdentitySource) -> Result<Arc<dyn Identity>, Error> {
+    match source {
+        IdentitySource::PemFile(path, kind) => {
+            let pem = std::fs::read(path)
+                .map_err(|e| anyhow!("could not read identity file {}: {}", path.display(), e))?;
+            identity_from_pem(&pem, *kind)
+        }
+        IdentitySource::PemEnv(var, kind) => {
+            let pem = std::env::var(var)?;
+            identity_from_pem(pem.as_bytes(), *kind)
+        }
+        IdentitySource::Random => {
+            println!("no icp identity configured, using a random key; privileged canister methods will reject");
+            Ok(random_identity())
+        }
+    }
+}
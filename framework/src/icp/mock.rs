@@ -0,0 +1,397 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use candid::{Decode, Encode};
+use serde::Deserialize;
+
+use crate::{get_now_secs, get_now_secs_str_zh, PatoInfoResp, SubmitTagsResponse, XFILES_SERVER};
+
+use super::error::CanisterError;
+use super::vector::cosine_similarity;
+use super::{
+    BecomeKolRequest, CreateResonse, HotTopicResponse, Knowledge, KolRelations, NamePros, NameResponse,
+    PatoInfoResponse, PlainDoc, SharedKnowledgesResponse, SimpleResponse, SubmitTagsRequest, TokenResponse, VecDoc,
+    VecQuery,
+};
+
+#[derive(Debug, Default, Clone)]
+pub struct MockPato {
+    pub sn: i64,
+    pub id: String,
+    pub name: String,
+    pub registered_datetime: String,
+    pub tags: Vec<String>,
+    pub character: String,
+    pub avatar: String,
+    pub cover: String,
+    pub session: String,
+    pub followers: Vec<(String, String)>,
+    pub followings: Vec<(String, String)>,
+    pub topics: Vec<(String, String)>,
+    pub auth_token: String,
+    pub kol_token: String,
+}
+
+#[derive(Default)]
+struct MockState {
+    patos: HashMap<String, MockPato>,
+    session_assets: HashMap<(String, String, String), Vec<u8>>,
+    sub_topics: HashMap<String, Vec<(String, String)>>,
+    hot_topics: Vec<String>,
+    knowledges: Vec<Knowledge>,
+    predefined_tags: String,
+    documents: Vec<VecDoc>,
+    failures: HashMap<String, VecDeque<CanisterError>>,
+    cycles: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct BatteryCall {
+    id: String,
+    method_name: String,
+    arg: String,
+}
+
+/// In-memory stand-in for the Agent Smith, Matrix, Battery and Vector canisters.
+#[derive(Default)]
+pub struct MockCanisters {
+    state: Mutex<MockState>,
+}
+
+fn mock_error(message: String) -> CanisterError {
+    CanisterError::reject(message)
+}
+
+impl MockCanisters {
+    pub fn new() -> Self {
+        MockCanisters::default()
+    }
+
+    pub fn set_predefined_tags(&self, tags: &str) {
+        self.state.lock().unwrap().predefined_tags = tags.to_string();
+    }
+
+    pub fn add_hot_topic(&self, topic: &str) {
+        self.state.lock().unwrap().hot_topics.push(topic.to_string());
+    }
+
+    pub fn add_shared_knowledge(&self, knowledge: Knowledge) {
+        self.state.lock().unwrap().knowledges.push(knowledge);
+    }
+
+    pub fn seed_session_asset(&self, id: &str, session: &str, file_name: &str, data: Vec<u8>) {
+        self.state.lock().unwrap()
+            .session_assets
+            .insert((id.to_string(), session.to_string(), file_name.to_string()), data);
+    }
+
+    pub fn session_asset(&self, id: &str, session: &str, file_name: &str) -> Option<Vec<u8>> {
+        self.state.lock().unwrap()
+            .session_assets
+            .get(&(id.to_string(), session.to_string(), file_name.to_string()))
+            .cloned()
+    }
+
+    pub fn pato(&self, id: &str) -> Option<MockPato> {
+        self.state.lock().unwrap().patos.get(id).cloned()
+    }
+
+    pub fn documents(&self) -> Vec<VecDoc> {
+        self.state.lock().unwrap().documents.clone()
+    }
+
+    /// Lets `cycles_balance` answer with this balance, otherwise the method is rejected like on a canister without it.
+    pub fn set_cycles(&self, cycles: u64) {
+        self.state.lock().unwrap().cycles = Some(cycles);
+    }
+
+    /// Makes the next call to `method_name` fail with `error`, queued per method.
+    pub fn fail_next(&self, method_name: &str, error: CanisterError) {
+        self.state.lock().unwrap()
+            .failures
+            .entry(method_name.to_string())
+            .or_default()
+            .push_back(error);
+    }
+
+    fn with_pato<R>(&self, id: &str, f: impl FnOnce(&mut MockPato) -> R) -> Result<R, CanisterError> {
+        let mut state = self.state.lock().unwrap();
+        match state.patos.get_mut(id) {
+            Some(pato) => Ok(f(pato)),
+            None => Err(mock_error(format!("pato {} not found", id))),
+        }
+    }
+
+    fn create_pato(&self, name: String) -> CreateResonse {
+        let mut state = self.state.lock().unwrap();
+        let id = uuid::Uuid::new_v4().to_string();
+        let pato = MockPato {
+            sn: state.patos.len() as i64,
+            id: id.clone(),
+            name,
+            registered_datetime: get_now_secs_str_zh(),
+            ..Default::default()
+        };
+        state.patos.insert(id.clone(), pato);
+
+        CreateResonse { id }
+    }
+
+    fn pato_info(pato: &MockPato) -> PatoInfoResponse {
+        PatoInfoResponse {
+            id: pato.id.clone(),
+            name: pato.name.clone(),
+            sn: pato.sn,
+            registered_datetime: pato.registered_datetime.clone(),
+            balance: 0.0,
+            tags: pato.tags.clone(),
+            avatar: pato.avatar.clone(),
+            cover: pato.cover.clone(),
+            followers: pato.followers.clone(),
+            followings: pato.followings.clone(),
+        }
+    }
+
+    fn token_of(&self, token: &str, kol: bool) -> TokenResponse {
+        let state = self.state.lock().unwrap();
+        state.patos.values()
+            .find(|p| !token.is_empty() && (if kol { &p.kol_token } else { &p.auth_token }) == token)
+            .map(|p| TokenResponse { id: p.id.clone(), name: p.name.clone(), token: token.to_string() })
+            .unwrap_or_default()
+    }
+
+    fn battery_service(&self, call: BatteryCall) -> Result<Vec<u8>, CanisterError> {
+        match call.method_name.as_str() {
+            "request_submit_tags" => {
+                let request: SubmitTagsRequest = serde_json::from_str(&call.arg)
+                    .map_err(|e| CanisterError::Decode(e.to_string()))?;
+                let avatar = format!("{}/ai/{}/avatar.png", XFILES_SERVER, request.id);
+                let response = self.with_pato(&request.id, |pato| {
+                    pato.tags = request.tags.clone();
+                    pato.session = request.session.clone();
+                    pato.avatar = avatar.clone();
+                    SubmitTagsResponse {
+                        avatar: pato.avatar.clone(),
+                        cover: pato.cover.clone(),
+                        character: pato.character.clone(),
+                    }
+                })?;
+                Ok(Encode!(&response)?)
+            }
+            "become_kol" => {
+                let request: BecomeKolRequest = serde_json::from_str(&call.arg)
+                    .map_err(|e| CanisterError::Decode(e.to_string()))?;
+                let token = uuid::Uuid::new_v4().to_string();
+                self.with_pato(&request.id, |pato| pato.kol_token = token.clone())?;
+                Ok(Encode!(&SimpleResponse { success: true, message: token })?)
+            }
+            other => Err(mock_error(format!("battery service {} not mocked for {}", other, call.id))),
+        }
+    }
+
+    pub fn handle(&self, method_name: &str, arg: &[u8]) -> Result<Vec<u8>, CanisterError> {
+        let failure = self.state.lock().unwrap()
+            .failures
+            .get_mut(method_name)
+            .and_then(|queue| queue.pop_front());
+        if let Some(error) = failure {
+            return Err(error);
+        }
+
+        match method_name {
+            "request_pato_info" => {
+                let id = Decode!(arg, String)?;
+                let info = self.with_pato(&id, |pato| MockCanisters::pato_info(pato))?;
+                Ok(Encode!(&info)?)
+            }
+            "request_pato_by_name" => {
+                let name = Decode!(arg, String)?;
+                let state = self.state.lock().unwrap();
+                let name_pros = state.patos.values()
+                    .filter(|p| p.name.contains(&name))
+                    .map(|p| NamePros { id: p.id.clone(), name: p.name.clone(), pros: p.tags.clone() })
+                    .collect();
+                Ok(Encode!(&NameResponse { name_pros })?)
+            }
+            "request_pato_name" => {
+                let id = Decode!(arg, String)?;
+                let name = self.with_pato(&id, |pato| pato.name.clone())?;
+                Ok(Encode!(&name)?)
+            }
+            "request_pato_names" => {
+                let ids = Decode!(arg, Vec<String>)?;
+                let state = self.state.lock().unwrap();
+                let names = ids.iter()
+                    .filter_map(|id| state.patos.get(id).map(|p| (p.id.clone(), p.name.clone())))
+                    .collect::<Vec<(String, String)>>();
+                Ok(Encode!(&names)?)
+            }
+            "request_predefined_tags" => {
+                let tags = self.state.lock().unwrap().predefined_tags.clone();
+                Ok(Encode!(&tags)?)
+            }
+            "refresh_battery_auth" => {
+                let id = Decode!(arg, String)?;
+                let token = uuid::Uuid::new_v4().to_string();
+                self.with_pato(&id, |pato| pato.auth_token = token.clone())?;
+                Ok(Encode!(&token)?)
+            }
+            "query_pato_kol_token" => {
+                let id = Decode!(arg, String)?;
+                let response = self.with_pato(&id, |pato| TokenResponse {
+                    id: pato.id.clone(),
+                    name: pato.name.clone(),
+                    token: pato.kol_token.clone(),
+                })?;
+                Ok(Encode!(&response)?)
+            }
+            "query_pato_by_kol_token" => {
+                let token = Decode!(arg, String)?;
+                Ok(Encode!(&self.token_of(&token, true))?)
+            }
+            "query_pato_by_auth_token" => {
+                let token = Decode!(arg, String)?;
+                Ok(Encode!(&self.token_of(&token, false))?)
+            }
+            "request_kol_list" => {
+                let state = self.state.lock().unwrap();
+                let kols = state.patos.values()
+                    .filter(|p| !p.kol_token.is_empty())
+                    .map(|p| KolRelations {
+                        id: p.id.clone(),
+                        name: p.name.clone(),
+                        follower: p.followers.iter().map(|f| f.0.clone()).collect(),
+                    })
+                    .collect::<Vec<KolRelations>>();
+                Ok(Encode!(&kols)?)
+            }
+            "request_pato_login" => {
+                let id = Decode!(arg, String)?;
+                self.with_pato(&id, |_| ())?;
+                Ok(Encode!()?)
+            }
+            "request_hot_ai" => {
+                let state = self.state.lock().unwrap();
+                let hots = state.patos.values()
+                    .map(|p| PatoInfoResp {
+                        sn: p.sn,
+                        id: p.id.clone(),
+                        name: p.name.clone(),
+                        registered_datetime: p.registered_datetime.clone(),
+                        tags: p.tags.clone(),
+                        avatar: p.avatar.clone(),
+                        token: String::new(),
+                        token_refresh_at: get_now_secs(),
+                    })
+                    .collect::<Vec<PatoInfoResp>>();
+                Ok(Encode!(&hots)?)
+            }
+            "request_hot_topics" => {
+                let topics = self.state.lock().unwrap().hot_topics.clone();
+                Ok(Encode!(&HotTopicResponse { topics })?)
+            }
+            "request_shared_knowledges" => {
+                let state = self.state.lock().unwrap();
+                let books = state.knowledges.iter()
+                    .map(|k| Knowledge {
+                        sig: k.sig.clone(),
+                        title: k.title.clone(),
+                        owner: k.owner.clone(),
+                        summary: k.summary.clone(),
+                    })
+                    .collect();
+                Ok(Encode!(&SharedKnowledgesResponse { books })?)
+            }
+            "request_create_pato" => {
+                let name = Decode!(arg, String)?;
+                Ok(Encode!(&self.create_pato(name))?)
+            }
+            "check_session_assets" => {
+                let (id, session, file_name) = Decode!(arg, String, String, String)?;
+                let data = self.session_asset(&id, &session, &file_name);
+                let exists = data.is_some();
+                let data = data.unwrap_or_default();
+                let size = data.len() as u64;
+                Ok(Encode!(&exists, &data, &size)?)
+            }
+            "query_session_assets" => {
+                let (id, session, file_name) = Decode!(arg, String, String, String)?;
+                let data = self.session_asset(&id, &session, &file_name).unwrap_or_default();
+                let size = data.len() as u64;
+                Ok(Encode!(&data, &size)?)
+            }
+            "upload_session_assets" => {
+                let (id, session, file_name, data) = Decode!(arg, String, String, String, Vec<u8>)?;
+                self.seed_session_asset(&id, &session, &file_name, data);
+                Ok(Encode!()?)
+            }
+            "do_battery_service" => {
+                let req = Decode!(arg, String)?;
+                let call: BatteryCall = serde_json::from_str(&req).map_err(|e| CanisterError::Decode(e.to_string()))?;
+                self.battery_service(call)
+            }
+            "set_session_of" | "set_tags_of" | "set_name_of" | "set_character_of" | "set_avatar_of" | "set_cover_of" => {
+                let (id, value) = Decode!(arg, String, String)?;
+                self.with_pato(&id, |pato| match method_name {
+                    "set_session_of" => pato.session = value,
+                    "set_tags_of" => pato.tags = value.split(',').map(|t| t.to_string()).collect(),
+                    "set_name_of" => pato.name = value,
+                    "set_character_of" => pato.character = value,
+                    "set_avatar_of" => pato.avatar = value,
+                    _ => pato.cover = value,
+                })?;
+                Ok(Encode!()?)
+            }
+            "set_topics_of" | "set_follower_of" | "set_following_of" => {
+                let (id, pair) = Decode!(arg, String, (String, String))?;
+                self.with_pato(&id, |pato| match method_name {
+                    "set_topics_of" => pato.topics.push(pair),
+                    "set_follower_of" => pato.followers.push(pair),
+                    _ => pato.followings.push(pair),
+                })?;
+                Ok(Encode!()?)
+            }
+            "set_sub_topics_of" => {
+                let (topic_id, comment) = Decode!(arg, String, (String, String))?;
+                self.state.lock().unwrap().sub_topics.entry(topic_id).or_default().push(comment);
+                Ok(Encode!()?)
+            }
+            "topics_of" => {
+                let id = Decode!(arg, String)?;
+                let topics = self.with_pato(&id, |pato| pato.topics.clone())?;
+                Ok(Encode!(&serde_json::to_string(&topics).unwrap_or_default())?)
+            }
+            "sub_topics_of" => {
+                let topic_id = Decode!(arg, String)?;
+                let comments = self.state.lock().unwrap().sub_topics.get(&topic_id).cloned().unwrap_or_default();
+                Ok(Encode!(&serde_json::to_string(&comments).unwrap_or_default())?)
+            }
+            "add" => {
+                let doc = Decode!(arg, VecDoc)?;
+                let mut state = self.state.lock().unwrap();
+                state.documents.push(doc);
+                Ok(Encode!(&state.documents.len().to_string())?)
+            }
+            "search" => {
+                let (query, size) = Decode!(arg, VecQuery, usize)?;
+                let VecQuery::Embeddings(embeddings) = query;
+                let state = self.state.lock().unwrap();
+                let mut scored = state.documents.iter()
+                    .map(|doc| (cosine_similarity(&embeddings, &doc.embeddings), doc))
+                    .collect::<Vec<(f32, &VecDoc)>>();
+                scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+                let docs = scored.iter()
+                    .take(size)
+                    .map(|(_, doc)| PlainDoc { content: doc.content.clone() })
+                    .collect::<Vec<PlainDoc>>();
+                let result = if docs.is_empty() { None } else { Some(docs) };
+                Ok(Encode!(&result)?)
+            }
+            "cycles_balance" => match self.state.lock().unwrap().cycles {
+                Some(cycles) => Ok(Encode!(&candid::Nat::from(cycles))?),
+                None => Err(mock_error("method cycles_balance not found".to_string())),
+            },
+            other => Err(mock_error(format!("method {} not mocked", other))),
+        }
+    }
+}
@@ -1,9 +1,27 @@
-use std::path::PathBuf;
-
-use candid::{CandidType, Encode, Principal};
-use ic_agent::{identity::BasicIdentity, Agent, AgentError, Identity};
-use ring::signature::Ed25519KeyPair;
-use serde::Deserialize;
+pub mod assets;
+pub mod canister;
+pub mod client;
+pub mod error;
+pub mod identity;
+pub mod mock;
+pub mod monitor;
+pub mod network;
+pub mod policy;
+pub mod vector;
+
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+pub use assets::AssetManifest;
+pub use canister::{AgentBatteryCanister, AgentSmithCanister, NaisMatrixCanister, NaisVectorCanister};
+pub use client::{CanisterCallStats, IcpClient, IcpClientConfig, QUERY_METHODS};
+pub use error::CanisterError;
+pub use identity::{load_identity, IdentityKind, IdentitySource};
+pub use mock::MockCanisters;
+pub use monitor::{CanisterHealth, CanisterMonitor, CanisterReport, MonitorConfig};
+pub use network::{CanisterIds, NetworkProfile};
+pub use policy::{BreakerState, CallPolicy};
+pub use vector::{cosine_similarity, ChunkMeta, ScoredChunk, VectorChunk, VectorFilter};
 
 const DEFAULT_IC_GATEWAY: &str = "https://ic0.app/";
 pub const ENDPOINT_URL: &str = "http://localhost:8000/";
@@ -59,53 +77,88 @@ pub struct NameRequest {
     pub id: Vec<String>,
 }
 
+#[derive(Deserialize, CandidType)]
+pub struct Knowledge {
+    pub sig: String,
+    pub title: String,
+    pub owner: String,
+    pub summary: String,
+}
 
-pub async fn init_icp_agent() -> Result<Agent, AgentError>{
-    let agent: Agent = Agent::builder()
-        .with_url(DEFAULT_IC_GATEWAY)
-        .with_identity(create_identity(None))
-        .build()?;
+#[derive(Deserialize, CandidType, Default)]
+pub struct SharedKnowledgesResponse {
+    pub books: Vec<Knowledge>,
+}
+
+#[derive(Deserialize, CandidType, Default)]
+pub struct HotTopicResponse {
+    pub topics: Vec<String>,
+}
+
+#[derive(Deserialize, CandidType, Default, Debug)]
+pub struct CreateResonse {
+    pub id: String,
+}
+
+#[derive(Deserialize, CandidType, Default)]
+pub struct SimpleResponse {
+    pub success: bool,
+    pub message: String,
+}
 
-    agent.fetch_root_key().await?;
+#[derive(Deserialize, CandidType, Default, Serialize)]
+pub struct PatoInfoResponse {
+    pub id: String,
+    pub name: String,
+    pub sn: i64,
+    pub registered_datetime: String,
+    pub balance: f32,
+    pub tags: Vec<String>,
+    pub avatar: String,
+    pub cover: String,
+    pub followers: Vec<(String, String)>,
+    pub followings: Vec<(String, String)>,
+}
 
-    Ok(agent)
+#[derive(Deserialize, Serialize, CandidType, Default)]
+pub struct TokenResponse {
+    pub id: String,
+    pub name: String,
+    pub token: String,
 }
-// static AGENT: Mutex<Agent> = Mutex::new(init_icp_agent().await.unwrap_or_default());
 
-fn create_identity(maybe_pem: Option<PathBuf>) -> impl Identity {
-    if let Some(pem_path) = maybe_pem {
-        BasicIdentity::from_pem_file(pem_path).expect("Could not read the key pair.")
-    } else {
-        let rng = ring::rand::SystemRandom::new();
-        let pkcs8_bytes = ring::signature::Ed25519KeyPair::generate_pkcs8(&rng)
-            .expect("Could not generate a key pair.")
-            .as_ref()
-            .to_vec();
+#[derive(Deserialize, CandidType, Serialize)]
+pub struct KolRelations {
+    pub id: String,
+    pub name: String,
+    pub follower: Vec<String>,
+}
 
-        BasicIdentity::from_key_pair(
-            Ed25519KeyPair::from_pkcs8(&pkcs8_bytes).expect("Could not generate the key pair."),
-        )
-    }
+#[derive(Deserialize, Serialize, CandidType)]
+pub struct SubmitTagsRequest {
+    pub id: String,
+    pub session: String,
+    pub tags: Vec<String>,
 }
 
-pub async fn call_update_method<T: CandidType>(canister_called: &str, method_name: &str, params: T) -> Result<Vec<u8>, AgentError>
-{
-    let agent = init_icp_agent().await?;
-    let effective_canister_id = Principal::from_text(canister_called).unwrap();
+#[derive(Deserialize, Serialize, CandidType)]
+pub struct BecomeKolRequest {
+    pub id: String,
+    pub from: String,
+}
 
-    agent.update(&effective_canister_id, method_name)
-      .with_effective_canister_id(effective_canister_id)
-      .with_arg(Encode!(&params)?)
-      .await
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub enum VecQuery {
+    Embeddings(Vec<f32>),
 }
 
-pub async fn call_query_method<T: CandidType>(canister_called: &str, method_name: &str, params: T) -> Result<Vec<u8>, AgentError>
-{
-    let agent = init_icp_agent().await?;
-    let effective_canister_id = Principal::from_text(canister_called).unwrap();
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PlainDoc {
+    pub content: String,
+}
 
-    agent.query(&effective_canister_id, method_name)
-      .with_effective_canister_id(effective_canister_id)
-      .with_arg(Encode!(&params)?)
-      .await
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub struct VecDoc {
+    pub content: String,
+    pub embeddings: Vec<f32>,
 }
@@ -0,0 +1,198 @@
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use candid::{Decode, Encode, Nat};
+use serde::{Deserialize, Serialize};
+
+use crate::{get_now_secs, log};
+
+use super::error::CanisterError;
+use super::IcpClient;
+
+const DEFAULT_INTERVAL_SECS: u64 = 60;
+const DEFAULT_HISTORY_LEN: usize = 60;
+const DEFAULT_MIN_CYCLES: u128 = 1_000_000_000_000;
+const DEFAULT_MAX_LATENCY_MS: u128 = 5_000;
+const DEFAULT_CYCLES_METHOD: &str = "cycles_balance";
+
+#[derive(Debug, Clone)]
+pub struct MonitorConfig {
+    pub interval: Duration,
+    pub history_len: usize,
+    /// Warn when a canister reports fewer cycles than this.
+    pub min_cycles: u128,
+    pub max_latency_ms: u128,
+    /// Query method that returns the cycle balance, for canisters that expose one.
+    pub cycles_method: String,
+}
+
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        MonitorConfig {
+            interval: Duration::from_secs(DEFAULT_INTERVAL_SECS),
+            history_len: DEFAULT_HISTORY_LEN,
+            min_cycles: DEFAULT_MIN_CYCLES,
+            max_latency_ms: DEFAULT_MAX_LATENCY_MS,
+            cycles_method: DEFAULT_CYCLES_METHOD.to_string(),
+        }
+    }
+}
+
+impl MonitorConfig {
+    pub fn from_env() -> Self {
+        let mut config = MonitorConfig::default();
+
+        if let Some(secs) = std::env::var("ICP_MONITOR_INTERVAL_SECS").ok().and_then(|s| s.parse::<u64>().ok()) {
+            config.interval = Duration::from_secs(secs.max(1));
+        }
+        if let Some(len) = std::env::var("ICP_MONITOR_HISTORY").ok().and_then(|s| s.parse::<usize>().ok()) {
+            config.history_len = len.max(1);
+        }
+        if let Some(cycles) = std::env::var("ICP_MIN_CYCLES").ok().and_then(|s| s.parse::<u128>().ok()) {
+            config.min_cycles = cycles;
+        }
+        if let Some(ms) = std::env::var("ICP_MAX_LATENCY_MS").ok().and_then(|s| s.parse::<u128>().ok()) {
+            config.max_latency_ms = ms;
+        }
+        if let Ok(method) = std::env::var("ICP_CYCLES_METHOD") {
+            config.cycles_method = method;
+        }
+
+        config
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CanisterHealth {
+    pub checked_at: u64,
+    pub reachable: bool,
+    pub latency_ms: u128,
+    pub cycles: Option<u128>,
+    pub error: Option<String>,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CanisterReport {
+    pub name: String,
+    pub canister_id: String,
+    pub latest: Option<CanisterHealth>,
+    pub history: Vec<CanisterHealth>,
+}
+
+/// Polls every configured canister and keeps a rolling history of its health, shared with the admin endpoint.
+#[derive(Clone)]
+pub struct CanisterMonitor {
+    icp: IcpClient,
+    config: MonitorConfig,
+    history: Arc<Mutex<HashMap<String, VecDeque<CanisterHealth>>>>,
+}
+
+fn decode_cycles(bytes: &[u8]) -> Option<u128> {
+    if let Ok(cycles) = Decode!(bytes, Nat) {
+        return cycles.0.try_into().ok();
+    }
+    if let Ok(cycles) = Decode!(bytes, u128) {
+        return Some(cycles);
+    }
+    Decode!(bytes, u64).ok().map(|cycles| cycles as u128)
+}
+
+impl CanisterMonitor {
+    pub fn new(icp: IcpClient, config: MonitorConfig) -> Self {
+        CanisterMonitor {
+            icp,
+            config,
+            history: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn config(&self) -> &MonitorConfig {
+        &self.config
+    }
+
+    async fn probe(&self, canister_id: &str) -> CanisterHealth {
+        let started = Instant::now();
+        let arg = Encode!().unwrap_or_default();
+        let result = self.icp.query_raw(canister_id, &self.config.cycles_method, arg).await;
+        let latency_ms = started.elapsed().as_millis();
+
+        // A reject still proves the canister is up, it just does not expose its balance.
+        let (reachable, cycles, error) = match result {
+            Ok(bytes) => (true, decode_cycles(&bytes), None),
+            Err(e @ CanisterError::Reject { .. }) => (true, None, Some(e.to_string())),
+            Err(e) => (false, None, Some(e.to_string())),
+        };
+
+        let mut warnings = vec![];
+        if !reachable {
+            warnings.push("unreachable".to_string());
+        }
+        if latency_ms > self.config.max_latency_ms {
+            warnings.push(format!("latency {}ms above {}ms", latency_ms, self.config.max_latency_ms));
+        }
+        if let Some(cycles) = cycles {
+            if cycles < self.config.min_cycles {
+                warnings.push(format!("cycles {} below {}", cycles, self.config.min_cycles));
+            }
+        }
+
+        CanisterHealth {
+            checked_at: get_now_secs(),
+            reachable,
+            latency_ms,
+            cycles,
+            error,
+            warnings,
+        }
+    }
+
+    pub async fn poll_once(&self) {
+        let canisters = self.icp.canisters().clone();
+
+        for (name, canister_id) in canisters.all() {
+            let health = self.probe(canister_id).await;
+            for warning in health.warnings.iter() {
+                log!("canister {} ({}) warning: {}", name, canister_id, warning);
+            }
+
+            if let Ok(mut history) = self.history.lock() {
+                let entries = history.entry(name.to_string()).or_default();
+                entries.push_back(health);
+                while entries.len() > self.config.history_len {
+                    entries.pop_front();
+                }
+            }
+        }
+    }
+
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(self.config.interval);
+        loop {
+            interval.tick().await;
+            self.poll_once().await;
+        }
+    }
+
+    pub fn reports(&self) -> Vec<CanisterReport> {
+        let history = self.history.lock().map(|h| h.clone()).unwrap_or_default();
+
+        self.icp
+            .canisters()
+            .all()
+            .into_iter()
+            .map(|(name, canister_id)| {
+                let entries: Vec<CanisterHealth> = history.get(name).map(|h| h.iter().cloned().collect()).unwrap_or_default();
+                CanisterReport {
+                    name: name.to_string(),
+                    canister_id: canister_id.to_string(),
+                    latest: entries.last().cloned(),
+                    history: entries,
+                }
+            })
+            .collect()
+    }
+}
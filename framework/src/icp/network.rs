@@ -0,0 +1,96 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+
+use super::{
+    AGENT_BATTERY_CANISTER, AGENT_SMITH_CANISTER, DEFAULT_IC_GATEWAY, ENDPOINT_URL, NAIS_MATRIX_CANISTER,
+    NAIS_VECTOR_CANISTER,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum NetworkProfile {
+    Mainnet,
+    Local,
+    Test,
+}
+
+impl NetworkProfile {
+    pub fn from_env() -> Self {
+        NetworkProfile::parse(&std::env::var("ICP_NETWORK").unwrap_or_default())
+    }
+
+    /// `local` and `test` select those profiles, anything else is mainnet.
+    pub fn parse(name: &str) -> Self {
+        match name.trim().to_lowercase().as_str() {
+            "local" => NetworkProfile::Local,
+            "test" => NetworkProfile::Test,
+            _ => NetworkProfile::Mainnet,
+        }
+    }
+
+    pub fn gateway(&self) -> &'static str {
+        match self {
+            NetworkProfile::Mainnet => DEFAULT_IC_GATEWAY,
+            NetworkProfile::Local | NetworkProfile::Test => ENDPOINT_URL,
+        }
+    }
+
+    /// Only a local replica has a root key the agent does not already know.
+    pub fn fetch_root_key(&self) -> bool {
+        !matches!(self, NetworkProfile::Mainnet)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CanisterIds {
+    pub agent_smith: String,
+    pub nais_matrix: String,
+    pub agent_battery: String,
+    pub nais_vector: String,
+}
+
+impl Default for CanisterIds {
+    fn default() -> Self {
+        CanisterIds {
+            agent_smith: AGENT_SMITH_CANISTER.to_string(),
+            nais_matrix: NAIS_MATRIX_CANISTER.to_string(),
+            agent_battery: AGENT_BATTERY_CANISTER.to_string(),
+            nais_vector: NAIS_VECTOR_CANISTER.to_string(),
+        }
+    }
+}
+
+impl CanisterIds {
+    /// Reads `<NAME>_CANISTER_ID`, then the `CANISTER_ID_<NAME>` variables `dfx deploy` writes to `.env`.
+    pub fn from_env(profile: NetworkProfile) -> Result<Self> {
+        CanisterIds::from_lookup(profile, |var| std::env::var(var).ok())
+    }
+
+    /// Same as `from_env`, with the variables read through `var`.
+    /// Only mainnet falls back to the built-in ids, so a local or test run never calls the production canisters.
+    pub fn from_lookup(profile: NetworkProfile, var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let defaults = CanisterIds::default();
+        let lookup = |name: &str, dfx_name: &str, default: String| -> Result<String> {
+            match var(&format!("{}_CANISTER_ID", name)).or_else(|| var(&format!("CANISTER_ID_{}", dfx_name))) {
+                Some(id) => Ok(id),
+                None if profile == NetworkProfile::Mainnet => Ok(default),
+                None => Err(anyhow!("{}_CANISTER_ID or CANISTER_ID_{} must be set for {:?}", name, dfx_name, profile)),
+            }
+        };
+
+        Ok(CanisterIds {
+            agent_smith: lookup("AGENT_SMITH", "AGENT", defaults.agent_smith)?,
+            nais_matrix: lookup("NAIS_MATRIX", "MATRIX", defaults.nais_matrix)?,
+            agent_battery: lookup("AGENT_BATTERY", "BATTERY", defaults.agent_battery)?,
+            nais_vector: lookup("NAIS_VECTOR", "VECTOR", defaults.nais_vector)?,
+        })
+    }
+
+    pub fn all(&self) -> Vec<(&'static str, &str)> {
+        vec![
+            ("agent_smith", self.agent_smith.as_str()),
+            ("nais_matrix", self.nais_matrix.as_str()),
+            ("agent_battery", self.agent_battery.as_str()),
+            ("nais_vector", self.nais_vector.as_str()),
+        ]
+    }
+}
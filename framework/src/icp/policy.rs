@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+const DEFAULT_CALL_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_QUERY_RETRIES: u32 = 3;
+const DEFAULT_BACKOFF_BASE_MS: u64 = 200;
+const DEFAULT_BACKOFF_MAX_MS: u64 = 5_000;
+const DEFAULT_BREAKER_THRESHOLD: u32 = 5;
+const DEFAULT_BREAKER_COOLDOWN_SECS: u64 = 30;
+
+/// Timeouts, retries and circuit-breaker thresholds applied to every canister call.
+#[derive(Debug, Clone)]
+pub struct CallPolicy {
+    pub default_timeout: Duration,
+    pub method_timeouts: HashMap<String, Duration>,
+    /// Extra attempts for query calls; updates are never retried since they may have been applied.
+    pub query_retries: u32,
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+    /// Consecutive transient failures that open the breaker of a canister.
+    pub breaker_threshold: u32,
+    pub breaker_cooldown: Duration,
+}
+
+impl Default for CallPolicy {
+    fn default() -> Self {
+        CallPolicy {
+            default_timeout: Duration::from_secs(DEFAULT_CALL_TIMEOUT_SECS),
+            method_timeouts: HashMap::new(),
+            query_retries: DEFAULT_QUERY_RETRIES,
+            backoff_base: Duration::from_millis(DEFAULT_BACKOFF_BASE_MS),
+            backoff_max: Duration::from_millis(DEFAULT_BACKOFF_MAX_MS),
+            breaker_threshold: DEFAULT_BREAKER_THRESHOLD,
+            breaker_cooldown: Duration::from_secs(DEFAULT_BREAKER_COOLDOWN_SECS),
+        }
+    }
+}
+
+fn env_u64(name: &str) -> Option<u64> {
+    std::env::var(name).ok().and_then(|s| s.trim().parse::<u64>().ok())
+}
+
+impl CallPolicy {
+    /// `ICP_METHOD_TIMEOUTS` takes a list like `upload_session_assets=120,request_create_pato=30` (seconds).
+    pub fn from_env() -> Self {
+        let mut policy = CallPolicy::default();
+
+        if let Some(secs) = env_u64("ICP_CALL_TIMEOUT_SECS") {
+            policy.default_timeout = Duration::from_secs(secs);
+        }
+        if let Ok(timeouts) = std::env::var("ICP_METHOD_TIMEOUTS") {
+            for entry in timeouts.split(',') {
+                if let Some((method, secs)) = entry.split_once('=') {
+                    match secs.trim().parse::<u64>() {
+                        Ok(secs) => {
+                            policy.method_timeouts.insert(method.trim().to_string(), Duration::from_secs(secs));
+                        }
+                        Err(_) => println!("ignore invalid ICP_METHOD_TIMEOUTS entry: {}", entry),
+                    }
+                }
+            }
+        }
+        if let Some(retries) = env_u64("ICP_QUERY_RETRIES") {
+            policy.query_retries = retries as u32;
+        }
+        if let Some(ms) = env_u64("ICP_BACKOFF_BASE_MS") {
+            policy.backoff_base = Duration::from_millis(ms);
+        }
+        if let Some(ms) = env_u64("ICP_BACKOFF_MAX_MS") {
+            policy.backoff_max = Duration::from_millis(ms);
+        }
+        if let Some(threshold) = env_u64("ICP_BREAKER_THRESHOLD") {
+            policy.breaker_threshold = threshold.max(1) as u32;
+        }
+        if let Some(secs) = env_u64("ICP_BREAKER_COOLDOWN_SECS") {
+            policy.breaker_cooldown = Duration::from_secs(secs);
+        }
+
+        policy
+    }
+
+    pub fn timeout_for(&self, method_name: &str) -> Duration {
+        self.method_timeouts.get(method_name).copied().unwrap_or(self.default_timeout)
+    }
+
+    /// Delay before retry number `attempt + 1`: base, 2 * base, 4 * base, ... capped at `backoff_max`.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.backoff_base
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.backoff_max)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum BreakerState {
+    #[default]
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct CircuitBreaker {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    probing: bool,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn state(&self, policy: &CallPolicy) -> BreakerState {
+        match self.opened_at {
+            None => BreakerState::Closed,
+            Some(at) if at.elapsed() >= policy.breaker_cooldown => BreakerState::HalfOpen,
+            Some(_) => BreakerState::Open,
+        }
+    }
+
+    pub(crate) fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures
+    }
+
+    /// Lets a single probe through once the cooldown has passed, everything else fails fast while open.
+    pub(crate) fn allow(&mut self, policy: &CallPolicy) -> bool {
+        match self.state(policy) {
+            BreakerState::Closed => true,
+            BreakerState::HalfOpen if !self.probing => {
+                self.probing = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub(crate) fn on_success(&mut self) {
+        *self = CircuitBreaker::default();
+    }
+
+    pub(crate) fn on_failure(&mut self, policy: &CallPolicy) {
+        self.consecutive_failures += 1;
+        self.probing = false;
+        if self.consecutive_failures >= policy.breaker_threshold {
+            self.opened_at = Some(Instant::now());
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use serde::{Deserialize, Serialize};
+
+use super::{PlainDoc, VecDoc};
+
+/// Where a passage in the vector canister came from. The canister only keeps a content string per document,
+/// so the metadata travels inside it as JSON.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChunkMeta {
+    pub doc_id: String,
+    pub owner: String,
+    pub title: String,
+    /// Character offset of the passage in the extracted document text.
+    pub offset: usize,
+    /// Set on topic comments, the md5 of the topic.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct VectorChunk {
+    pub meta: Option<ChunkMeta>,
+    pub text: String,
+}
+
+impl VectorChunk {
+    pub fn new(meta: ChunkMeta, text: String) -> Self {
+        VectorChunk { meta: Some(meta), text }
+    }
+
+    pub fn to_content(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    /// Documents added before chunked ingestion hold plain text and come back without metadata.
+    pub fn from_content(content: &str) -> Self {
+        match serde_json::from_str::<VectorChunk>(content) {
+            Ok(chunk) if chunk.meta.is_some() => chunk,
+            _ => VectorChunk { meta: None, text: content.to_string() },
+        }
+    }
+
+    pub fn into_doc(self, embeddings: Vec<f32>) -> VecDoc {
+        VecDoc { content: self.to_content(), embeddings }
+    }
+}
+
+impl From<&PlainDoc> for VectorChunk {
+    fn from(doc: &PlainDoc) -> Self {
+        VectorChunk::from_content(&doc.content)
+    }
+}
+
+/// Restricts a vector search to passages with matching metadata. Documents without metadata only match an empty filter.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct VectorFilter {
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub sigs: Vec<String>,
+    #[serde(default)]
+    pub topic_id: Option<String>,
+}
+
+impl VectorFilter {
+    pub fn is_empty(&self) -> bool {
+        self.owner.is_none() && self.sigs.is_empty() && self.topic_id.is_none()
+    }
+
+    pub fn matches(&self, meta: Option<&ChunkMeta>) -> bool {
+        let Some(meta) = meta else {
+            return self.is_empty();
+        };
+
+        let owner_matches = match &self.owner {
+            Some(owner) => &meta.owner == owner,
+            None => true,
+        };
+        let topic_matches = match &self.topic_id {
+            Some(topic_id) => meta.topic_id.as_ref() == Some(topic_id),
+            None => true,
+        };
+        owner_matches && topic_matches && (self.sigs.is_empty() || self.sigs.contains(&meta.doc_id))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScoredChunk {
+    /// Similarity to the query, when the passage's vector was at hand to compute it.
+    pub score: Option<f32>,
+    pub meta: Option<ChunkMeta>,
+    pub text: String,
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum();
+    let norm_a: f32 = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b: f32 = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
@@ -69,6 +69,14 @@ pub const DEFAULT_TEMPLATE: &str = r#"You are a person who likes to talk to peop
 pub struct DataResponse {
     pub content: String,
     pub code: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Errors that know the HTTP status and the `DataResponse.error` code they are reported with.
+pub trait ApiError: std::error::Error + Send + Sync + 'static {
+    fn status_code(&self) -> u16;
+    fn error_code(&self) -> &'static str;
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -91,6 +91,8 @@ pub struct CharacterGenRequest {
     pub name: ::prost::alloc::string::String,
     #[prost(string, tag = "3")]
     pub gender: ::prost::alloc::string::String,
+    #[prost(string, tag = "4")]
+    pub personality: ::prost::alloc::string::String,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CharacterGenResponse {
@@ -103,6 +105,10 @@ pub struct MultiImagesGenRequest {
     pub prompt: ::prost::alloc::string::String,
     #[prost(int32, tag = "2")]
     pub num_images: i32,
+    #[prost(string, tag = "3")]
+    pub negative_prompt: ::prost::alloc::string::String,
+    #[prost(int64, tag = "4")]
+    pub seed: i64,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct MultiImagesGenResponse {
@@ -218,6 +224,13 @@ pub struct AnswerReply {
     pub answer: ::prost::alloc::string::String,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AnswerRequest {
+    #[prost(string, tag = "1")]
+    pub question: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub prompt: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct TileClassifyRequest {
     #[prost(string, repeated, tag = "1")]
     pub name: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
@@ -808,6 +821,28 @@ pub mod chat_svc_client {
                 .insert(GrpcMethod::new("llmchat.ChatSvc", "GenCharacterWithPrompt"));
             self.inner.unary(req, path, codec).await
         }
+        pub async fn answer_with_prompt(
+            &mut self,
+            request: impl tonic::IntoRequest<super::AnswerRequest>,
+        ) -> std::result::Result<tonic::Response<super::AnswerReply>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/llmchat.ChatSvc/AnswerWithPrompt",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("llmchat.ChatSvc", "AnswerWithPrompt"));
+            self.inner.unary(req, path, codec).await
+        }
     }
 }
 /// Generated server implementations.
@@ -945,6 +980,10 @@ pub mod chat_svc_server {
             tonic::Response<super::CharacterGenResponse>,
             tonic::Status,
         >;
+        async fn answer_with_prompt(
+            &self,
+            request: tonic::Request<super::AnswerRequest>,
+        ) -> std::result::Result<tonic::Response<super::AnswerReply>, tonic::Status>;
     }
     #[derive(Debug)]
     pub struct ChatSvcServer<T> {
@@ -1917,6 +1956,49 @@ pub mod chat_svc_server {
                     };
                     Box::pin(fut)
                 }
+                "/llmchat.ChatSvc/AnswerWithPrompt" => {
+                    #[allow(non_camel_case_types)]
+                    struct AnswerWithPromptSvc<T: ChatSvc>(pub Arc<T>);
+                    impl<T: ChatSvc> tonic::server::UnaryService<super::AnswerRequest>
+                    for AnswerWithPromptSvc<T> {
+                        type Response = super::AnswerReply;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::AnswerRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as ChatSvc>::answer_with_prompt(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = AnswerWithPromptSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
                 _ => {
                     Box::pin(async move {
                         Ok(
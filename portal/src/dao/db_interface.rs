@@ -11,6 +11,7 @@ pub async fn get_all_rows(
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
     let (token, session, table) = path.into_inner();
@@ -23,6 +24,7 @@ pub async fn get_row_by_id(
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
     let (token, session, table, id) = path.into_inner();
@@ -36,6 +38,7 @@ pub async fn save_table_rows(
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
     let (token, session, table) = path.into_inner();
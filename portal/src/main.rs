@@ -1,28 +1,28 @@
 pub mod dao;
 pub mod model;
 pub mod service;
+#[cfg(test)]
+mod tests;
 
-use crate::service::ai_town::town_register;
 use actix_cors::Cors;
 use actix_multipart::Multipart;
 use actix_web::{
-    http::header::ContentType,
+    http::{header::{self, ContentType}, StatusCode},
     middleware, web, App, HttpResponse, HttpServer, Responder,
 };
-use candid::CandidType;
 use futures::StreamExt;
 use futures::TryStreamExt;
+use tokio::sync::broadcast::error::RecvError;
+use metapower_framework::dao::crawler::{safe_file_name, DownloadError};
 use metapower_framework::compute_md5;
 use metapower_framework::get_now_secs_str;
-use metapower_framework::{
-    dao::crawler::download_image, ensure_directory_exists, DataResponse, XFILES_LOCAL_DIR, XFILES_SERVER
-};
+use metapower_framework::icp::{AgentBatteryCanister, CanisterError, CanisterMonitor, IcpClient, IcpClientConfig, MonitorConfig};
+use metapower_framework::{ApiError, DataResponse};
 use serde::{Deserialize, Serialize};
 use service::ai_town::get_names_by_ids;
 use service::ai_town::request_submit_tags_with_proxy;
+use service::llm_gateway::{LlmClient, LlmConfig};
 use service::llm_proxy::comment_topic;
-use service::llm_proxy::get_pato_meta;
-use service::llm_proxy::set_pato_info_generic;
 use service::llm_proxy::upload_topic_comment_save_in_canister;
 use service::{
     ai_town::{
@@ -31,10 +31,11 @@ use service::{
         query_document_summary, query_kol_rooms, query_pato_by_kol_token,
         query_pato_kol_token, refresh_pato_auth_token, retrieve_pato_by_name, submit_tags, town_hot_topics, town_hots, town_login,
     },
-    bsc_proxy::{monitor_pab_transfer_event, proxy_contract_call_query_kol_staking, proxy_contract_call_query_kol_ticket}, llm_proxy::{upload_image_save_in_canister, upload_knowledge_save_in_canister},
+    bsc_proxy::{monitor_pab_transfer_event, proxy_contract_call_query_kol_staking, proxy_contract_call_query_kol_ticket}, extract::{extract_document, ExtractError}, gallery::{GalleryConfig, GalleryStore, ImageGenError, ImageGenOptions}, idempotency::{IdempotencyConfig, IdempotencyStore}, images::ImageError, jobs::{JobConfig, JobQueue}, knowledge::{ask_knowledge, ingestion_status, AskRequest, KnowledgeDoc, SearchOptions}, profile::{get_profile, profile_history, register_pato, regenerate_asset, update_profile, ProfileAsset, ProfileConfig, ProfileError, ProfileStore, ProfileUpdate}, progress::{ProgressHub, KEEP_ALIVE_SSE, PROGRESS_KEEP_ALIVE_SECS}, llm_proxy::{download_into_store, generate_images, upload_image_save_in_canister, upload_knowledge_save_in_canister}, xfiles::{local_root, XFileKind, XFilesConfig, XFilesError, XFilesStore},
 };
 use sha1::Digest;
-use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Deserialize, Debug)]
 struct TopicChatInfo {
@@ -51,23 +52,16 @@ struct UserInfo {
     pub personality: String,
 }
 
-#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
-pub enum VecQuery {
-    Embeddings(Vec<f32>),
-}
-#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
-pub struct PlainDoc {
-    pub content: String,
-}
-#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
-pub struct VecDoc {
-    pub content: String,
-    pub embeddings: Vec<f32>,
+#[derive(Deserialize, Debug)]
+struct OwnerToken {
+    pub token: String,
 }
 
 #[derive(Deserialize, Debug)]
 struct QueryEmbedInfo {
     input: String,
+    #[serde(flatten)]
+    options: SearchOptions,
 }
 
 #[derive(Deserialize, Debug, Serialize)]
@@ -85,20 +79,129 @@ struct ArchiveInfo {
     content: String,
 }
 
+#[derive(Deserialize, Debug)]
+struct SummaryFile {
+    file_name: String,
+}
+
 #[derive(Deserialize)]
 pub struct PathInfo {
     absolute_path: String,
     saved_name: String,
 }
 
+/// The typed error behind `e`, if it is one the portal reports with its own status and code.
+fn api_error(e: &anyhow::Error) -> Option<&dyn ApiError> {
+    macro_rules! find {
+        ($($ty:ty),* $(,)?) => {
+            $(if let Some(err) = e.downcast_ref::<$ty>() {
+                return Some(err);
+            })*
+        };
+    }
+    find!(CanisterError, ExtractError, ImageError, XFilesError, DownloadError, ImageGenError, ProfileError);
+
+    None
+}
+
+/// Typed failures keep their own status and code; anything else is reported as an internal error.
+fn set_error(resp: &mut DataResponse, e: &anyhow::Error) {
+    match api_error(e) {
+        Some(err) => {
+            resp.code = err.status_code().to_string();
+            resp.error = Some(err.error_code().to_string());
+        }
+        None => {
+            resp.code = String::from("500");
+            resp.error = Some(String::from("internal"));
+        }
+    }
+}
+fn reply(resp: DataResponse) -> HttpResponse {
+    let status = match resp.error {
+        Some(_) => resp.code.parse::<u16>().ok().and_then(|c| StatusCode::from_u16(c).ok()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+        None => StatusCode::OK,
+    };
+
+    HttpResponse::build(status).json(resp)
+}
+
+/// `metapower_portal_icp xfiles verify|gc` checks or cleans the local xfiles store, printing a JSON report.
+fn xfiles_command(command: Option<&str>) -> std::io::Result<()> {
+    let store = XFilesStore::open(XFilesConfig::from_env(&local_root())).map_err(std::io::Error::other)?;
+
+    match command {
+        Some("verify") => {
+            let report = store.verify().map_err(std::io::Error::other)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            if !report.is_clean() {
+                std::process::exit(1);
+            }
+        }
+        Some("gc") => {
+            let report = store.gc().map_err(std::io::Error::other)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        _ => eprintln!("usage: metapower_portal_icp xfiles verify|gc"),
+    }
+
+    Ok(())
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    dotenv::dotenv().ok();
+
+    if std::env::args().nth(1).as_deref() == Some("xfiles") {
+        return xfiles_command(std::env::args().nth(2).as_deref());
+    }
+
     println!("monitor event staking");
     tokio::spawn(monitor_pab_transfer_event());
 
+    let icp_config = IcpClientConfig::from_env().expect("Could not read the icp network config.");
+    println!("icp network {:?} @ {}, canisters: {:?}", icp_config.network, icp_config.gateway, icp_config.canisters);
+    let icp = IcpClient::new(icp_config)
+        .expect("Could not create the icp agent.");
+    match icp.principal() {
+        Ok(principal) => println!("icp identity principal: {}", principal),
+        Err(e) => panic!("icp identity has no principal: {}", e),
+    }
+
+    let root = local_root();
+    let xfiles_config = XFilesConfig::from_env(&root);
+    println!("xfiles store @ {}, served from {}", xfiles_config.root, xfiles_config.server);
+    let xfiles = Arc::new(XFilesStore::open(xfiles_config).expect("Could not open the xfiles store."));
+
+    let llm_config = LlmConfig::from_env(&root);
+    println!("llm gateway @ {}", llm_config.base_url);
+    let llm = LlmClient::new(&llm_config, xfiles.clone()).expect("Could not create the llm gateway client.");
+
+    let job_config = JobConfig::from_env(&root);
+    println!("job queue with {} workers @ {}", job_config.workers, job_config.db_file);
+    let idempotency = Arc::new(IdempotencyStore::open(IdempotencyConfig::from_env(&root)).expect("Could not open the idempotency store."));
+    let profiles = Arc::new(ProfileStore::open(ProfileConfig::from_env(&root)).expect("Could not open the profile store."));
+    let gallery = Arc::new(GalleryStore::open(GalleryConfig::from_env(&root)).expect("Could not open the gallery store."));
+    let progress = ProgressHub::new();
+    let jobs = JobQueue::start(icp.clone(), llm.clone(), job_config, idempotency.clone(), progress.clone(), xfiles.clone(), profiles.clone())
+        .expect("Could not start the job queue.");
+
+    let monitor = CanisterMonitor::new(icp.clone(), MonitorConfig::from_env());
+    println!("monitor canisters every {:?}", monitor.config().interval);
+    tokio::spawn(monitor.clone().run());
+
     println!("metapower portal rest api @ 8030");
-    HttpServer::new(|| {
+    HttpServer::new(move || {
         App::new()
+            .app_data(web::Data::new(icp.clone()))
+            .app_data(web::Data::new(llm.clone()))
+            .app_data(web::Data::new(monitor.clone()))
+            .app_data(web::Data::new(jobs.clone()))
+            .app_data(web::Data::from(idempotency.clone()))
+            .app_data(web::Data::new(progress.clone()))
+            .app_data(web::Data::from(xfiles.clone()))
+            .app_data(web::Data::from(gallery.clone()))
+            .app_data(web::Data::from(profiles.clone()))
             .configure(config_app)
             .wrap(Cors::permissive().supports_credentials().max_age(3600))
             .wrap(middleware::Logger::default())
@@ -125,68 +228,76 @@ async fn get_index() -> HttpResponse {
             "#,
     )
 }
-async fn portal_register(user_info: web::Json<UserInfo>) -> actix_web::Result<impl Responder> {
+async fn portal_register(
+    icp: web::Data<IcpClient>,
+    profiles: web::Data<ProfileStore>,
+    user_info: web::Json<UserInfo>,
+) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
     let info = user_info.into_inner();
 
-    match town_register(info.name).await {
+    match register_pato(&icp, &profiles, info.name, info.gender, info.personality).await {
         Ok(id) => {
             resp.content = id;
         }
         Err(e) => {
             println!("error: {}", e);
-            resp.code = String::from("500");
+            set_error(&mut resp, &e);
         }
     }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
-async fn portal_kol_list() -> actix_web::Result<impl Responder> {
+async fn portal_kol_list(icp: web::Data<IcpClient>) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
-    match query_kol_rooms().await {
+    match query_kol_rooms(&icp).await {
         Ok(kols) => {
             resp.content = kols;
         }
         Err(e) => {
             println!("error: {}", e);
-            resp.code = String::from("500");
+            set_error(&mut resp, &e);
         }
     }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
-async fn portal_become_kol(info: web::Path<(String,String)>) -> actix_web::Result<impl Responder> {
+async fn portal_become_kol(icp: web::Data<IcpClient>, info: web::Path<(String,String)>) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
     let (id, from) = info.into_inner();
 
-    match become_kol(id, from).await {
+    match become_kol(&icp, id, from).await {
         Ok(token) => {
             resp.content = token;
         }
         Err(e) => {
             println!("error: {}", e);
-            resp.code = String::from("500");
+            set_error(&mut resp, &e);
         }
     }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
 async fn portal_query_kol_staking(info: web::Path<String>) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: "0".to_string(),
         code: String::from("404"),
+        error: None,
     };
 
     let from = info.into_inner();
@@ -203,12 +314,13 @@ async fn portal_query_kol_staking(info: web::Path<String>) -> actix_web::Result<
         }
     }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
 async fn portal_query_kol_ticket(info: web::Path<String>) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: "0".to_string(),
         code: String::from("404"),
+        error: None,
     };
 
     let from = info.into_inner();
@@ -223,29 +335,36 @@ async fn portal_query_kol_ticket(info: web::Path<String>) -> actix_web::Result<i
         }
     }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
-async fn portal_join_kol(info: web::Path<(String, String, String)>) -> actix_web::Result<impl Responder> {
+async fn portal_join_kol(icp: web::Data<IcpClient>, info: web::Path<(String, String, String)>) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
     let (follower, kol, from) = info.into_inner();
-    let follower_name = get_name_by_id(follower.clone()).await.unwrap_or_default();
-    let kol_name = get_name_by_id(kol.clone()).await.unwrap_or_default();
+    let follower_name = get_name_by_id(&icp, follower.clone()).await.unwrap_or_default();
+    let kol_name = get_name_by_id(&icp, kol.clone()).await.unwrap_or_default();
 
-    if let Err(e) = follow_kol(kol, follower, kol_name, follower_name).await {
+    if let Err(e) = follow_kol(&icp, kol, follower, kol_name, follower_name).await {
         println!("error: {}", e);
-        resp.code = String::from("500");
+        set_error(&mut resp, &e);
     }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
-async fn portal_upload_knowledge(mut payload: Multipart) -> actix_web::Result<impl Responder> {
+async fn portal_upload_knowledge(
+    icp: web::Data<IcpClient>,
+    llm: web::Data<LlmClient>,
+    progress: web::Data<ProgressHub>,
+    mut payload: Multipart,
+) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
     // Initialize variables to hold the file bytes and the message
@@ -296,217 +415,327 @@ async fn portal_upload_knowledge(mut payload: Multipart) -> actix_web::Result<im
         let mut session = format!("{:x}", hasher.finalize());
         let filename_saved = "content.txt".to_string();
         
-        let header: [u8; 4] = file_bytes.as_slice()[0..4].try_into().unwrap_or_default();
-        if &header == b"%PDF" {
-            let content = pdf_extract::extract_text_from_mem(&file_bytes).unwrap_or_default();
-            println!("pdf file detected: {}", content.len());
-            if !content.is_empty() {
-                let mut hasher = sha1::Sha1::new();
-                hasher.update(&content);
-                // hasher.update(get_now_secs_str());
-                session = format!("{:x}", hasher.finalize());
-                file_bytes = content.as_bytes().to_vec();    
+        match extract_document(file_bytes.clone(), filename.clone()).await {
+            Ok(extracted) => {
+                println!("{:?} file detected: {}", extracted.format, extracted.text.len());
+                if extracted.text.as_bytes() != file_bytes.as_slice() {
+                    let mut hasher = sha1::Sha1::new();
+                    hasher.update(&extracted.text);
+                    session = format!("{:x}", hasher.finalize());
+                    file_bytes = extracted.text.into_bytes();
+                }
+            }
+            Err(e) => {
+                resp.content = format!("{}", e);
+                set_error(&mut resp, &e.into());
+                return Ok(reply(resp));
             }
         }
-        
-        match upload_knowledge_save_in_canister(session, id,  filename_saved, file_bytes).await
+
+        let doc = KnowledgeDoc {
+            owner: id,
+            sig: session,
+            file_name: filename_saved,
+            title: filename,
+        };
+        match upload_knowledge_save_in_canister(&icp, &llm, &progress, &doc, file_bytes).await
         {
             Ok(url) => {
                 resp.content = url;
             }
             Err(e) => {
                 resp.content = format!("{}", e);
-                resp.code = String::from("500");
+                set_error(&mut resp, &e);
             }
         }
     }
 
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
-async fn portal_login(id: web::Path<String>) -> actix_web::Result<impl Responder> {
-    let resp = DataResponse {
+async fn portal_login(icp: web::Data<IcpClient>, id: web::Path<String>) -> actix_web::Result<impl Responder> {
+    let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
-    let _ = town_login(id.into_inner()).await;
+    if let Err(e) = town_login(&icp, id.into_inner()).await {
+        println!("error: {}", e);
+        set_error(&mut resp, &e);
+    }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
-async fn portal_town_hots() -> actix_web::Result<impl Responder> {
+async fn portal_town_hots(icp: web::Data<IcpClient>) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
-    resp.content = town_hots().await;
+    match town_hots(&icp).await {
+        Ok(content) => resp.content = content,
+        Err(e) => {
+            println!("error: {}", e);
+            set_error(&mut resp, &e);
+        }
+    }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
-async fn portal_town_hot_topics() -> actix_web::Result<impl Responder> {
+async fn portal_town_hot_topics(icp: web::Data<IcpClient>) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
-    resp.content = town_hot_topics().await;
+    match town_hot_topics(&icp).await {
+        Ok(content) => resp.content = content,
+        Err(e) => {
+            println!("error: {}", e);
+            set_error(&mut resp, &e);
+        }
+    }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
 
 async fn portal_query_summary(
-    data: web::Path<(String, String, String)>,
+    icp: web::Data<IcpClient>,
+    data: web::Path<(String, String)>,
+    file: web::Query<SummaryFile>,
 ) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
-    let (id, sig, file_name) = data.into_inner();
+    let (id, sig) = data.into_inner();
+    let file_name = file.into_inner().file_name;
 
-    match query_document_summary(id, sig, file_name).await {
+    match query_document_summary(&icp, id, sig, file_name).await {
         Ok(info) => {
             resp.content = info;
         }
         Err(e) => {
             println!("error: {}", e);
-            resp.code = String::from("500");
+            set_error(&mut resp, &e);
+        }
+    }
+
+    Ok(reply(resp))
+}
+async fn portal_query_ingestion(
+    icp: web::Data<IcpClient>,
+    data: web::Path<(String, String, String)>,
+) -> actix_web::Result<impl Responder> {
+    let mut resp = DataResponse {
+        content: String::from(""),
+        code: String::from("200"),
+        error: None,
+    };
+
+    let (id, sig, file_name) = data.into_inner();
+
+    match ingestion_status(&icp, &id, &sig, &file_name).await {
+        Ok(Some(status)) => {
+            resp.content = serde_json::to_string(&status).unwrap_or_default();
+        }
+        Ok(None) => {
+            resp.code = String::from("404");
+            resp.error = Some("ingestion_not_found".to_string());
+        }
+        Err(e) => {
+            println!("error: {}", e);
+            set_error(&mut resp, &e);
         }
     }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
-async fn portal_get_predefined_tags() -> actix_web::Result<impl Responder> {
+async fn portal_ask_knowledge(
+    icp: web::Data<IcpClient>,
+    llm: web::Data<LlmClient>,
+    data: web::Json<AskRequest>,
+) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
-    match get_predefined_tags().await {
+    let request = data.into_inner();
+    if request.id.is_none() && request.sigs.is_empty() {
+        resp.code = String::from("400");
+        resp.error = Some("knowledge_scope_required".to_string());
+        return Ok(reply(resp));
+    }
+
+    match ask_knowledge(&icp, &llm, request).await {
+        Ok(Some(answer)) => {
+            resp.content = serde_json::to_string(&answer).unwrap_or_default();
+        }
+        Ok(None) => {
+            resp.code = String::from("404");
+            resp.error = Some("knowledge_not_found".to_string());
+        }
+        Err(e) => {
+            println!("error: {}", e);
+            set_error(&mut resp, &e);
+        }
+    }
+
+    Ok(reply(resp))
+}
+async fn portal_get_predefined_tags(icp: web::Data<IcpClient>) -> actix_web::Result<impl Responder> {
+    let mut resp = DataResponse {
+        content: String::from(""),
+        code: String::from("200"),
+        error: None,
+    };
+
+    match get_predefined_tags(&icp).await {
         Ok(content) => resp.content = content,
         Err(e) => {
             println!("read tags json file error: {}", e);
-            resp.code = String::from("404")
+            set_error(&mut resp, &e);
         }
     }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
 async fn portal_submit_tags(
+    icp: web::Data<IcpClient>,
     id: web::Path<(String, String)>,
     tags: web::Json<Vec<String>>,
 ) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
     let (id, session) = id.into_inner();
-    match submit_tags(id, session, tags.into_inner()).await {
+    match submit_tags(&icp, id, session, tags.into_inner()).await {
         Ok(avatar_url) => resp.content = avatar_url,
         Err(e) => {
-            resp.code = String::from("500");
             resp.content = e.to_string();
+            set_error(&mut resp, &e);
         }
     }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
 
 async fn proxy_submit_tags(
+    jobs: web::Data<JobQueue>,
     data: web::Path<(String, String)>,
     tags: web::Json<Vec<String>>,
 ) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
     let (id, session) = data.into_inner();
 
-    match request_submit_tags_with_proxy(id, session, tags.into_inner()).await {
-        Ok(_) => (),
+    match request_submit_tags_with_proxy(&jobs, id, session, tags.into_inner()) {
+        Ok(job_id) => resp.content = job_id,
         Err(e) => {
             println!("request_submit_tags_with_proxy error: {}", e);
             resp.content = e.to_string();
+            set_error(&mut resp, &e);
         }
     }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
 async fn submit_topics(
+    icp: web::Data<IcpClient>,
     data: web::Path<String>,
     topics: web::Json<(String, String)>,
 ) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
     let id = data.into_inner();
 
-    match set_pato_info_generic(id.clone(), topics.into_inner(), "set_topics_of").await{
+    match icp.set_topics_of(id.clone(), topics.into_inner()).await{
         Ok(_) => (),
         Err(e) => {
             println!("submit_topics error: {}", e);
             resp.content = e.to_string();
+            set_error(&mut resp, &e.into());
         }
     }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
-async fn get_topics(data: web::Path<String>) -> actix_web::Result<impl Responder> {
+async fn get_topics(icp: web::Data<IcpClient>, data: web::Path<String>) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("404"),
+        error: None,
     };
 
     let id = data.into_inner();
 
-    match get_pato_meta(id, "topics_of").await {
+    match icp.topics_of(id).await {
         Ok(topics) => {
             resp.content = topics;
             resp.code = String::from("200");
         }
         Err(e) => {
             println!("error: {}", e);
+            set_error(&mut resp, &e.into());
         }
     }
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
 
-async fn portal_get_pato_info(id: web::Path<String>) -> actix_web::Result<impl Responder> {
+async fn portal_get_pato_info(icp: web::Data<IcpClient>, id: web::Path<String>) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
     let id = id.into_inner();
 
-    match get_pato_info(id).await {
-        Ok(info) => {
-            resp.content = serde_json::to_string(&info).unwrap_or_else(|e| {
+    match get_pato_info(&icp, id).await {
+        Ok(info) => match serde_json::to_string(&info) {
+            Ok(content) => resp.content = content,
+            Err(e) => {
                 println!("error: {}", e);
-                resp.code = String::from("500");
-                "".to_string()
-            });
-        }
+                set_error(&mut resp, &e.into());
+            }
+        },
         Err(e) => {
             println!("error: {}", e);
-            resp.code = String::from("500");
+            set_error(&mut resp, &e);
         }
     }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
 
 async fn portal_upload_image(
+    icp: web::Data<IcpClient>,
+    llm: web::Data<LlmClient>,
+    xfiles: web::Data<XFilesStore>,
     mut payload: Multipart,
 ) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
     // Initialize variables to hold the file bytes and the message
@@ -545,204 +774,220 @@ async fn portal_upload_image(
 
     if has_file_uploaded {
         let session = format!("{:x}", hasher.finalize());
-        match upload_image_save_in_canister(session, id, file_bytes).await
+        match upload_image_save_in_canister(&icp, &llm, &xfiles, session, id, file_bytes).await
         {
-            Ok(url) => {
-                resp.content = url;
+            Ok(uploaded) => {
+                resp.content = serde_json::to_string(&uploaded).unwrap_or_default();
             }
             Err(e) => {
                 resp.content = format!("{}", e);
-                resp.code = String::from("500");
+                set_error(&mut resp, &e);
             }
         }
     }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
-async fn portal_query_embeddings(data: web::Json<QueryEmbedInfo>) -> actix_web::Result<impl Responder> {
+async fn portal_query_embeddings(icp: web::Data<IcpClient>, llm: web::Data<LlmClient>, data: web::Json<QueryEmbedInfo>) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
     let embed = data.into_inner();
     println!("embed: {:?}", embed);
 
-    match service::ai_town::query_document_embeddings(embed.input).await {
+    match service::ai_town::query_document_embeddings(&icp, &llm, embed.input, &embed.options).await {
         Ok(answer) => {
             resp.content = answer;
         }
         Err(e) => {
             println!("error: {}", e);
-            resp.code = String::from("500");
+            set_error(&mut resp, &e);
         }
     }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
 async fn portal_archive_pato_session(
+    icp: web::Data<IcpClient>,
+    llm: web::Data<LlmClient>,
+    progress: web::Data<ProgressHub>,
     form: web::Json<ArchiveInfo>,
 ) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
     let archive = form.into_inner();
 
-    match service::ai_town::archive_pato_session(archive.id, archive.session, archive.content).await
+    match service::ai_town::archive_pato_session(&icp, &llm, &progress, archive.id, archive.session, archive.content).await
     {
         Ok(file_url) => {
             resp.content = file_url;
         }
         Err(e) => {
             resp.content = format!("{}", e);
-            resp.code = String::from("500");
+            set_error(&mut resp, &e);
         }
     }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
-async fn portal_get_pato_auth_token(id: web::Path<String>) -> actix_web::Result<impl Responder> {
+async fn portal_get_pato_auth_token(icp: web::Data<IcpClient>, id: web::Path<String>) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
-    match refresh_pato_auth_token(id.into_inner()).await {
+    match refresh_pato_auth_token(&icp, id.into_inner()).await {
         Ok(token) => {
             resp.content = token;
         }
         Err(e) => {
             println!("error: {}", e);
-            resp.code = String::from("500");
+            set_error(&mut resp, &e);
         }
     }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
-async fn portal_get_pato_kol_token(id: web::Path<String>) -> actix_web::Result<impl Responder> {
+async fn portal_get_pato_kol_token(icp: web::Data<IcpClient>, id: web::Path<String>) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
-    match query_pato_kol_token(id.into_inner()).await {
+    match query_pato_kol_token(&icp, id.into_inner()).await {
         Ok(token) => {
             resp.content = serde_json::to_string(&token).unwrap_or_default();
         }
         Err(e) => {
             println!("error: {}", e);
-            resp.code = String::from("500");
+            set_error(&mut resp, &e);
         }
     }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
 async fn portal_get_pato_by_kol_token(
+    icp: web::Data<IcpClient>,
     token: web::Path<String>,
 ) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
-    match query_pato_by_kol_token(token.into_inner()).await {
+    match query_pato_by_kol_token(&icp, token.into_inner()).await {
         Ok(token) => {
             resp.content = serde_json::to_string(&token).unwrap_or_default();
         }
         Err(e) => {
             println!("error: {}", e);
-            resp.code = String::from("500");
+            set_error(&mut resp, &e);
         }
     }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
 async fn portal_get_pato_chat_messages(
+    icp: web::Data<IcpClient>,
     id: web::Path<(String, String)>,
 ) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
     let (id, date) = id.into_inner();
 
-    match get_pato_chat_messages(id, date).await {
-        Ok(info) => {
-            resp.content = serde_json::to_string(&info).unwrap_or_else(|e| {
+    match get_pato_chat_messages(&icp, id, date).await {
+        Ok(info) => match serde_json::to_string(&info) {
+            Ok(content) => resp.content = content,
+            Err(e) => {
                 println!("error: {}", e);
-                resp.code = String::from("500");
-                "".to_string()
-            });
-        }
+                set_error(&mut resp, &e.into());
+            }
+        },
         Err(e) => {
             println!("error: {}", e);
-            resp.code = String::from("500");
+            set_error(&mut resp, &e);
         }
     }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
 async fn portal_retrieve_pato_by_name(
+    icp: web::Data<IcpClient>,
     data: web::Path<String>,
 ) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
     let name = data.into_inner();
 
-    match retrieve_pato_by_name(name).await {
+    match retrieve_pato_by_name(&icp, name).await {
         Ok(info) => {
             resp.content = info;
         }
         Err(e) => {
             println!("error: {}", e);
-            resp.code = String::from("500");
+            set_error(&mut resp, &e);
         }
     }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
-async fn portal_get_names_by_ids(ids: web::Json<Vec<String>>) -> actix_web::Result<impl Responder> {
+async fn portal_get_names_by_ids(icp: web::Data<IcpClient>, ids: web::Json<Vec<String>>) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
-    match get_names_by_ids(ids.into_inner()).await {
+    match get_names_by_ids(&icp, ids.into_inner()).await {
         Ok(name) => {
             resp.content = serde_json::to_string(&name).unwrap_or_default();
         }
         Err(e) => {
             println!("error: {}", e);
-            resp.code = String::from("500");
+            set_error(&mut resp, &e);
         }
     }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
 async fn portal_get_topic_comment(
+    icp: web::Data<IcpClient>,
     data: web::Json<TopicChatInfo>,
 ) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
     let topic_id = compute_md5(&data.topic);
     // println!("get comments of {}", topic_id);
-    match get_pato_meta(topic_id, "sub_topics_of").await {
+    match icp.sub_topics_of(topic_id).await {
         Ok(his) => {
             let mut comments = serde_json::from_str::<Vec<(String,String)>>(&his).unwrap_or_default();
             let mut ids: Vec<String> = vec![];
             for comment in comments.iter(){
                 ids.push(comment.1.clone());
             }
-            let names = get_names_by_ids(ids).await.unwrap_or_default();
+            let names = get_names_by_ids(&icp, ids).await.unwrap_or_default();
             for comment in comments.iter_mut(){
                 for name in names.iter(){
                     if name.0 == comment.1{
@@ -757,81 +1002,321 @@ async fn portal_get_topic_comment(
         }
         Err(e) => {
             resp.content = format!("{}", e);
-            resp.code = String::from("500");
+            set_error(&mut resp, &e.into());
         }
     }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
 async fn portal_topic_comment(
+    icp: web::Data<IcpClient>,
+    llm: web::Data<LlmClient>,
+    idempotency: web::Data<IdempotencyStore>,
     data: web::Json<TopicChatInfo>,
 ) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
-    match comment_topic(data.topic.clone(), data.prompt.clone(), data.contributor.clone()).await {
-        Ok(()) => (),
+    match comment_topic(&icp, &llm, &idempotency, data.topic.clone(), data.prompt.clone(), data.contributor.clone()).await {
+        Ok(Some(comment)) => resp.content = comment,
+        Ok(None) => {
+            resp.code = String::from("409");
+            resp.error = Some("request_in_progress".to_string());
+        }
         Err(e) => {
             resp.content = format!("{}", e);
-            resp.code = String::from("500");
+            set_error(&mut resp, &e);
         }
     }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
 async fn portal_topic_embedding(
+    icp: web::Data<IcpClient>,
+    llm: web::Data<LlmClient>,
     data: web::Json<TopicChatInfo>,
 ) -> actix_web::Result<impl Responder> {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
-    match upload_topic_comment_save_in_canister(data.topic.as_bytes().to_vec()).await {
+    match upload_topic_comment_save_in_canister(&icp, &llm, data.topic.as_bytes().to_vec(), data.contributor.clone()).await {
         Ok(()) => (),
         Err(e) => {
             resp.content = format!("{}", e);
-            resp.code = String::from("500");
+            set_error(&mut resp, &e);
         }
     }
 
-    Ok(web::Json(resp))
+    Ok(reply(resp))
 }
 pub async fn download_generated_file_with_path(
-    id: web::Path<String>, path: web::Json<PathInfo>,
+    xfiles: web::Data<XFilesStore>, id: web::Path<String>, path: web::Json<PathInfo>,
 )  -> actix_web::Result<impl Responder>  {
     let mut resp = DataResponse {
         content: String::from(""),
         code: String::from("200"),
+        error: None,
     };
 
     let id = id.into_inner();
+    let saved_name = match safe_file_name(&path.saved_name) {
+        Ok(name) => name,
+        Err(e) => {
+            set_error(&mut resp, &e.into());
+            return Ok(reply(resp));
+        }
+    };
+    let saved_path = format!("ai/{}/{}", id, saved_name);
 
-    let _ = ensure_directory_exists(&format!("{}/ai/{}", XFILES_LOCAL_DIR, id));
-    let saved_local_file = format!("{}/ai/{}/{}", XFILES_LOCAL_DIR, id, path.saved_name);
-
-    println!("download ai resource {:?}, saved to {}", path.absolute_path, saved_local_file);
+    println!("download ai resource {:?}, saved to {}", path.absolute_path, saved_path);
 
-    if Path::new(&saved_local_file).exists() {
-        resp.content = format!("{}/ai/{}/{}", XFILES_SERVER, id, path.saved_name);
+    if xfiles.exists(&saved_path).unwrap_or(false) {
+        resp.content = xfiles.url(&saved_path);
         println!("file already exists, return link: {}", resp.content);
-        return Ok(web::Json(resp));
+        return Ok(reply(resp));
     }
 
-    let xfiles_link = format!("{}/ai/{}/{}", XFILES_SERVER, id, path.saved_name);
-    match download_image(&path.absolute_path, &saved_local_file).await {
-        Ok(_) => {
-            resp.content = xfiles_link;
+    match download_into_store(&xfiles, &path.absolute_path, &saved_path, &id, "", XFileKind::Download).await {
+        Ok(entry) => {
+            resp.content = xfiles.url(&entry.path);
         }
         Err(e) => {
-            resp.code = "500".to_string();
             println!("download ai resource error: {}", e);
+            set_error(&mut resp, &e);
         }
     }
     
-    Ok(web::Json(resp))
+    Ok(reply(resp))
+}
+
+async fn portal_icp_metrics(icp: web::Data<IcpClient>) -> actix_web::Result<impl Responder> {
+    let resp = DataResponse {
+        content: serde_json::to_string(&icp.metrics()).unwrap_or_default(),
+        code: String::from("200"),
+        error: None,
+    };
+
+    Ok(reply(resp))
+}
+
+async fn portal_canister_health(monitor: web::Data<CanisterMonitor>) -> actix_web::Result<impl Responder> {
+    let resp = DataResponse {
+        content: serde_json::to_string(&monitor.reports()).unwrap_or_default(),
+        code: String::from("200"),
+        error: None,
+    };
+
+    Ok(reply(resp))
+}
+async fn portal_get_job(jobs: web::Data<JobQueue>, id: web::Path<String>) -> actix_web::Result<impl Responder> {
+    let mut resp = DataResponse {
+        content: String::from(""),
+        code: String::from("200"),
+        error: None,
+    };
+
+    match jobs.get(&id.into_inner()) {
+        Ok(Some(job)) => resp.content = serde_json::to_string(&job).unwrap_or_default(),
+        Ok(None) => {
+            resp.code = String::from("404");
+            resp.error = Some("job_not_found".to_string());
+        }
+        Err(e) => {
+            println!("error: {}", e);
+            set_error(&mut resp, &e);
+        }
+    }
+
+    Ok(reply(resp))
+}
+async fn portal_get_profile(
+    icp: web::Data<IcpClient>,
+    profiles: web::Data<ProfileStore>,
+    id: web::Path<String>,
+) -> actix_web::Result<impl Responder> {
+    let mut resp = DataResponse {
+        content: String::from(""),
+        code: String::from("200"),
+        error: None,
+    };
+
+    match get_profile(&icp, &profiles, id.into_inner()).await {
+        Ok(profile) => resp.content = serde_json::to_string(&profile).unwrap_or_default(),
+        Err(e) => {
+            println!("error: {}", e);
+            set_error(&mut resp, &e);
+        }
+    }
+
+    Ok(reply(resp))
+}
+async fn portal_update_profile(
+    icp: web::Data<IcpClient>,
+    profiles: web::Data<ProfileStore>,
+    id: web::Path<String>,
+    update: web::Json<ProfileUpdate>,
+) -> actix_web::Result<impl Responder> {
+    let mut resp = DataResponse {
+        content: String::from(""),
+        code: String::from("200"),
+        error: None,
+    };
+
+    match update_profile(&icp, &profiles, id.into_inner(), update.into_inner()).await {
+        Ok(profile) => resp.content = serde_json::to_string(&profile).unwrap_or_default(),
+        Err(e) => {
+            println!("update profile error: {}", e);
+            set_error(&mut resp, &e);
+        }
+    }
+
+    Ok(reply(resp))
+}
+async fn portal_regenerate_profile_asset(
+    icp: web::Data<IcpClient>,
+    llm: web::Data<LlmClient>,
+    xfiles: web::Data<XFilesStore>,
+    profiles: web::Data<ProfileStore>,
+    path: web::Path<(String, String)>,
+    owner: web::Json<OwnerToken>,
+) -> actix_web::Result<impl Responder> {
+    let mut resp = DataResponse {
+        content: String::from(""),
+        code: String::from("200"),
+        error: None,
+    };
+
+    let (id, asset) = path.into_inner();
+    let regenerated = match ProfileAsset::parse(&asset) {
+        Ok(asset) => regenerate_asset(&icp, &llm, &xfiles, &profiles, id, asset, owner.into_inner().token).await,
+        Err(e) => Err(e.into()),
+    };
+    match regenerated {
+        Ok(version) => resp.content = serde_json::to_string(&version).unwrap_or_default(),
+        Err(e) => {
+            println!("regenerate {} error: {}", asset, e);
+            set_error(&mut resp, &e);
+        }
+    }
+
+    Ok(reply(resp))
+}
+async fn portal_profile_history(
+    icp: web::Data<IcpClient>,
+    profiles: web::Data<ProfileStore>,
+    id: web::Path<String>,
+    owner: web::Query<OwnerToken>,
+) -> actix_web::Result<impl Responder> {
+    let mut resp = DataResponse {
+        content: String::from(""),
+        code: String::from("200"),
+        error: None,
+    };
+
+    match profile_history(&icp, &profiles, id.into_inner(), owner.into_inner().token).await {
+        Ok(versions) => resp.content = serde_json::to_string(&versions).unwrap_or_default(),
+        Err(e) => {
+            println!("error: {}", e);
+            set_error(&mut resp, &e);
+        }
+    }
+
+    Ok(reply(resp))
+}
+async fn portal_generate_images(
+    icp: web::Data<IcpClient>,
+    llm: web::Data<LlmClient>,
+    xfiles: web::Data<XFilesStore>,
+    gallery: web::Data<GalleryStore>,
+    progress: web::Data<ProgressHub>,
+    path: web::Path<(String, String)>,
+    options: web::Json<ImageGenOptions>,
+) -> actix_web::Result<impl Responder> {
+    let mut resp = DataResponse {
+        content: String::from(""),
+        code: String::from("200"),
+        error: None,
+    };
+
+    let (id, session) = path.into_inner();
+    match generate_images(&icp, &llm, &xfiles, &gallery, &progress, id, session, options.into_inner()).await {
+        Ok(images) => resp.content = serde_json::to_string(&images).unwrap_or_default(),
+        Err(e) => {
+            println!("generate images error: {}", e);
+            set_error(&mut resp, &e);
+        }
+    }
+
+    Ok(reply(resp))
+}
+async fn portal_session_images(
+    gallery: web::Data<GalleryStore>,
+    path: web::Path<(String, String)>,
+) -> actix_web::Result<impl Responder> {
+    let mut resp = DataResponse {
+        content: String::from(""),
+        code: String::from("200"),
+        error: None,
+    };
+
+    let (id, session) = path.into_inner();
+    match gallery.list(&id, &session) {
+        Ok(images) => resp.content = serde_json::to_string(&images).unwrap_or_default(),
+        Err(e) => {
+            println!("error: {}", e);
+            set_error(&mut resp, &e);
+        }
+    }
+
+    Ok(reply(resp))
+}
+async fn portal_session_progress(progress: web::Data<ProgressHub>, session: web::Path<String>) -> HttpResponse {
+    let Some((history, receiver)) = progress.subscribe(&session.into_inner()) else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    // A finished run is replayed and the stream ends; otherwise it ends after the terminal event of the run.
+    let receiver = if history.last().is_some_and(|event| event.stage.is_terminal()) { None } else { Some(receiver) };
+    let live = futures::stream::unfold(receiver, |receiver| async move {
+        let mut receiver = receiver?;
+        loop {
+            match tokio::time::timeout(Duration::from_secs(PROGRESS_KEEP_ALIVE_SECS), receiver.recv()).await {
+                Ok(Ok(event)) => {
+                    let next = if event.stage.is_terminal() { None } else { Some(receiver) };
+                    return Some((event.to_sse(), next));
+                }
+                // A slow client skips the events it missed and keeps listening.
+                Ok(Err(RecvError::Lagged(_))) => continue,
+                Ok(Err(RecvError::Closed)) => return None,
+                Err(_) => return Some((KEEP_ALIVE_SSE.to_string(), Some(receiver))),
+            }
+        }
+    });
+    let events = futures::stream::iter(history)
+        .map(|event| event.to_sse())
+        .chain(live)
+        .map(|sse| Ok::<_, actix_web::Error>(web::Bytes::from(sse)));
+
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .content_type("text/event-stream")
+        .streaming(events)
+}
+async fn portal_embedding_cache_stats(llm: web::Data<LlmClient>) -> actix_web::Result<impl Responder> {
+    let stats = llm.embedding_cache().map(|cache| cache.stats());
+    let resp = DataResponse {
+        content: serde_json::to_string(&stats).unwrap_or_default(),
+        code: String::from("200"),
+        error: None,
+    };
+
+    Ok(reply(resp))
 }
 
 pub fn config_app(cfg: &mut web::ServiceConfig) {
@@ -842,6 +1327,14 @@ pub fn config_app(cfg: &mut web::ServiceConfig) {
             .service(
                 web::scope("api")
                     .service(web::resource("download/ai/resource/{id}").route(web::post().to(download_generated_file_with_path)))
+                    .service(web::resource("jobs/{id}").route(web::get().to(portal_get_job)))
+                    .service(web::resource("progress/{session}").route(web::get().to(portal_session_progress)))
+                    .service(
+                        web::scope("admin")
+                            .service(web::resource("icp/metrics").route(web::get().to(portal_icp_metrics)))
+                            .service(web::resource("canisters").route(web::get().to(portal_canister_health)))
+                            .service(web::resource("embeddings/cache").route(web::get().to(portal_embedding_cache_stats))),
+                    )
                     .service(
                         web::scope("kol")
                             .service(
@@ -879,6 +1372,24 @@ pub fn config_app(cfg: &mut web::ServiceConfig) {
                                 web::resource("upload/image")
                                     .route(web::post().to(portal_upload_image)),
                             )
+                            .service(
+                                web::resource("profile/{id}")
+                                    .route(web::get().to(portal_get_profile))
+                                    .route(web::post().to(portal_update_profile)),
+                            )
+                            .service(
+                                web::resource("profile/{id}/regenerate/{asset}")
+                                    .route(web::post().to(portal_regenerate_profile_asset)),
+                            )
+                            .service(
+                                web::resource("profile/{id}/history")
+                                    .route(web::get().to(portal_profile_history)),
+                            )
+                            .service(
+                                web::resource("images/{id}/{session}")
+                                    .route(web::post().to(portal_generate_images))
+                                    .route(web::get().to(portal_session_images)),
+                            )
                             .service(
                                 web::resource("submit/tags/{id}/{session}")
                                     .route(web::post().to(portal_submit_tags)),
@@ -949,6 +1460,14 @@ pub fn config_app(cfg: &mut web::ServiceConfig) {
                         web::resource("knowledge/summary/{id}/{sig}")
                             .route(web::get().to(portal_query_summary)),
                     )
+                    .service(
+                        web::resource("knowledge/status/{id}/{sig}/{file_name}")
+                            .route(web::get().to(portal_query_ingestion)),
+                    )
+                    .service(
+                        web::resource("knowledge/ask")
+                            .route(web::post().to(portal_ask_knowledge)),
+                    )
                     .service(
                         web::resource("knowledge/query")
                             .route(web::post().to(portal_query_embeddings)),
@@ -0,0 +1,90 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Error;
+use metapower_framework::dao::sqlite::MetapowerSqlite3;
+use metapower_framework::get_now_secs;
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+
+const CREATE_TABLE_SQL: &str = "CREATE TABLE IF NOT EXISTS embedding_cache (
+    key TEXT PRIMARY KEY,
+    model TEXT NOT NULL,
+    embedding TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+)";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbeddingCacheStats {
+    pub model: String,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Embeddings keyed by the sha1 of the model name and the whitespace-normalized text, kept in a local sqlite file.
+pub struct EmbeddingCache {
+    db: MetapowerSqlite3,
+    model: String,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl EmbeddingCache {
+    pub fn open(db_file: &str, model: &str) -> Result<Self, Error> {
+        let db = MetapowerSqlite3::new(db_file.to_string());
+        db.create_table(CREATE_TABLE_SQL.to_string())?;
+
+        Ok(EmbeddingCache {
+            db,
+            model: model.to_string(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    pub fn key(&self, content: &str) -> String {
+        let normalized = content.split_whitespace().collect::<Vec<&str>>().join(" ");
+        let mut hasher = Sha1::new();
+        hasher.update(self.model.as_bytes());
+        hasher.update(b"\n");
+        hasher.update(normalized.as_bytes());
+        format!("{:x}", hasher.finalize())
+    }
+
+    pub fn get(&self, content: &str) -> Option<Vec<f32>> {
+        let embedding = self.db.query("SELECT embedding FROM embedding_cache WHERE key = ?1", &[&self.key(content)], vec!["embedding"])
+            .unwrap_or_else(|e| {
+                println!("embedding cache read error: {}", e);
+                vec![]
+            })
+            .first()
+            .and_then(|row| row.get("embedding"))
+            .and_then(|value| serde_json::from_str::<Vec<f32>>(value).ok());
+
+        match embedding {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        embedding
+    }
+
+    pub fn put(&self, content: &str, embedding: &[f32]) {
+        let key = self.key(content);
+        let value = serde_json::to_string(embedding).unwrap_or_default();
+        let created_at = get_now_secs() as i64;
+
+        if let Err(e) = self.db.insert_record(
+            "INSERT OR REPLACE INTO embedding_cache (key, model, embedding, created_at) VALUES (?1, ?2, ?3, ?4)",
+            &[&key, &self.model, &value, &created_at],
+        ) {
+            println!("embedding cache write error: {}", e);
+        }
+    }
+
+    pub fn stats(&self) -> EmbeddingCacheStats {
+        EmbeddingCacheStats {
+            model: self.model.clone(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
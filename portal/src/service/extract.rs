@@ -0,0 +1,628 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Cursor, Read};
+use std::panic::catch_unwind;
+
+use encoding_rs::{Encoding, GB18030, WINDOWS_1252};
+use metapower_framework::ApiError;
+use serde::Serialize;
+use zip::ZipArchive;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum DocFormat {
+    Pdf,
+    Docx,
+    Epub,
+    Html,
+    Markdown,
+    Text,
+}
+
+#[derive(Debug, Clone)]
+pub enum ExtractError {
+    Unsupported(String),
+    Malformed { format: DocFormat, message: String },
+    Empty(DocFormat),
+}
+
+impl ApiError for ExtractError {
+    fn status_code(&self) -> u16 {
+        match self {
+            ExtractError::Unsupported(_) => 415,
+            ExtractError::Malformed { .. } | ExtractError::Empty(_) => 422,
+        }
+    }
+
+    fn error_code(&self) -> &'static str {
+        match self {
+            ExtractError::Unsupported(_) => "unsupported_format",
+            ExtractError::Malformed { .. } => "extract_failed",
+            ExtractError::Empty(_) => "extract_empty",
+        }
+    }
+}
+
+impl fmt::Display for ExtractError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExtractError::Unsupported(kind) => write!(f, "unsupported document format: {}", kind),
+            ExtractError::Malformed { format, message } => write!(f, "cannot extract {:?} document: {}", format, message),
+            ExtractError::Empty(format) => write!(f, "no text found in {:?} document", format),
+        }
+    }
+}
+
+impl std::error::Error for ExtractError {}
+
+#[derive(Debug, Clone)]
+pub struct Extracted {
+    pub format: DocFormat,
+    pub text: String,
+}
+
+const BINARY_SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG", "png image"),
+    (b"\xFF\xD8\xFF", "jpeg image"),
+    (b"GIF8", "gif image"),
+    (b"RIFF", "riff media"),
+    (b"ID3", "mp3 audio"),
+    (b"\x1F\x8B", "gzip archive"),
+    (b"Rar!", "rar archive"),
+    (b"7z\xBC\xAF", "7z archive"),
+    (b"\xD0\xCF\x11\xE0", "legacy office document"),
+    (b"\x7FELF", "executable"),
+];
+
+/// Uncompressed bytes read from one DOCX or EPUB archive, so a zip bomb is refused before it fills memory.
+const MAX_UNCOMPRESSED_BYTES: u64 = 64 * 1024 * 1024;
+
+/// `extract_text` on the blocking pool, since parsing a PDF is CPU bound and would stall the calling worker.
+pub async fn extract_document(data: Vec<u8>, file_name: String) -> Result<Extracted, ExtractError> {
+    let format = sniff_format(&data, &file_name)?;
+    tokio::task::spawn_blocking(move || extract_text(&data, &file_name))
+        .await
+        .map_err(|e| ExtractError::Malformed { format, message: e.to_string() })?
+}
+
+/// Extracts plain text from an uploaded document. Headings come out as `#` lines and pages or chapters are
+/// separated by `--- page N ---` / `--- section N ---` lines, so passages can still be placed in the source.
+pub fn extract_text(data: &[u8], file_name: &str) -> Result<Extracted, ExtractError> {
+    let format = sniff_format(data, file_name)?;
+    let text = match format {
+        DocFormat::Pdf => tidy(&pdf_text(data)?),
+        DocFormat::Docx => tidy(&docx_text(data)?),
+        DocFormat::Epub => tidy(&epub_text(data)?),
+        DocFormat::Html => tidy(&html_text(&decode_text(data))),
+        DocFormat::Markdown => tidy(&markdown_text(&decode_text(data))),
+        DocFormat::Text => decode_text(data),
+    };
+
+    if text.trim().is_empty() {
+        return Err(ExtractError::Empty(format));
+    }
+
+    Ok(Extracted { format, text })
+}
+
+/// Decides the format from the content; the file name only tells Markdown and HTML apart from plain text.
+pub fn sniff_format(data: &[u8], file_name: &str) -> Result<DocFormat, ExtractError> {
+    if data.starts_with(b"%PDF") {
+        return Ok(DocFormat::Pdf);
+    }
+    if data.starts_with(b"PK\x03\x04") {
+        return sniff_zip(data);
+    }
+    if let Some((_, kind)) = BINARY_SIGNATURES.iter().find(|(signature, _)| data.starts_with(signature)) {
+        return Err(ExtractError::Unsupported(kind.to_string()));
+    }
+    if Encoding::for_bom(data).is_none() && data.iter().take(8192).any(|b| *b == 0) {
+        return Err(ExtractError::Unsupported("binary file".to_string()));
+    }
+
+    let extension = file_name.rsplit_once('.').map(|(_, ext)| ext.to_lowercase()).unwrap_or_default();
+    let head = String::from_utf8_lossy(&data[..data.len().min(4096)]).trim_start().to_lowercase();
+
+    if matches!(extension.as_str(), "html" | "htm" | "xhtml") || head.starts_with("<!doctype html") || head.starts_with("<html") {
+        Ok(DocFormat::Html)
+    } else if matches!(extension.as_str(), "md" | "markdown") {
+        Ok(DocFormat::Markdown)
+    } else {
+        Ok(DocFormat::Text)
+    }
+}
+
+fn sniff_zip(data: &[u8]) -> Result<DocFormat, ExtractError> {
+    let archive = open_zip(data).map_err(ExtractError::Unsupported)?;
+    let names: Vec<&str> = archive.file_names().collect();
+
+    if names.contains(&"word/document.xml") {
+        Ok(DocFormat::Docx)
+    } else if names.contains(&"META-INF/container.xml") {
+        Ok(DocFormat::Epub)
+    } else {
+        Err(ExtractError::Unsupported("zip archive".to_string()))
+    }
+}
+
+/// Text without a BOM that is not valid UTF-8 is most likely GB18030 from a Chinese Windows machine.
+pub fn decode_text(data: &[u8]) -> String {
+    if let Some((encoding, bom_len)) = Encoding::for_bom(data) {
+        return encoding.decode_without_bom_handling(&data[bom_len..]).0.into_owned();
+    }
+    if let Ok(text) = std::str::from_utf8(data) {
+        return text.to_string();
+    }
+
+    let (text, had_errors) = GB18030.decode_without_bom_handling(data);
+    if !had_errors {
+        return text.into_owned();
+    }
+    WINDOWS_1252.decode_without_bom_handling(data).0.into_owned()
+}
+
+fn open_zip(data: &[u8]) -> Result<ZipArchive<Cursor<&[u8]>>, String> {
+    ZipArchive::new(Cursor::new(data)).map_err(|e| format!("unreadable zip archive: {}", e))
+}
+
+/// Reads one archive entry within what is left of `budget`; the size in the entry header is checked first, but the read
+/// itself is capped too because that header can lie.
+fn read_entry(archive: &mut ZipArchive<Cursor<&[u8]>>, name: &str, budget: &mut u64) -> Result<String, String> {
+    let too_large = || format!("{}: archive holds more than {} bytes of text", name, MAX_UNCOMPRESSED_BYTES);
+    let entry = archive.by_name(name).map_err(|e| format!("{}: {}", name, e))?;
+    if entry.size() > *budget {
+        return Err(too_large());
+    }
+
+    let mut data = vec![];
+    entry.take(*budget + 1).read_to_end(&mut data).map_err(|e| format!("{}: {}", name, e))?;
+    if data.len() as u64 > *budget {
+        return Err(too_large());
+    }
+    *budget -= data.len() as u64;
+
+    Ok(decode_text(&data))
+}
+
+/// `pdf_extract` panics on some malformed files, which is reported like any other unreadable PDF.
+fn pdf_text(data: &[u8]) -> Result<String, ExtractError> {
+    let malformed = |message: String| ExtractError::Malformed { format: DocFormat::Pdf, message };
+    let pages = catch_unwind(|| pdf_extract::extract_text_from_mem_by_pages(data))
+        .map_err(|panic| {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "parser panicked".to_string());
+            malformed(message)
+        })?
+        .map_err(|e| malformed(e.to_string()))?;
+
+    let mut out = String::new();
+    for (index, page) in pages.iter().enumerate() {
+        if page.trim().is_empty() {
+            continue;
+        }
+        boundary(&mut out, &format!("page {}", index + 1));
+        out.push_str(page);
+        out.push_str("\n\n");
+    }
+
+    Ok(out)
+}
+
+fn docx_text(data: &[u8]) -> Result<String, ExtractError> {
+    let malformed = |message: String| ExtractError::Malformed { format: DocFormat::Docx, message };
+    let mut archive = open_zip(data).map_err(malformed)?;
+    let mut budget = MAX_UNCOMPRESSED_BYTES;
+    let xml = read_entry(&mut archive, "word/document.xml", &mut budget).map_err(malformed)?;
+
+    let mut out = String::new();
+    let mut paragraph = String::new();
+    let mut heading: Option<usize> = None;
+    let mut in_text = false;
+    let mut page = 1;
+
+    for token in tokenize(&xml) {
+        match token {
+            Token::Open { name, attrs, self_closing } => match name.as_str() {
+                "w:p" => {
+                    paragraph.clear();
+                    heading = None;
+                }
+                "w:pstyle" => heading = attr(&attrs, "w:val").and_then(docx_heading_level),
+                "w:t" => in_text = !self_closing,
+                "w:tab" => paragraph.push(' '),
+                "w:br" | "w:cr" if attr(&attrs, "w:type") == Some("page") => {
+                    push_block(&mut out, heading, &paragraph);
+                    paragraph.clear();
+                    page += 1;
+                    boundary(&mut out, &format!("page {}", page));
+                }
+                "w:br" | "w:cr" => paragraph.push(' '),
+                _ => {}
+            },
+            Token::Close(name) => match name.as_str() {
+                "w:t" => in_text = false,
+                "w:p" => {
+                    push_block(&mut out, heading, &paragraph);
+                    paragraph.clear();
+                }
+                _ => {}
+            },
+            Token::Text(text) => {
+                if in_text {
+                    paragraph.push_str(&text);
+                }
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+/// Word writes headings as `Title`, `Heading1`, `heading 2`, ... style ids.
+fn docx_heading_level(style: &str) -> Option<usize> {
+    let style = style.to_lowercase().replace(' ', "");
+    if style == "title" {
+        return Some(1);
+    }
+    style.strip_prefix("heading").and_then(|level| level.parse::<usize>().ok()).map(|level| level.clamp(1, 6))
+}
+
+fn epub_text(data: &[u8]) -> Result<String, ExtractError> {
+    let malformed = |message: String| ExtractError::Malformed { format: DocFormat::Epub, message };
+    let mut archive = open_zip(data).map_err(malformed)?;
+    let mut budget = MAX_UNCOMPRESSED_BYTES;
+
+    let container = read_entry(&mut archive, "META-INF/container.xml", &mut budget).map_err(malformed)?;
+    let opf_path = tokenize(&container)
+        .into_iter()
+        .find_map(|token| match token {
+            Token::Open { name, attrs, .. } if local_name(&name) == "rootfile" => attr(&attrs, "full-path").map(str::to_string),
+            _ => None,
+        })
+        .ok_or_else(|| malformed("container.xml has no rootfile".to_string()))?;
+    let opf = read_entry(&mut archive, &opf_path, &mut budget).map_err(malformed)?;
+    let base = opf_path.rsplit_once('/').map(|(dir, _)| format!("{}/", dir)).unwrap_or_default();
+
+    let mut hrefs: HashMap<String, String> = HashMap::new();
+    let mut spine: Vec<String> = vec![];
+    for token in tokenize(&opf) {
+        if let Token::Open { name, attrs, .. } = token {
+            match local_name(&name) {
+                "item" => {
+                    if let (Some(id), Some(href)) = (attr(&attrs, "id"), attr(&attrs, "href")) {
+                        hrefs.insert(id.to_string(), href.to_string());
+                    }
+                }
+                "itemref" => {
+                    if let Some(idref) = attr(&attrs, "idref") {
+                        spine.push(idref.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut out = String::new();
+    for (index, idref) in spine.iter().enumerate() {
+        let Some(href) = hrefs.get(idref) else {
+            continue;
+        };
+        let path = format!("{}{}", base, href.split('#').next().unwrap_or_default());
+        let chapter = html_text(&read_entry(&mut archive, &path, &mut budget).map_err(malformed)?);
+        if chapter.trim().is_empty() {
+            continue;
+        }
+        boundary(&mut out, &format!("section {}", index + 1));
+        out.push_str(&chapter);
+    }
+
+    Ok(out)
+}
+
+const SKIPPED_TAGS: &[&str] = &["head", "script", "style", "noscript", "template"];
+const BLOCK_TAGS: &[&str] = &[
+    "p", "div", "br", "li", "tr", "td", "th", "dd", "dt", "blockquote", "pre", "ul", "ol", "table", "section", "article",
+    "header", "footer", "figcaption", "hr",
+];
+
+fn html_heading_level(name: &str) -> Option<usize> {
+    match name {
+        "h1" => Some(1),
+        "h2" => Some(2),
+        "h3" => Some(3),
+        "h4" => Some(4),
+        "h5" => Some(5),
+        "h6" => Some(6),
+        _ => None,
+    }
+}
+
+fn html_text(html: &str) -> String {
+    let mut out = String::new();
+    let mut block = String::new();
+    let mut heading: Option<usize> = None;
+    let mut skipping = 0usize;
+
+    for token in tokenize(html) {
+        match token {
+            Token::Open { name, self_closing, .. } => {
+                let name = local_name(&name);
+                if SKIPPED_TAGS.contains(&name) {
+                    if !self_closing {
+                        skipping += 1;
+                    }
+                } else if let Some(level) = html_heading_level(name) {
+                    push_block(&mut out, heading, &block);
+                    block.clear();
+                    heading = Some(level);
+                } else if BLOCK_TAGS.contains(&name) {
+                    push_block(&mut out, heading, &block);
+                    block.clear();
+                    heading = None;
+                }
+            }
+            Token::Close(name) => {
+                let name = local_name(&name);
+                if SKIPPED_TAGS.contains(&name) {
+                    skipping = skipping.saturating_sub(1);
+                } else if html_heading_level(name).is_some() || BLOCK_TAGS.contains(&name) {
+                    push_block(&mut out, heading, &block);
+                    block.clear();
+                    heading = None;
+                }
+            }
+            Token::Text(text) => {
+                if skipping == 0 {
+                    block.push_str(&text);
+                }
+            }
+        }
+    }
+    push_block(&mut out, heading, &block);
+
+    out
+}
+
+fn markdown_text(markdown: &str) -> String {
+    let mut out = String::new();
+    let mut in_fence = false;
+
+    for line in markdown.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+            continue;
+        }
+        if in_fence {
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        }
+
+        let level = trimmed.chars().take_while(|c| *c == '#').count();
+        if (1..=6).contains(&level) && trimmed[level..].starts_with(' ') {
+            out.push('\n');
+            out.push_str(&"#".repeat(level));
+            out.push(' ');
+            out.push_str(strip_inline(trimmed[level..].trim()).trim_end_matches('#').trim());
+            out.push_str("\n\n");
+            continue;
+        }
+        if trimmed.len() >= 3 && trimmed.chars().all(|c| matches!(c, '-' | '*' | '_' | ' ')) {
+            out.push('\n');
+            continue;
+        }
+
+        let body = trimmed.trim_start_matches('>').trim_start();
+        let body = ["- ", "* ", "+ "].iter().find_map(|marker| body.strip_prefix(marker)).unwrap_or(body);
+        out.push_str(&strip_inline(body));
+        out.push('\n');
+    }
+
+    out
+}
+
+/// Keeps the text of links and images and drops emphasis and code markers.
+fn strip_inline(line: &str) -> String {
+    let mut out = String::new();
+    let mut rest = line;
+
+    while let Some(c) = rest.chars().next() {
+        let label_start = if rest.starts_with("![") {
+            Some(2)
+        } else if c == '[' {
+            Some(1)
+        } else {
+            None
+        };
+        if let Some(start) = label_start {
+            if let Some(close) = rest.find("](") {
+                if let Some(end) = rest[close..].find(')') {
+                    out.push_str(&rest[start..close]);
+                    rest = &rest[close + end + 1..];
+                    continue;
+                }
+            }
+        }
+        if rest.starts_with("__") {
+            rest = &rest[2..];
+            continue;
+        }
+        if !matches!(c, '*' | '`') {
+            out.push(c);
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+
+    out
+}
+
+fn push_block(out: &mut String, heading: Option<usize>, text: &str) {
+    let text = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+    if text.is_empty() {
+        return;
+    }
+    if let Some(level) = heading {
+        out.push_str(&"#".repeat(level));
+        out.push(' ');
+    }
+    out.push_str(&text);
+    out.push_str("\n\n");
+}
+
+fn boundary(out: &mut String, label: &str) {
+    out.push_str(&format!("--- {} ---\n\n", label));
+}
+
+/// Trims trailing spaces and collapses runs of blank lines left behind by removed markup.
+fn tidy(text: &str) -> String {
+    let mut out = String::new();
+    let mut blank = true;
+
+    for line in text.lines() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            if !blank {
+                out.push('\n');
+            }
+            blank = true;
+            continue;
+        }
+        out.push_str(line);
+        out.push('\n');
+        blank = false;
+    }
+
+    out.trim_end().to_string()
+}
+
+enum Token {
+    Open { name: String, attrs: String, self_closing: bool },
+    Close(String),
+    Text(String),
+}
+
+fn local_name(name: &str) -> &str {
+    name.rsplit(':').next().unwrap_or(name)
+}
+
+/// A forgiving tokenizer for HTML and the XML inside DOCX and EPUB files; tag names are lowercased.
+fn tokenize(markup: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut rest = markup;
+
+    while !rest.is_empty() {
+        match rest.find('<') {
+            Some(0) => {
+                if rest.starts_with("<!--") {
+                    rest = rest.find("-->").map(|end| &rest[end + 3..]).unwrap_or("");
+                    continue;
+                }
+                if let Some(cdata) = rest.strip_prefix("<![CDATA[") {
+                    let end = cdata.find("]]>").unwrap_or(cdata.len());
+                    tokens.push(Token::Text(cdata[..end].to_string()));
+                    rest = cdata.get(end + 3..).unwrap_or("");
+                    continue;
+                }
+                let Some(end) = rest.find('>') else {
+                    break;
+                };
+                let inner = &rest[1..end];
+                rest = &rest[end + 1..];
+
+                if inner.starts_with('!') || inner.starts_with('?') {
+                    continue;
+                }
+                if let Some(name) = inner.strip_prefix('/') {
+                    tokens.push(Token::Close(name.trim().to_lowercase()));
+                    continue;
+                }
+                let self_closing = inner.ends_with('/');
+                let inner = inner.trim_end_matches('/');
+                let (name, attrs) = inner.split_once(char::is_whitespace).unwrap_or((inner, ""));
+                tokens.push(Token::Open {
+                    name: name.to_lowercase(),
+                    attrs: attrs.to_string(),
+                    self_closing,
+                });
+            }
+            Some(start) => {
+                tokens.push(Token::Text(decode_entities(&rest[..start])));
+                rest = &rest[start..];
+            }
+            None => {
+                tokens.push(Token::Text(decode_entities(rest)));
+                break;
+            }
+        }
+    }
+
+    tokens
+}
+
+fn attr<'a>(attrs: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = attrs;
+
+    while let Some(eq) = rest.find('=') {
+        let key = rest[..eq].trim();
+        let value = rest[eq + 1..].trim_start();
+        let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let end = value[1..].find(quote)? + 1;
+        if key.eq_ignore_ascii_case(name) {
+            return Some(&value[1..end]);
+        }
+        rest = &value[end + 1..];
+    }
+
+    None
+}
+
+fn decode_entities(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_string();
+    }
+
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let decoded = rest.find(';').filter(|end| *end <= 10).and_then(|end| entity(&rest[1..end]).map(|c| (c, end)));
+        match decoded {
+            Some((c, end)) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+
+    out
+}
+
+fn entity(name: &str) -> Option<char> {
+    match name {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        _ => {
+            let number = name.strip_prefix('#')?;
+            let code = match number.strip_prefix('x').or_else(|| number.strip_prefix('X')) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => number.parse::<u32>().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
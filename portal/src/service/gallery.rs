@@ -0,0 +1,216 @@
+use std::fmt;
+
+use anyhow::Error;
+use metapower_framework::dao::sqlite::MetapowerSqlite3;
+use metapower_framework::{ApiError, XFILES_LOCAL_DIR};
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_IMAGE_MAX_VARIANTS: u32 = 4;
+
+const CREATE_TABLE_SQL: &str = "CREATE TABLE IF NOT EXISTS gallery (
+    id TEXT PRIMARY KEY,
+    owner TEXT NOT NULL,
+    session TEXT NOT NULL,
+    data TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+)";
+const CREATE_INDEX_SQL: &str = "CREATE INDEX IF NOT EXISTS gallery_session ON gallery (owner, session)";
+
+#[derive(Debug, Clone)]
+pub struct GalleryConfig {
+    pub db_file: String,
+    /// Most variants one generation request may ask for.
+    pub max_variants: u32,
+}
+
+impl Default for GalleryConfig {
+    fn default() -> Self {
+        GalleryConfig::in_root(XFILES_LOCAL_DIR)
+    }
+}
+
+impl GalleryConfig {
+    /// Defaults with the gallery database under the local data `root`.
+    pub fn in_root(root: &str) -> Self {
+        GalleryConfig {
+            db_file: format!("{}/gallery.db", root),
+            max_variants: DEFAULT_IMAGE_MAX_VARIANTS,
+        }
+    }
+
+    pub fn from_env(root: &str) -> Self {
+        let mut config = GalleryConfig::in_root(root);
+
+        if let Ok(db_file) = std::env::var("GALLERY_DB") {
+            config.db_file = db_file;
+        }
+        if let Some(max) = std::env::var("IMAGE_MAX_VARIANTS").ok().and_then(|s| s.parse::<u32>().ok()) {
+            config.max_variants = max.max(1);
+        }
+
+        config
+    }
+}
+
+/// Wording added to the prompt and the negative prompt of a generation in this style.
+#[derive(Debug, Clone, Copy)]
+pub struct StylePreset {
+    pub name: &'static str,
+    pub prompt: &'static str,
+    pub negative_prompt: &'static str,
+}
+
+pub const STYLE_PRESETS: &[StylePreset] = &[
+    StylePreset {
+        name: "photo",
+        prompt: "photorealistic, natural lighting, sharp focus, high detail",
+        negative_prompt: "cartoon, illustration, painting, blurry, deformed",
+    },
+    StylePreset {
+        name: "anime",
+        prompt: "anime style, cel shading, vibrant colors, clean line art",
+        negative_prompt: "photorealistic, 3d render, blurry, extra limbs",
+    },
+    StylePreset {
+        name: "watercolor",
+        prompt: "watercolor painting, soft edges, paper texture, pastel palette",
+        negative_prompt: "photorealistic, hard edges, 3d render",
+    },
+    StylePreset {
+        name: "pixel",
+        prompt: "pixel art, 16-bit, limited palette, crisp pixels",
+        negative_prompt: "blurry, smooth gradients, photorealistic",
+    },
+    StylePreset {
+        name: "3d",
+        prompt: "3d render, octane render, soft studio lighting, high detail",
+        negative_prompt: "flat, sketch, low poly, blurry",
+    },
+];
+
+pub fn style_preset(name: &str) -> Option<&'static StylePreset> {
+    STYLE_PRESETS.iter().find(|preset| preset.name.eq_ignore_ascii_case(name))
+}
+
+/// What a client asks `/api/pato/images/{id}/{session}` to generate.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageGenOptions {
+    pub prompt: String,
+    #[serde(default)]
+    pub negative_prompt: String,
+    #[serde(default)]
+    pub style: Option<String>,
+    #[serde(default = "default_num_images")]
+    pub num_images: u32,
+    /// Seed of the first variant; a random one is picked, and recorded, when missing.
+    #[serde(default)]
+    pub seed: Option<u32>,
+    /// Auth token of the pato; only its owner may generate images for it.
+    #[serde(default)]
+    pub token: String,
+}
+
+fn default_num_images() -> u32 {
+    1
+}
+
+#[derive(Debug, Clone)]
+pub enum ImageGenError {
+    EmptyPrompt,
+    UnknownStyle(String),
+    TooManyVariants { requested: u32, max: u32 },
+}
+
+impl ApiError for ImageGenError {
+    fn status_code(&self) -> u16 {
+        400
+    }
+
+    fn error_code(&self) -> &'static str {
+        match self {
+            ImageGenError::EmptyPrompt => "empty_prompt",
+            ImageGenError::UnknownStyle(_) => "unknown_style",
+            ImageGenError::TooManyVariants { .. } => "too_many_variants",
+        }
+    }
+}
+
+impl fmt::Display for ImageGenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageGenError::EmptyPrompt => write!(f, "prompt is empty"),
+            ImageGenError::UnknownStyle(style) => write!(f, "unknown style preset: {}", style),
+            ImageGenError::TooManyVariants { requested, max } => {
+                write!(f, "{} variants requested, at most {} allowed", requested, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ImageGenError {}
+
+/// One variant of a generation, kept in the xfiles store at `path`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GeneratedImage {
+    pub id: String,
+    pub generation: String,
+    pub owner: String,
+    pub session: String,
+    pub index: u32,
+    pub prompt: String,
+    pub negative_prompt: String,
+    pub style: Option<String>,
+    pub seed: u32,
+    pub path: String,
+    pub url: String,
+    pub created_at: u64,
+}
+
+/// Records the generated images of every session so they can be listed again.
+pub struct GalleryStore {
+    db: MetapowerSqlite3,
+    config: GalleryConfig,
+}
+
+impl GalleryStore {
+    pub fn open(config: GalleryConfig) -> Result<Self, Error> {
+        let db = MetapowerSqlite3::new(config.db_file.clone());
+        db.create_table(CREATE_TABLE_SQL.to_string())?;
+        db.create_table(CREATE_INDEX_SQL.to_string())?;
+
+        Ok(GalleryStore { db, config })
+    }
+
+    pub fn max_variants(&self) -> u32 {
+        self.config.max_variants
+    }
+
+    pub fn save(&self, image: &GeneratedImage) -> Result<(), Error> {
+        let data = serde_json::to_string(image)?;
+        let created_at = image.created_at as i64;
+        self.db.insert_record(
+            "INSERT OR REPLACE INTO gallery (id, owner, session, data, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            &[&image.id, &image.owner, &image.session, &data, &created_at],
+        )?;
+
+        Ok(())
+    }
+
+    /// The images of a session, oldest generation first and variants in order.
+    pub fn list(&self, owner: &str, session: &str) -> Result<Vec<GeneratedImage>, Error> {
+        let rows = self.db.query(
+            "SELECT data FROM gallery WHERE owner = ?1 AND session = ?2 ORDER BY created_at",
+            &[&owner, &session],
+            vec!["data"],
+        )?;
+
+        let mut images: Vec<GeneratedImage> = rows
+            .iter()
+            .filter_map(|row| row.get("data"))
+            .filter_map(|data| serde_json::from_str::<GeneratedImage>(data).ok())
+            .collect();
+        images.sort_by(|a, b| (a.created_at, &a.generation, a.index).cmp(&(b.created_at, &b.generation, b.index)));
+
+        Ok(images)
+    }
+}
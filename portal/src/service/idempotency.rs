@@ -0,0 +1,162 @@
+use std::time::Duration;
+
+use anyhow::Error;
+use metapower_framework::dao::sqlite::MetapowerSqlite3;
+use metapower_framework::{get_now_secs, XFILES_LOCAL_DIR};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+const DEFAULT_IDEMPOTENCY_LOCK_TTL_SECS: u64 = 1800;
+const DEFAULT_IDEMPOTENCY_TTL_SECS: u64 = 86400;
+
+const CREATE_TABLE_SQL: &str = "CREATE TABLE IF NOT EXISTS idempotency (
+    key TEXT PRIMARY KEY,
+    state TEXT NOT NULL,
+    result TEXT,
+    error TEXT,
+    expires_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL
+)";
+
+#[derive(Debug, Clone)]
+pub struct IdempotencyConfig {
+    pub db_file: String,
+    /// How long a claim taken with `begin` stays in progress before another request may take it over.
+    pub lock_ttl: Duration,
+    /// How long a completed result is replayed.
+    pub ttl: Duration,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        IdempotencyConfig::in_root(XFILES_LOCAL_DIR)
+    }
+}
+
+impl IdempotencyConfig {
+    /// Defaults with the key database under the local data `root`.
+    pub fn in_root(root: &str) -> Self {
+        IdempotencyConfig {
+            db_file: format!("{}/idempotency.db", root),
+            lock_ttl: Duration::from_secs(DEFAULT_IDEMPOTENCY_LOCK_TTL_SECS),
+            ttl: Duration::from_secs(DEFAULT_IDEMPOTENCY_TTL_SECS),
+        }
+    }
+
+    pub fn from_env(root: &str) -> Self {
+        let mut config = IdempotencyConfig::in_root(root);
+
+        if let Ok(db_file) = std::env::var("IDEMPOTENCY_DB") {
+            config.db_file = db_file;
+        }
+        if let Some(secs) = std::env::var("IDEMPOTENCY_LOCK_TTL_SECS").ok().and_then(|s| s.parse::<u64>().ok()) {
+            config.lock_ttl = Duration::from_secs(secs);
+        }
+        if let Some(secs) = std::env::var("IDEMPOTENCY_TTL_SECS").ok().and_then(|s| s.parse::<u64>().ok()) {
+            config.ttl = Duration::from_secs(secs);
+        }
+
+        config
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IdempotencyState {
+    InProgress,
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Claim {
+    /// The caller owns the work and must finish it with `complete` or `fail`.
+    Acquired,
+    /// Another request is doing the work; carries the reference it stored when claiming.
+    InProgress(Option<String>),
+    /// The work is done; carries its result for replay.
+    Completed(Option<String>),
+}
+
+/// Deduplicates work by key. Failed or expired keys can be claimed again, completed ones replay their result.
+pub struct IdempotencyStore {
+    db: MetapowerSqlite3,
+    config: IdempotencyConfig,
+}
+
+impl IdempotencyStore {
+    pub fn open(config: IdempotencyConfig) -> Result<Self, Error> {
+        let db = MetapowerSqlite3::new(config.db_file.clone());
+        db.create_table(CREATE_TABLE_SQL.to_string())?;
+
+        Ok(IdempotencyStore { db, config })
+    }
+
+    /// Keys are stored hashed, so callers can build them from request data.
+    fn hash(key: &str) -> String {
+        let mut hasher = Sha1::new();
+        hasher.update(key.as_bytes());
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Claims `key` for the caller, storing `reference` (e.g. a job id) for concurrent requests to see.
+    /// The claim expires after the lock ttl, in case the caller dies before finishing.
+    pub fn begin(&self, key: &str, reference: Option<&str>) -> Result<Claim, Error> {
+        let now = get_now_secs() as i64;
+        self.claim(key, reference, now + self.config.lock_ttl.as_secs() as i64)
+    }
+
+    /// Like `begin`, but the claim never expires: it is held until `complete` or `fail`. For work such as persisted
+    /// jobs that is resumed after a restart and may wait out retries far longer than the lock ttl.
+    pub fn begin_until_finished(&self, key: &str, reference: Option<&str>) -> Result<Claim, Error> {
+        self.claim(key, reference, i64::MAX)
+    }
+
+    fn claim(&self, key: &str, reference: Option<&str>, expires_at: i64) -> Result<Claim, Error> {
+        let hashed = IdempotencyStore::hash(key);
+        let now = get_now_secs() as i64;
+
+        let claimed = self.db.execute(
+            "INSERT INTO idempotency (key, state, result, error, expires_at, updated_at) VALUES (?1, 'InProgress', ?2, NULL, ?3, ?4)
+            ON CONFLICT(key) DO UPDATE SET state = 'InProgress', result = ?2, error = NULL, expires_at = ?3, updated_at = ?4
+            WHERE idempotency.state = 'Failed' OR idempotency.expires_at <= ?4",
+            &[&hashed, &reference, &expires_at, &now],
+        )?;
+        if claimed > 0 {
+            return Ok(Claim::Acquired);
+        }
+
+        let rows = self.db.query("SELECT state, result FROM idempotency WHERE key = ?1", &[&hashed], vec!["state", "result"])?;
+        let Some(row) = rows.first() else {
+            return Err(anyhow::anyhow!("idempotency key {} vanished", key));
+        };
+        let result = row.get("result").cloned();
+
+        match row.get("state").map(|s| s.as_str()) {
+            Some("Completed") => Ok(Claim::Completed(result)),
+            _ => Ok(Claim::InProgress(result)),
+        }
+    }
+
+    pub fn complete(&self, key: &str, result: &str) -> Result<(), Error> {
+        self.finish(key, IdempotencyState::Completed, Some(result), None)
+    }
+
+    /// Keeps the error for inspection and lets the next request claim the key again.
+    pub fn fail(&self, key: &str, error: &str) -> Result<(), Error> {
+        self.finish(key, IdempotencyState::Failed, None, Some(error))
+    }
+
+    fn finish(&self, key: &str, state: IdempotencyState, result: Option<&str>, error: Option<&str>) -> Result<(), Error> {
+        let now = get_now_secs() as i64;
+        let expires_at = now + self.config.ttl.as_secs() as i64;
+        let state = format!("{:?}", state);
+
+        self.db.execute(
+            "UPDATE idempotency SET state = ?2, result = ?3, error = ?4, expires_at = ?5, updated_at = ?6 WHERE key = ?1",
+            &[&IdempotencyStore::hash(key), &state, &result, &error, &expires_at, &now],
+        )?;
+        self.db.execute("DELETE FROM idempotency WHERE expires_at <= ?1", &[&now])?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,454 @@
+use std::fmt;
+use std::io::Cursor;
+
+use anyhow::Error;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageReader};
+use metapower_framework::ApiError;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use super::xfiles::{XFileKind, XFilesStore};
+
+const DEFAULT_IMAGE_MAX_DIMENSION: u32 = 8192;
+const DEFAULT_AVATAR_SIZE: u32 = 256;
+const DEFAULT_THUMBNAIL_SIZE: u32 = 128;
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const JPEG_XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const PNG_XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp\0";
+const GPS_IFD_TAG: usize = 0x8825;
+const WEBP_XMP_FLAG: u8 = 0x04;
+
+#[derive(Debug, Clone)]
+pub struct ImageConfig {
+    /// Largest accepted width or height, checked before the pixels are decoded.
+    pub max_dimension: u32,
+    pub avatar_size: u32,
+    pub thumbnail_size: u32,
+}
+
+impl Default for ImageConfig {
+    fn default() -> Self {
+        ImageConfig {
+            max_dimension: DEFAULT_IMAGE_MAX_DIMENSION,
+            avatar_size: DEFAULT_AVATAR_SIZE,
+            thumbnail_size: DEFAULT_THUMBNAIL_SIZE,
+        }
+    }
+}
+
+impl ImageConfig {
+    pub fn from_env() -> Self {
+        let mut config = ImageConfig::default();
+
+        if let Some(max) = std::env::var("IMAGE_MAX_DIMENSION").ok().and_then(|s| s.parse::<u32>().ok()) {
+            config.max_dimension = max;
+        }
+        if let Some(size) = std::env::var("IMAGE_AVATAR_SIZE").ok().and_then(|s| s.parse::<u32>().ok()) {
+            config.avatar_size = size.max(1);
+        }
+        if let Some(size) = std::env::var("IMAGE_THUMBNAIL_SIZE").ok().and_then(|s| s.parse::<u32>().ok()) {
+            config.thumbnail_size = size.max(1);
+        }
+
+        config
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    WebP,
+    Gif,
+}
+
+impl ImageFormat {
+    /// Detects the format from the leading bytes, whatever the file is called.
+    pub fn sniff(data: &[u8]) -> Option<Self> {
+        if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(ImageFormat::Png)
+        } else if data.starts_with(b"\xFF\xD8\xFF") {
+            Some(ImageFormat::Jpeg)
+        } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+            Some(ImageFormat::Gif)
+        } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+            Some(ImageFormat::WebP)
+        } else {
+            None
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::WebP => "webp",
+            ImageFormat::Gif => "gif",
+        }
+    }
+
+    fn codec(&self) -> image::ImageFormat {
+        match self {
+            ImageFormat::Png => image::ImageFormat::Png,
+            ImageFormat::Jpeg => image::ImageFormat::Jpeg,
+            ImageFormat::WebP => image::ImageFormat::WebP,
+            ImageFormat::Gif => image::ImageFormat::Gif,
+        }
+    }
+
+    /// Variants of photos stay JPEG, everything else becomes PNG to keep transparency.
+    fn variant(&self) -> ImageFormat {
+        match self {
+            ImageFormat::Jpeg => ImageFormat::Jpeg,
+            _ => ImageFormat::Png,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ImageError {
+    NotAnImage,
+    TooLarge { width: u32, height: u32, max: u32 },
+    Malformed { format: ImageFormat, message: String },
+}
+
+impl ApiError for ImageError {
+    fn status_code(&self) -> u16 {
+        match self {
+            ImageError::NotAnImage => 415,
+            ImageError::TooLarge { .. } => 413,
+            ImageError::Malformed { .. } => 422,
+        }
+    }
+
+    fn error_code(&self) -> &'static str {
+        match self {
+            ImageError::NotAnImage => "not_an_image",
+            ImageError::TooLarge { .. } => "image_too_large",
+            ImageError::Malformed { .. } => "image_malformed",
+        }
+    }
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::NotAnImage => write!(f, "not a PNG, JPEG, WebP or GIF image"),
+            ImageError::TooLarge { width, height, max } => {
+                write!(f, "image is {}x{}, larger than {} pixels on a side", width, height, max)
+            }
+            ImageError::Malformed { format, message } => write!(f, "cannot read {:?} image: {}", format, message),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+/// A validated upload: the original without location metadata plus its avatar and thumbnail variants.
+#[derive(Debug, Clone)]
+pub struct ProcessedImage {
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32,
+    /// sha1 of the stored original, used to name the files.
+    pub hash: String,
+    pub original: Vec<u8>,
+    pub avatar: Vec<u8>,
+    pub thumbnail: Vec<u8>,
+}
+
+impl ProcessedImage {
+    pub fn original_name(&self) -> String {
+        format!("{}.{}", self.hash, self.format.extension())
+    }
+
+    pub fn avatar_name(&self) -> String {
+        format!("{}_avatar.{}", self.hash, self.format.variant().extension())
+    }
+
+    pub fn thumbnail_name(&self) -> String {
+        format!("{}_thumb.{}", self.hash, self.format.variant().extension())
+    }
+
+    /// Stores the files under `user/uploaded/{id}/` and returns where `XFILES_SERVER` serves them.
+    pub fn save(&self, xfiles: &XFilesStore, id: &str, session: &str) -> Result<StoredImage, Error> {
+        let path = |name: String| format!("user/uploaded/{}/{}", id, name);
+        let original = xfiles.put(&path(self.original_name()), id, session, XFileKind::Upload, &self.original)?;
+        let avatar = xfiles.put(&path(self.avatar_name()), id, session, XFileKind::Avatar, &self.avatar)?;
+        let thumbnail = xfiles.put(&path(self.thumbnail_name()), id, session, XFileKind::Thumbnail, &self.thumbnail)?;
+
+        Ok(StoredImage {
+            format: self.format,
+            width: self.width,
+            height: self.height,
+            original: xfiles.url(&original.path),
+            avatar: xfiles.url(&avatar.path),
+            thumbnail: xfiles.url(&thumbnail.path),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredImage {
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32,
+    pub original: String,
+    pub avatar: String,
+    pub thumbnail: String,
+}
+
+/// Checks that `data` is an image of acceptable size, removes its location metadata and renders the variants.
+pub fn process_image(data: &[u8], config: &ImageConfig) -> Result<ProcessedImage, ImageError> {
+    let format = ImageFormat::sniff(data).ok_or(ImageError::NotAnImage)?;
+    let malformed = |e: image::ImageError| ImageError::Malformed { format, message: e.to_string() };
+
+    let (width, height) = ImageReader::with_format(Cursor::new(data), format.codec())
+        .into_dimensions()
+        .map_err(malformed)?;
+    if width.max(height) > config.max_dimension {
+        return Err(ImageError::TooLarge { width, height, max: config.max_dimension });
+    }
+
+    let original = strip_location(data, format).ok_or_else(|| ImageError::Malformed {
+        format,
+        message: "truncated metadata".to_string(),
+    })?;
+    let image = image::load_from_memory_with_format(&original, format.codec()).map_err(malformed)?;
+
+    let variant = format.variant();
+    let avatar = encode(&image.resize_to_fill(config.avatar_size, config.avatar_size, FilterType::Lanczos3), variant)?;
+    let size = config.thumbnail_size;
+    // Small images are their own thumbnail rather than being blown up.
+    let thumbnail = if image.width() <= size && image.height() <= size { image.clone() } else { image.thumbnail(size, size) };
+    let thumbnail = encode(&thumbnail, variant)?;
+
+    let mut hasher = Sha1::new();
+    hasher.update(&original);
+
+    Ok(ProcessedImage {
+        format,
+        width,
+        height,
+        hash: format!("{:x}", hasher.finalize()),
+        original,
+        avatar,
+        thumbnail,
+    })
+}
+
+fn encode(image: &DynamicImage, format: ImageFormat) -> Result<Vec<u8>, ImageError> {
+    let mut data = Vec::new();
+    let written = match format {
+        // JPEG has no alpha channel.
+        ImageFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8()).write_to(&mut Cursor::new(&mut data), format.codec()),
+        _ => image.write_to(&mut Cursor::new(&mut data), format.codec()),
+    };
+    written.map_err(|e| ImageError::Malformed { format, message: e.to_string() })?;
+
+    Ok(data)
+}
+
+/// Removes GPS tags from EXIF and drops XMP packets, which may repeat them. Other metadata such as the
+/// orientation is kept, and the pixel data is copied untouched. Returns `None` when the container is truncated.
+fn strip_location(data: &[u8], format: ImageFormat) -> Option<Vec<u8>> {
+    match format {
+        ImageFormat::Jpeg => strip_jpeg(data),
+        ImageFormat::Png => strip_png(data),
+        ImageFormat::WebP => strip_webp(data),
+        ImageFormat::Gif => Some(data.to_vec()),
+    }
+}
+
+fn strip_jpeg(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = data.get(..2)?.to_vec();
+    let mut pos = 2;
+
+    while pos + 2 <= data.len() {
+        if data[pos] != 0xFF {
+            return None;
+        }
+        let marker = data[pos + 1];
+        match marker {
+            // Fill byte before a marker.
+            0xFF => {
+                pos += 1;
+                continue;
+            }
+            // Start of scan or end of image: the rest is pixel data.
+            0xDA | 0xD9 => {
+                out.extend_from_slice(&data[pos..]);
+                return Some(out);
+            }
+            0x01 | 0xD0..=0xD7 => {
+                out.extend_from_slice(&data[pos..pos + 2]);
+                pos += 2;
+                continue;
+            }
+            _ => (),
+        }
+
+        let length = data.get(pos + 2..pos + 4).map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)?;
+        let end = pos + 2 + length;
+        if length < 2 || end > data.len() {
+            return None;
+        }
+
+        let payload = &data[pos + 4..end];
+        if marker == 0xE1 && payload.starts_with(EXIF_HEADER) {
+            let mut segment = data[pos..end].to_vec();
+            scrub_gps(&mut segment[4 + EXIF_HEADER.len()..]);
+            out.extend_from_slice(&segment);
+        } else if !(marker == 0xE1 && payload.starts_with(JPEG_XMP_HEADER)) {
+            out.extend_from_slice(&data[pos..end]);
+        }
+        pos = end;
+    }
+
+    None
+}
+
+fn strip_png(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = data.get(..8)?.to_vec();
+    let mut pos = 8;
+
+    while pos + 12 <= data.len() {
+        let length = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        let kind = &data[pos + 4..pos + 8];
+        let end = pos + 12 + length;
+        if end > data.len() {
+            return None;
+        }
+
+        // eXIf is dropped whole, editing it would mean recomputing the chunk crc.
+        let dropped = kind == b"eXIf" || (kind == b"iTXt" && data[pos + 8..end].starts_with(PNG_XMP_KEYWORD));
+        if !dropped {
+            out.extend_from_slice(&data[pos..end]);
+        }
+        pos = end;
+
+        if kind == b"IEND" {
+            return Some(out);
+        }
+    }
+
+    None
+}
+
+fn strip_webp(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = data.get(..12)?.to_vec();
+    let mut pos = 12;
+
+    while pos + 8 <= data.len() {
+        let kind = &data[pos..pos + 4];
+        let size = u32::from_le_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]]) as usize;
+        let body_end = pos + 8 + size;
+        if body_end > data.len() {
+            return None;
+        }
+        // Chunks are padded to an even size, some encoders leave the padding off the last one.
+        let end = (body_end + (size & 1)).min(data.len());
+
+        match kind {
+            b"XMP " => (),
+            b"EXIF" => {
+                let mut chunk = data[pos..end].to_vec();
+                let tiff = &mut chunk[8..8 + size];
+                let skip = if tiff.starts_with(EXIF_HEADER) { EXIF_HEADER.len() } else { 0 };
+                scrub_gps(&mut tiff[skip..]);
+                out.extend_from_slice(&chunk);
+            }
+            _ => out.extend_from_slice(&data[pos..end]),
+        }
+        pos = end;
+    }
+
+    let riff_size = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    if out.get(12..16) == Some(b"VP8X".as_slice()) && out.len() > 20 {
+        out[20] &= !WEBP_XMP_FLAG;
+    }
+
+    Some(out)
+}
+
+fn read_u16(tiff: &[u8], at: usize, little: bool) -> Option<usize> {
+    let b = tiff.get(at..at.checked_add(2)?)?;
+    let value = if little { u16::from_le_bytes([b[0], b[1]]) } else { u16::from_be_bytes([b[0], b[1]]) };
+    Some(value as usize)
+}
+
+fn read_u32(tiff: &[u8], at: usize, little: bool) -> Option<usize> {
+    let b = tiff.get(at..at.checked_add(4)?)?;
+    let value = if little {
+        u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+    } else {
+        u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+    };
+    Some(value as usize)
+}
+
+fn tiff_type_size(kind: usize) -> usize {
+    match kind {
+        1 | 2 | 6 | 7 => 1,
+        3 | 8 => 2,
+        4 | 9 | 11 => 4,
+        5 | 10 | 12 => 8,
+        _ => 0,
+    }
+}
+
+/// Blanks the GPS directory of an EXIF (TIFF) block in place, so lengths and offsets stay valid.
+fn scrub_gps(tiff: &mut [u8]) {
+    let little = match tiff.get(..2) {
+        Some(b"II") => true,
+        Some(b"MM") => false,
+        _ => return,
+    };
+    let Some(ifd0) = read_u32(tiff, 4, little) else {
+        return;
+    };
+    let Some(count) = read_u16(tiff, ifd0, little) else {
+        return;
+    };
+
+    for i in 0..count {
+        let entry = ifd0 + 2 + i * 12;
+        if read_u16(tiff, entry, little) == Some(GPS_IFD_TAG) {
+            if let Some(gps) = read_u32(tiff, entry + 8, little) {
+                blank_ifd(tiff, gps, little);
+            }
+        }
+    }
+}
+
+fn blank_ifd(tiff: &mut [u8], ifd: usize, little: bool) {
+    let Some(count) = read_u16(tiff, ifd, little) else {
+        return;
+    };
+
+    for i in 0..count {
+        let entry = ifd + 2 + i * 12;
+        let (Some(kind), Some(n)) = (read_u16(tiff, entry + 2, little), read_u32(tiff, entry + 4, little)) else {
+            return;
+        };
+        // Values longer than four bytes live elsewhere in the block, at the offset the entry holds.
+        let size = tiff_type_size(kind).saturating_mul(n);
+        if size > 4 {
+            if let Some(offset) = read_u32(tiff, entry + 8, little) {
+                if let Some(value) = offset.checked_add(size).and_then(|end| tiff.get_mut(offset..end)) {
+                    value.fill(0);
+                }
+            }
+        }
+        if let Some(bytes) = tiff.get_mut(entry..entry + 12) {
+            bytes.fill(0);
+        }
+    }
+    // An empty directory: readers stop before the blanked entries.
+    if let Some(bytes) = tiff.get_mut(ifd..ifd + 2) {
+        bytes.fill(0);
+    }
+}
@@ -0,0 +1,342 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Error;
+use metapower_framework::dao::sqlite::{MetapowerSqlite3, ToSql};
+use metapower_framework::icp::IcpClient;
+use metapower_framework::{get_now_secs, XFILES_LOCAL_DIR};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, Mutex};
+
+use super::idempotency::{Claim, IdempotencyStore};
+use super::llm_gateway::LlmClient;
+use super::llm_proxy::submit_tags_with_proxy;
+use super::profile::ProfileStore;
+use super::progress::{ProgressHub, ProgressStage};
+use super::xfiles::XFilesStore;
+
+const DEFAULT_JOB_WORKERS: usize = 4;
+const DEFAULT_JOB_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_JOB_RETRY_DELAY_SECS: u64 = 10;
+
+const CREATE_TABLE_SQL: &str = "CREATE TABLE IF NOT EXISTS jobs (
+    id TEXT PRIMARY KEY,
+    state TEXT NOT NULL,
+    data TEXT NOT NULL,
+    updated_at INTEGER NOT NULL
+)";
+
+#[derive(Debug, Clone)]
+pub struct JobConfig {
+    pub workers: usize,
+    /// Attempts before a job is marked failed, the first run included.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every further attempt.
+    pub retry_delay: Duration,
+    pub db_file: String,
+}
+
+impl Default for JobConfig {
+    fn default() -> Self {
+        JobConfig::in_root(XFILES_LOCAL_DIR)
+    }
+}
+
+impl JobConfig {
+    /// Defaults with the job database under the local data `root`.
+    pub fn in_root(root: &str) -> Self {
+        JobConfig {
+            workers: DEFAULT_JOB_WORKERS,
+            max_attempts: DEFAULT_JOB_MAX_ATTEMPTS,
+            retry_delay: Duration::from_secs(DEFAULT_JOB_RETRY_DELAY_SECS),
+            db_file: format!("{}/jobs.db", root),
+        }
+    }
+
+    pub fn from_env(root: &str) -> Self {
+        let mut config = JobConfig::in_root(root);
+
+        if let Some(workers) = std::env::var("JOB_WORKERS").ok().and_then(|s| s.parse::<usize>().ok()) {
+            config.workers = workers.max(1);
+        }
+        if let Some(attempts) = std::env::var("JOB_MAX_ATTEMPTS").ok().and_then(|s| s.parse::<u32>().ok()) {
+            config.max_attempts = attempts.max(1);
+        }
+        if let Some(secs) = std::env::var("JOB_RETRY_DELAY_SECS").ok().and_then(|s| s.parse::<u64>().ok()) {
+            config.retry_delay = Duration::from_secs(secs);
+        }
+        if let Ok(db_file) = std::env::var("JOB_DB") {
+            config.db_file = db_file;
+        }
+
+        config
+    }
+}
+
+/// Long-running generation work that runs outside the request that asked for it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum JobKind {
+    SubmitTags { id: String, session: String, tags: Vec<String> },
+}
+
+impl JobKind {
+    async fn run(
+        &self,
+        icp: &IcpClient,
+        llm: &LlmClient,
+        xfiles: &XFilesStore,
+        profiles: &ProfileStore,
+        progress: &ProgressHub,
+    ) -> Result<serde_json::Value, Error> {
+        match self {
+            JobKind::SubmitTags { id, session, tags } => {
+                let generated =
+                    submit_tags_with_proxy(icp, llm, xfiles, profiles, progress, tags.clone(), session.clone(), id.clone()).await?;
+                Ok(serde_json::to_value(generated)?)
+            }
+        }
+    }
+
+    /// The session whose listeners hear about the job.
+    fn session(&self) -> &str {
+        match self {
+            JobKind::SubmitTags { session, .. } => session,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub kind: JobKind,
+    pub state: JobState,
+    pub attempts: u32,
+    pub result: Option<serde_json::Value>,
+    pub error: Option<String>,
+    /// Released with the job's outcome, so a failed job can be submitted again.
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+struct JobStore {
+    db: MetapowerSqlite3,
+}
+
+impl JobStore {
+    fn open(db_file: &str) -> Result<Self, Error> {
+        let db = MetapowerSqlite3::new(db_file.to_string());
+        db.create_table(CREATE_TABLE_SQL.to_string())?;
+
+        Ok(JobStore { db })
+    }
+
+    fn save(&self, job: &mut Job) -> Result<(), Error> {
+        job.updated_at = get_now_secs();
+        let data = serde_json::to_string(job)?;
+        let state = format!("{:?}", job.state);
+        let updated_at = job.updated_at as i64;
+        self.db.insert_record(
+            "INSERT OR REPLACE INTO jobs (id, state, data, updated_at) VALUES (?1, ?2, ?3, ?4)",
+            &[&job.id, &state, &data, &updated_at],
+        )?;
+
+        Ok(())
+    }
+
+    fn select(&self, condition: &str, parameters: &[&dyn ToSql]) -> Result<Vec<Job>, Error> {
+        let sql = format!("SELECT data FROM jobs WHERE {} ORDER BY updated_at", condition);
+        let rows = self.db.query(&sql, parameters, vec!["data"])?;
+
+        Ok(rows
+            .iter()
+            .filter_map(|row| row.get("data"))
+            .filter_map(|data| serde_json::from_str::<Job>(data).ok())
+            .collect())
+    }
+
+    fn load(&self, id: &str) -> Result<Option<Job>, Error> {
+        Ok(self.select("id = ?1", &[&id])?.pop())
+    }
+
+    /// Jobs still queued, or interrupted while running, when the previous process stopped.
+    fn unfinished(&self) -> Result<Vec<Job>, Error> {
+        self.select("state IN ('Queued', 'Running')", &[])
+    }
+}
+
+struct JobQueueInner {
+    store: JobStore,
+    config: JobConfig,
+    idempotency: Arc<IdempotencyStore>,
+    progress: ProgressHub,
+    xfiles: Arc<XFilesStore>,
+    profiles: Arc<ProfileStore>,
+    sender: mpsc::UnboundedSender<String>,
+}
+
+/// Persists jobs in sqlite and runs them on a fixed number of workers, retrying failures with backoff.
+#[derive(Clone)]
+pub struct JobQueue {
+    inner: Arc<JobQueueInner>,
+}
+
+impl JobQueue {
+    /// Opens the job store, starts the workers and resumes the jobs a previous run left unfinished.
+    pub fn start(
+        icp: IcpClient,
+        llm: LlmClient,
+        config: JobConfig,
+        idempotency: Arc<IdempotencyStore>,
+        progress: ProgressHub,
+        xfiles: Arc<XFilesStore>,
+        profiles: Arc<ProfileStore>,
+    ) -> Result<Self, Error> {
+        let store = JobStore::open(&config.db_file)?;
+        let (sender, receiver) = mpsc::unbounded_channel::<String>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = config.workers;
+
+        let queue = JobQueue { inner: Arc::new(JobQueueInner { store, config, idempotency, progress, xfiles, profiles, sender }) };
+        for _ in 0..workers {
+            tokio::spawn(queue.clone().work(icp.clone(), llm.clone(), receiver.clone()));
+        }
+
+        for job in queue.inner.store.unfinished()? {
+            println!("resume job {} ({:?})", job.id, job.state);
+            queue.inner.progress.open(job.kind.session());
+            queue.dispatch(&job.id);
+        }
+
+        Ok(queue)
+    }
+
+    /// Enqueues the job unless `key` is already running or done, in which case the id of that job is returned.
+    /// The key stays claimed until the job succeeds or fails for good, however long it waits in the queue or for retries.
+    pub fn enqueue_once(&self, key: &str, kind: JobKind) -> Result<String, Error> {
+        let id = uuid::Uuid::new_v4().to_string();
+        match self.inner.idempotency.begin_until_finished(key, Some(&id))? {
+            Claim::Acquired => Ok(self.enqueue(id, kind, Some(key.to_string()))?.id),
+            Claim::InProgress(job_id) | Claim::Completed(job_id) => Ok(job_id.unwrap_or_default()),
+        }
+    }
+
+    fn enqueue(&self, id: String, kind: JobKind, idempotency_key: Option<String>) -> Result<Job, Error> {
+        let now = get_now_secs();
+        let mut job = Job {
+            id,
+            kind,
+            state: JobState::Queued,
+            attempts: 0,
+            result: None,
+            error: None,
+            idempotency_key,
+            created_at: now,
+            updated_at: now,
+        };
+        if let Err(e) = self.inner.store.save(&mut job) {
+            if let Some(key) = &job.idempotency_key {
+                if let Err(release) = self.inner.idempotency.fail(key, &e.to_string()) {
+                    println!("release idempotency key of job {} error: {}", job.id, release);
+                }
+            }
+            return Err(e);
+        }
+        self.inner.progress.open(job.kind.session());
+        self.dispatch(&job.id);
+
+        Ok(job)
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<Job>, Error> {
+        if uuid::Uuid::parse_str(id).is_err() {
+            return Ok(None);
+        }
+        self.inner.store.load(id)
+    }
+
+    fn dispatch(&self, id: &str) {
+        if let Err(e) = self.inner.sender.send(id.to_string()) {
+            println!("dispatch job {} error: {}", id, e);
+        }
+    }
+
+    async fn work(self, icp: IcpClient, llm: LlmClient, receiver: Arc<Mutex<mpsc::UnboundedReceiver<String>>>) {
+        loop {
+            let id = match receiver.lock().await.recv().await {
+                Some(id) => id,
+                None => return,
+            };
+            if let Err(e) = self.run(&icp, &llm, &id).await {
+                println!("job {} store error: {}", id, e);
+            }
+        }
+    }
+
+    async fn run(&self, icp: &IcpClient, llm: &LlmClient, id: &str) -> Result<(), Error> {
+        let store = &self.inner.store;
+        let Some(mut job) = store.load(id)? else {
+            return Ok(());
+        };
+        if matches!(job.state, JobState::Succeeded | JobState::Failed) {
+            return Ok(());
+        }
+
+        job.state = JobState::Running;
+        job.attempts += 1;
+        store.save(&mut job)?;
+
+        let retry_in = match job.kind.run(icp, llm, &self.inner.xfiles, &self.inner.profiles, &self.inner.progress).await {
+            Ok(result) => {
+                job.state = JobState::Succeeded;
+                job.result = Some(result);
+                job.error = None;
+                self.inner.progress.publish(job.kind.session(), ProgressStage::Done, job.id.clone());
+                None
+            }
+            Err(e) => {
+                println!("job {} attempt {} error: {}", job.id, job.attempts, e);
+                job.error = Some(e.to_string());
+                if job.attempts < self.inner.config.max_attempts {
+                    job.state = JobState::Queued;
+                    Some(self.inner.config.retry_delay.saturating_mul(2u32.saturating_pow(job.attempts - 1)))
+                } else {
+                    job.state = JobState::Failed;
+                    self.inner.progress.publish(job.kind.session(), ProgressStage::Failed, e.to_string());
+                    None
+                }
+            }
+        };
+        store.save(&mut job)?;
+
+        if let Some(key) = &job.idempotency_key {
+            match job.state {
+                JobState::Succeeded => self.inner.idempotency.complete(key, &job.id)?,
+                JobState::Failed => self.inner.idempotency.fail(key, job.error.as_deref().unwrap_or_default())?,
+                _ => (),
+            }
+        }
+
+        // Dispatch only after the queued state is stored, so the retry never sees the job as running.
+        if let Some(delay) = retry_in {
+            let queue = self.clone();
+            let id = job.id;
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                queue.dispatch(&id);
+            });
+        }
+
+        Ok(())
+    }
+}
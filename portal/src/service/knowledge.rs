@@ -0,0 +1,323 @@
+use anyhow::Error;
+use metapower_framework::icp::{cosine_similarity, ChunkMeta, IcpClient, NaisVectorCanister, ScoredChunk, VectorChunk, VectorFilter};
+use metapower_framework::{get_now_secs, ANSWERER_TEMPLATE_RAG};
+use serde::{Deserialize, Serialize};
+
+use super::ai_town::query_embedding;
+use super::llm_gateway::{LlmClient, LlmGateway};
+use super::llm_proxy::get_content_embeddings;
+
+const DEFAULT_CHUNK_CHARS: usize = 1000;
+const DEFAULT_CHUNK_OVERLAP: usize = 200;
+const DEFAULT_SEARCH_K: usize = 5;
+const MAX_SEARCH_K: usize = 50;
+/// The vector canister cannot filter, so filtered searches ask for this many times more passages than they keep.
+const SEARCH_OVERFETCH: usize = 8;
+const DEFAULT_ASK_K: usize = 4;
+const DEFAULT_ASK_DOMAIN: &str = "uploaded documents";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkConfig {
+    /// Passage length in characters.
+    pub size: usize,
+    /// Characters shared by neighbouring passages, so a sentence cut at a boundary is still found whole.
+    pub overlap: usize,
+}
+
+impl Default for ChunkConfig {
+    fn default() -> Self {
+        ChunkConfig {
+            size: DEFAULT_CHUNK_CHARS,
+            overlap: DEFAULT_CHUNK_OVERLAP,
+        }
+    }
+}
+
+impl ChunkConfig {
+    pub fn from_env() -> Self {
+        let mut config = ChunkConfig::default();
+
+        if let Some(size) = std::env::var("KNOWLEDGE_CHUNK_CHARS").ok().and_then(|s| s.parse::<usize>().ok()) {
+            config.size = size.max(1);
+        }
+        if let Some(overlap) = std::env::var("KNOWLEDGE_CHUNK_OVERLAP").ok().and_then(|s| s.parse::<usize>().ok()) {
+            config.overlap = overlap;
+        }
+        config.overlap = config.overlap.min(config.size - 1);
+
+        config
+    }
+}
+
+/// Splits text into passages of `size` characters, each starting `size - overlap` after the previous one.
+/// Returns the character offset of every passage along with its text; blank passages are dropped.
+pub fn split_chunks(text: &str, config: &ChunkConfig) -> Vec<(usize, String)> {
+    let chars: Vec<char> = text.chars().collect();
+    let step = config.size.saturating_sub(config.overlap).max(1);
+    let mut chunks = vec![];
+    let mut start = 0;
+
+    while start < chars.len() {
+        let end = (start + config.size).min(chars.len());
+        let chunk: String = chars[start..end].iter().collect();
+        if !chunk.trim().is_empty() {
+            chunks.push((start, chunk));
+        }
+        if end == chars.len() {
+            break;
+        }
+        start += step;
+    }
+
+    chunks
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IngestState {
+    Indexing,
+    Indexed,
+    Failed,
+}
+
+/// Progress of one document through the vector index, stored next to the document as a session asset.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IngestionStatus {
+    pub doc_id: String,
+    pub owner: String,
+    pub title: String,
+    pub state: IngestState,
+    pub chunk_size: usize,
+    pub chunk_overlap: usize,
+    pub chunks_total: usize,
+    pub chunks_done: usize,
+    pub error: Option<String>,
+    pub updated_at: u64,
+}
+
+/// A document stored under `owner/sig/file_name` in the session assets.
+#[derive(Debug, Clone)]
+pub struct KnowledgeDoc {
+    pub owner: String,
+    pub sig: String,
+    pub file_name: String,
+    pub title: String,
+}
+
+pub fn status_file(file_name: &str) -> String {
+    format!("{}.ingest", file_name)
+}
+
+pub async fn ingestion_status(icp: &IcpClient, id: &str, sig: &str, file_name: &str) -> Result<Option<IngestionStatus>, Error> {
+    let (exists, data, _) = icp.check_session_asset(id, sig, &status_file(file_name)).await?;
+    if !exists {
+        return Ok(None);
+    }
+
+    Ok(Some(serde_json::from_slice::<IngestionStatus>(&data)?))
+}
+
+async fn save_status(icp: &IcpClient, sig: &str, file_name: &str, status: &mut IngestionStatus) -> Result<(), Error> {
+    status.updated_at = get_now_secs();
+    let data = serde_json::to_vec(status)?;
+    icp.save_session_asset(&status.owner, sig, &status_file(file_name), data).await?;
+
+    Ok(())
+}
+
+/// Embeds every passage of a document and adds it to the vector canister, saving the status after each stored passage.
+/// The vector canister has no ids to deduplicate by, so a document that failed or was interrupted part way resumes
+/// after its last stored passage when uploaded again with the same chunking, without adding any passage twice.
+pub async fn ingest_document(icp: &IcpClient, llm: &LlmClient, doc: &KnowledgeDoc, text: &str, config: &ChunkConfig) -> Result<IngestionStatus, Error> {
+    let (id, sig, file_name) = (doc.owner.as_str(), doc.sig.as_str(), doc.file_name.as_str());
+    let chunks = split_chunks(text, config);
+
+    let previous = ingestion_status(icp, id, sig, file_name).await?;
+    let mut status = match previous {
+        Some(status) if status.chunk_size == config.size && status.chunk_overlap == config.overlap => status,
+        _ => IngestionStatus {
+            doc_id: sig.to_string(),
+            owner: id.to_string(),
+            title: doc.title.clone(),
+            state: IngestState::Indexing,
+            chunk_size: config.size,
+            chunk_overlap: config.overlap,
+            chunks_total: chunks.len(),
+            chunks_done: 0,
+            error: None,
+            updated_at: 0,
+        },
+    };
+    if status.state == IngestState::Indexed {
+        return Ok(status);
+    }
+
+    status.state = IngestState::Indexing;
+    status.error = None;
+    save_status(icp, sig, file_name, &mut status).await?;
+
+    for (offset, passage) in chunks.into_iter().skip(status.chunks_done) {
+        let meta = ChunkMeta {
+            doc_id: sig.to_string(),
+            owner: id.to_string(),
+            title: doc.title.clone(),
+            offset,
+            topic_id: None,
+        };
+
+        let indexed = match get_content_embeddings(llm, passage.clone()).await {
+            Ok(embeddings) => icp.add(VectorChunk::new(meta, passage).into_doc(embeddings)).await.map_err(Error::from),
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = indexed {
+            println!("ingest {} chunk {} error: {}", sig, status.chunks_done, e);
+            status.state = IngestState::Failed;
+            status.error = Some(e.to_string());
+            save_status(icp, sig, file_name, &mut status).await?;
+            return Err(e);
+        }
+
+        status.chunks_done += 1;
+        if status.chunks_done < status.chunks_total {
+            save_status(icp, sig, file_name, &mut status).await?;
+        }
+    }
+
+    status.state = IngestState::Indexed;
+    save_status(icp, sig, file_name, &mut status).await?;
+
+    Ok(status)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AskRequest {
+    pub question: String,
+    /// Answer from every document this pato uploaded.
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Or only from these documents.
+    #[serde(default)]
+    pub sigs: Vec<String>,
+    #[serde(default)]
+    pub k: Option<usize>,
+    #[serde(default)]
+    pub min_score: Option<f32>,
+    #[serde(default)]
+    pub domain: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SearchOptions {
+    #[serde(default)]
+    pub k: Option<usize>,
+    #[serde(default)]
+    pub filter: VectorFilter,
+    #[serde(default)]
+    pub min_score: Option<f32>,
+}
+
+/// Passages nearest to `query` that pass the filter, best first. The vector canister returns neither scores nor
+/// metadata filtering, so filtered searches fetch extra candidates and the portal scores them itself, only with the
+/// passage vectors the embedding cache kept at indexing time; no passage is embedded again on the request path.
+/// Passages without a cached vector have no score and keep the canister's order, and a `min_score` drops them.
+pub async fn search_knowledge(icp: &IcpClient, llm: &LlmClient, query: String, options: &SearchOptions) -> Result<Vec<ScoredChunk>, Error> {
+    let k = options.k.unwrap_or(DEFAULT_SEARCH_K).clamp(1, MAX_SEARCH_K);
+    let fetch = if options.filter.is_empty() { k } else { k * SEARCH_OVERFETCH };
+
+    let embeddings = get_content_embeddings(llm, query).await?;
+    let docs = query_embedding(icp, embeddings.clone(), fetch).await?.unwrap_or_default();
+
+    let mut candidates: Vec<ScoredChunk> = docs
+        .iter()
+        .map(VectorChunk::from)
+        .filter(|chunk| options.filter.matches(chunk.meta.as_ref()))
+        .map(|chunk| {
+            let cached = llm.embedding_cache().and_then(|cache| cache.get(&chunk.text));
+            let score = cached.map(|vector| cosine_similarity(&embeddings, &vector));
+            ScoredChunk { score, meta: chunk.meta, text: chunk.text }
+        })
+        .collect();
+    if let Some(min_score) = options.min_score {
+        candidates.retain(|chunk| chunk.score.is_some_and(|score| score >= min_score));
+    }
+
+    // Scored passages are re-ranked among the places they hold; unscored ones stay where the canister put them.
+    let places: Vec<usize> = candidates.iter().enumerate().filter(|(_, chunk)| chunk.score.is_some()).map(|(i, _)| i).collect();
+    let mut ranked: Vec<ScoredChunk> = places.iter().map(|i| candidates[*i].clone()).collect();
+    ranked.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    for (place, chunk) in places.into_iter().zip(ranked) {
+        candidates[place] = chunk;
+    }
+    candidates.truncate(k);
+
+    Ok(candidates)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Citation {
+    pub sig: String,
+    pub title: String,
+    pub offset: usize,
+    pub score: Option<f32>,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AskAnswer {
+    pub answer: String,
+    pub citations: Vec<Citation>,
+}
+
+async fn retrieve_passages(icp: &IcpClient, llm: &LlmClient, request: &AskRequest) -> Result<Vec<Citation>, Error> {
+    let options = SearchOptions {
+        k: Some(request.k.unwrap_or(DEFAULT_ASK_K)),
+        filter: VectorFilter {
+            owner: request.id.clone(),
+            sigs: request.sigs.clone(),
+            topic_id: None,
+        },
+        min_score: request.min_score,
+    };
+
+    let citations = search_knowledge(icp, llm, request.question.clone(), &options)
+        .await?
+        .into_iter()
+        .filter_map(|chunk| {
+            let meta = chunk.meta?;
+            Some(Citation {
+                sig: meta.doc_id,
+                title: meta.title,
+                offset: meta.offset,
+                score: chunk.score,
+                text: chunk.text,
+            })
+        })
+        .collect();
+
+    Ok(citations)
+}
+
+fn rag_prompt(domain: &str, citations: &[Citation]) -> String {
+    let context = citations
+        .iter()
+        .enumerate()
+        .map(|(index, citation)| format!("[{}] {} (offset {}):\n{}", index + 1, citation.title, citation.offset, citation.text))
+        .collect::<Vec<String>>()
+        .join("\n\n");
+
+    ANSWERER_TEMPLATE_RAG.replace("{domain}", domain).replace("{context}", &context)
+}
+
+/// Answers a question from the passages of a pato's documents, or of the given documents. Returns `None`
+/// without asking the LLM when nothing in scope was found, since the template only allows answers from context.
+pub async fn ask_knowledge(icp: &IcpClient, llm: &LlmClient, request: AskRequest) -> Result<Option<AskAnswer>, Error> {
+    let citations = retrieve_passages(icp, llm, &request).await?;
+    if citations.is_empty() {
+        return Ok(None);
+    }
+
+    let domain = request.domain.clone().unwrap_or_else(|| DEFAULT_ASK_DOMAIN.to_string());
+    let answer = llm.answer(rag_prompt(&domain, &citations), request.question).await?;
+
+    Ok(Some(AskAnswer { answer, citations }))
+}
@@ -0,0 +1,566 @@
+#![allow(async_fn_in_trait)]
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use metapower_framework::service::llmchat_model::llmchat_grpc::chat_svc_client::ChatSvcClient;
+use metapower_framework::service::llmchat_model::llmchat_grpc::{
+    AnswerRequest, CharacterGenRequest as GrpcCharacterGenRequest, ImageDescriptionRequest, ImageGenRequest as GrpcImageGenRequest,
+    MultiImagesGenRequest, QuestionRequest, SomeDocs,
+};
+use metapower_framework::{ensure_directory_exists, LLMCHAT_GRPC_REST_SERVER, XFILES_LOCAL_DIR};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use tonic::transport::{Channel, Endpoint};
+
+use super::embedding_cache::EmbeddingCache;
+use super::llm_proxy::CharacterGenRequest;
+use super::xfiles::{XFileKind, XFilesStore};
+
+const DEFAULT_LLM_BASE_URL: &str = "https://llm.metapowermatrix.ai";
+const DEFAULT_LLM_TIMEOUT_SECS: u64 = 120;
+const FAKE_EMBEDDING_DIMENSIONS: usize = 32;
+const DEFAULT_EMBEDDING_MODEL: &str = "default";
+
+#[derive(Clone, Serialize)]
+struct ImageGenRequest {
+    pub prompt: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub negative_prompt: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u32>,
+}
+
+impl ImageGenRequest {
+    fn new(prompt: String) -> Self {
+        ImageGenRequest { prompt, negative_prompt: String::new(), seed: None }
+    }
+}
+
+/// Asks for `num_images` variants of one prompt; variant `i` is generated with `seed + i`.
+#[derive(Debug, Clone)]
+pub struct ImagesGenRequest {
+    pub prompt: String,
+    pub negative_prompt: String,
+    pub num_images: u32,
+    pub seed: u32,
+}
+
+#[derive(Clone, Serialize)]
+struct TopicCommentRequest {
+    pub topic: String,
+    pub prompt: String,
+}
+
+#[derive(Clone, Serialize)]
+struct AnswerPromptRequest {
+    pub question: String,
+    pub prompt: String,
+}
+
+#[derive(Clone, Serialize)]
+struct FileGenRequest {
+    pub content: String,
+}
+
+/// The generation endpoints of the LLM service used by the portal flows.
+pub trait LlmGateway {
+    async fn embedding(&self, content: String) -> Result<Vec<f32>, Error>;
+    async fn summary(&self, content: String) -> Result<String, Error>;
+    async fn character(&self, request: CharacterGenRequest) -> Result<String, Error>;
+    /// Returns the url of the generated avatar.
+    async fn avatar(&self, prompt: String) -> Result<String, Error>;
+    /// Returns the url of the generated image.
+    async fn image(&self, prompt: String) -> Result<String, Error>;
+    /// Returns the urls of the generated variants, in seed order.
+    async fn images(&self, request: ImagesGenRequest) -> Result<Vec<String>, Error>;
+    async fn image_description(&self, image_url: String) -> Result<String, Error>;
+    async fn topic_comment(&self, topic: String, prompt: String) -> Result<String, Error>;
+    /// Answers `question` following the instructions and context in `prompt`.
+    async fn answer(&self, prompt: String, question: String) -> Result<String, Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LlmBackendKind {
+    Http,
+    Grpc,
+}
+
+impl LlmBackendKind {
+    /// `grpc` selects the ChatSvc server, anything else the REST gateway.
+    pub fn parse(name: &str) -> Self {
+        if name.trim().eq_ignore_ascii_case("grpc") {
+            LlmBackendKind::Grpc
+        } else {
+            LlmBackendKind::Http
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LlmConfig {
+    pub backend: LlmBackendKind,
+    pub base_url: String,
+    pub grpc_url: String,
+    pub timeout: Duration,
+    /// Part of the embedding cache key, so switching models does not reuse old vectors.
+    pub embedding_model: String,
+    /// Sqlite file of the embedding cache, `None` to embed every text again.
+    pub embedding_cache_db: Option<String>,
+}
+
+impl Default for LlmConfig {
+    fn default() -> Self {
+        LlmConfig::in_root(XFILES_LOCAL_DIR)
+    }
+}
+
+impl LlmConfig {
+    /// Defaults with the embedding cache under the local data `root`.
+    pub fn in_root(root: &str) -> Self {
+        LlmConfig {
+            backend: LlmBackendKind::Http,
+            base_url: DEFAULT_LLM_BASE_URL.to_string(),
+            grpc_url: LLMCHAT_GRPC_REST_SERVER.to_string(),
+            timeout: Duration::from_secs(DEFAULT_LLM_TIMEOUT_SECS),
+            embedding_model: DEFAULT_EMBEDDING_MODEL.to_string(),
+            embedding_cache_db: Some(format!("{}/llm/embedding_cache.db", root)),
+        }
+    }
+
+    pub fn from_env(root: &str) -> Self {
+        let mut config = LlmConfig::in_root(root);
+
+        config.backend = LlmBackendKind::parse(&std::env::var("LLM_BACKEND").unwrap_or_default());
+        if let Ok(grpc_url) = std::env::var("LLM_GRPC_URL") {
+            config.grpc_url = grpc_url;
+        }
+        if let Ok(base_url) = std::env::var("LLM_BASE_URL") {
+            config.base_url = base_url.trim_end_matches('/').to_string();
+        }
+        if let Some(secs) = std::env::var("LLM_TIMEOUT_SECS").ok().and_then(|s| s.parse::<u64>().ok()) {
+            config.timeout = Duration::from_secs(secs);
+        }
+        if let Ok(model) = std::env::var("EMBEDDING_MODEL") {
+            config.embedding_model = model;
+        }
+        match std::env::var("EMBEDDING_CACHE_DB") {
+            Ok(db) if db.eq_ignore_ascii_case("off") => config.embedding_cache_db = None,
+            Ok(db) => config.embedding_cache_db = Some(db),
+            Err(_) => {}
+        }
+
+        config
+    }
+}
+
+pub struct HttpLlmGateway {
+    client: reqwest::Client,
+    base_url: String,
+}
+
+impl HttpLlmGateway {
+    pub fn new(config: &LlmConfig) -> Result<Self, Error> {
+        let client = reqwest::Client::builder().timeout(config.timeout).build()?;
+
+        Ok(HttpLlmGateway { client, base_url: config.base_url.clone() })
+    }
+
+    async fn post<Req: Serialize, Resp: DeserializeOwned>(&self, path: &str, request: &Req) -> Result<Resp, Error> {
+        let url = format!("{}{}", self.base_url, path);
+        let response = self.client.post(&url).json(request).send().await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow!("llm {} returned {}: {}", path, status, body));
+        }
+
+        Ok(response.json::<Resp>().await?)
+    }
+}
+
+impl LlmGateway for HttpLlmGateway {
+    async fn embedding(&self, content: String) -> Result<Vec<f32>, Error> {
+        self.post("/api/gen/embedding", &FileGenRequest { content }).await
+    }
+    async fn summary(&self, content: String) -> Result<String, Error> {
+        self.post("/api/gen/summary", &FileGenRequest { content }).await
+    }
+    async fn character(&self, request: CharacterGenRequest) -> Result<String, Error> {
+        self.post("/api/gen/character", &request).await
+    }
+    async fn avatar(&self, prompt: String) -> Result<String, Error> {
+        self.post("/api/gen/avatar", &ImageGenRequest::new(prompt)).await
+    }
+    async fn image(&self, prompt: String) -> Result<String, Error> {
+        self.post("/api/gen/image", &ImageGenRequest::new(prompt)).await
+    }
+    /// One `/api/gen/image` call per variant, each with its own seed.
+    async fn images(&self, request: ImagesGenRequest) -> Result<Vec<String>, Error> {
+        let mut urls = vec![];
+        for i in 0..request.num_images {
+            let variant = ImageGenRequest {
+                prompt: request.prompt.clone(),
+                negative_prompt: request.negative_prompt.clone(),
+                seed: Some(request.seed.wrapping_add(i)),
+            };
+            urls.push(self.post("/api/gen/image", &variant).await?);
+        }
+
+        Ok(urls)
+    }
+    async fn image_description(&self, image_url: String) -> Result<String, Error> {
+        self.post("/api/gen/image/description", &FileGenRequest { content: image_url }).await
+    }
+    async fn topic_comment(&self, topic: String, prompt: String) -> Result<String, Error> {
+        self.post("/api/chat/topic", &TopicCommentRequest { topic, prompt }).await
+    }
+    async fn answer(&self, prompt: String, question: String) -> Result<String, Error> {
+        self.post("/api/chat/answer", &AnswerPromptRequest { question, prompt }).await
+    }
+}
+
+/// Talks to the framework's ChatSvc server. ChatSvc has no rpc returning a raw embedding vector,
+/// so embeddings still come from the REST gateway.
+pub struct GrpcLlmGateway {
+    client: ChatSvcClient<Channel>,
+    http: HttpLlmGateway,
+    xfiles: Arc<XFilesStore>,
+}
+
+impl From<CharacterGenRequest> for GrpcCharacterGenRequest {
+    fn from(request: CharacterGenRequest) -> Self {
+        GrpcCharacterGenRequest {
+            tags: request.tags,
+            name: request.name,
+            gender: request.gender,
+            personality: request.personality,
+        }
+    }
+}
+
+impl GrpcLlmGateway {
+    pub fn new(config: &LlmConfig, xfiles: Arc<XFilesStore>) -> Result<Self, Error> {
+        let channel = Endpoint::from_shared(config.grpc_url.clone())?
+            .timeout(config.timeout)
+            .connect_lazy();
+
+        Ok(GrpcLlmGateway {
+            client: ChatSvcClient::new(channel),
+            http: HttpLlmGateway::new(config)?,
+            xfiles,
+        })
+    }
+
+    /// ChatSvc runs on another host, so it fetches the document from the xfiles server rather than the portal's disk.
+    fn write_document(&self, content: &str) -> Result<String, Error> {
+        let path = format!("llm/docs/{:x}.txt", Sha1::digest(content.as_bytes()));
+        self.xfiles.put(&path, "", "", XFileKind::Document, content.as_bytes())?;
+
+        Ok(self.xfiles.url(&path))
+    }
+}
+
+impl LlmGateway for GrpcLlmGateway {
+    async fn embedding(&self, content: String) -> Result<Vec<f32>, Error> {
+        self.http.embedding(content).await
+    }
+    async fn summary(&self, content: String) -> Result<String, Error> {
+        let doc_file = self.write_document(&content)?;
+        let request = SomeDocs { doc_file, doc_format: "txt".to_string() };
+        let response = self.client.clone().got_documents_summary(request).await?;
+
+        Ok(response.into_inner().summary)
+    }
+    async fn character(&self, request: CharacterGenRequest) -> Result<String, Error> {
+        let response = self.client.clone().gen_character_with_prompt(GrpcCharacterGenRequest::from(request)).await?;
+
+        Ok(response.into_inner().iss)
+    }
+    async fn avatar(&self, prompt: String) -> Result<String, Error> {
+        self.image(prompt).await
+    }
+    async fn image(&self, prompt: String) -> Result<String, Error> {
+        let response = self.client.clone().gen_image_with_prompt(GrpcImageGenRequest { prompt }).await?;
+
+        Ok(response.into_inner().image_url)
+    }
+    /// One single-image request per variant, so every image is generated with the seed the gallery records for it.
+    async fn images(&self, request: ImagesGenRequest) -> Result<Vec<String>, Error> {
+        let mut urls = vec![];
+        for i in 0..request.num_images {
+            let variant = MultiImagesGenRequest {
+                prompt: request.prompt.clone(),
+                num_images: 1,
+                negative_prompt: request.negative_prompt.clone(),
+                seed: request.seed.wrapping_add(i) as i64,
+            };
+            let response = self.client.clone().gen_multi_images_with_prompt(variant).await?;
+            urls.extend(response.into_inner().image_url.into_iter().take(1));
+        }
+
+        Ok(urls)
+    }
+    async fn image_description(&self, image_url: String) -> Result<String, Error> {
+        let response = self.client.clone().request_image_description(ImageDescriptionRequest { image_url }).await?;
+
+        Ok(response.into_inner().description)
+    }
+    async fn topic_comment(&self, topic: String, prompt: String) -> Result<String, Error> {
+        let request = QuestionRequest { question: prompt, subject: topic, persona: String::new() };
+        let response = self.client.clone().talk(request).await?;
+
+        Ok(response.into_inner().answer)
+    }
+    /// ChatSvc servers older than `AnswerWithPrompt` answer through `Talk`, with the prompt as the persona.
+    async fn answer(&self, prompt: String, question: String) -> Result<String, Error> {
+        let request = AnswerRequest { question: question.clone(), prompt: prompt.clone() };
+        match self.client.clone().answer_with_prompt(request).await {
+            Ok(response) => Ok(response.into_inner().answer),
+            Err(status) if status.code() == tonic::Code::Unimplemented => {
+                let request = QuestionRequest { question, subject: String::new(), persona: prompt };
+                let response = self.client.clone().talk(request).await?;
+
+                Ok(response.into_inner().answer)
+            }
+            Err(status) => Err(status.into()),
+        }
+    }
+}
+
+#[derive(Default)]
+struct FakeLlmState {
+    summary: Option<String>,
+    image_url: String,
+    calls: Vec<String>,
+    last_prompt: Option<String>,
+    last_character: Option<CharacterGenRequest>,
+}
+
+/// Offline stand-in for the LLM service with deterministic answers, for tests and local runs.
+#[derive(Default)]
+pub struct FakeLlmGateway {
+    state: Mutex<FakeLlmState>,
+}
+
+impl FakeLlmGateway {
+    pub fn new() -> Self {
+        FakeLlmGateway::default()
+    }
+
+    /// Answers every summary request with `summary` instead of the leading words of the content.
+    pub fn set_summary(&self, summary: &str) {
+        self.state.lock().unwrap().summary = Some(summary.to_string());
+    }
+
+    pub fn set_image_url(&self, url: &str) {
+        self.state.lock().unwrap().image_url = url.to_string();
+    }
+
+    /// Names of the gateway methods called so far, in order.
+    pub fn calls(&self) -> Vec<String> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    /// The prompt of the last `answer` call.
+    pub fn last_prompt(&self) -> Option<String> {
+        self.state.lock().unwrap().last_prompt.clone()
+    }
+
+    /// The request of the last `character` call.
+    pub fn last_character(&self) -> Option<CharacterGenRequest> {
+        self.state.lock().unwrap().last_character.clone()
+    }
+
+    fn record(&self, call: &str) {
+        self.state.lock().unwrap().calls.push(call.to_string());
+    }
+
+    /// Hashes the words into a small normalized bag-of-words vector, so similar texts get similar embeddings.
+    pub fn embed(content: &str) -> Vec<f32> {
+        let mut embedding = vec![0.0f32; FAKE_EMBEDDING_DIMENSIONS];
+        for word in content.split_whitespace() {
+            let word = word.to_lowercase();
+            let index = word.bytes().fold(0usize, |h, b| h.wrapping_mul(31).wrapping_add(b as usize)) % FAKE_EMBEDDING_DIMENSIONS;
+            embedding[index] += 1.0;
+        }
+
+        let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            embedding.iter_mut().for_each(|x| *x /= norm);
+        }
+        embedding
+    }
+}
+
+impl LlmGateway for FakeLlmGateway {
+    async fn embedding(&self, content: String) -> Result<Vec<f32>, Error> {
+        self.record("embedding");
+        Ok(FakeLlmGateway::embed(&content))
+    }
+    async fn summary(&self, content: String) -> Result<String, Error> {
+        self.record("summary");
+        let summary = self.state.lock().unwrap().summary.clone();
+        Ok(summary.unwrap_or_else(|| content.split_whitespace().take(12).collect::<Vec<&str>>().join(" ")))
+    }
+    async fn character(&self, request: CharacterGenRequest) -> Result<String, Error> {
+        self.record("character");
+        self.state.lock().unwrap().last_character = Some(request.clone());
+        Ok(format!("{} likes {}", request.name, request.tags.join(", ")))
+    }
+    async fn avatar(&self, _prompt: String) -> Result<String, Error> {
+        self.record("avatar");
+        Ok(self.state.lock().unwrap().image_url.clone())
+    }
+    async fn image(&self, _prompt: String) -> Result<String, Error> {
+        self.record("image");
+        Ok(self.state.lock().unwrap().image_url.clone())
+    }
+    async fn images(&self, request: ImagesGenRequest) -> Result<Vec<String>, Error> {
+        self.record("images");
+        let image_url = self.state.lock().unwrap().image_url.clone();
+        Ok((0..request.num_images).map(|i| format!("{}?seed={}", image_url, request.seed.wrapping_add(i))).collect())
+    }
+    async fn image_description(&self, image_url: String) -> Result<String, Error> {
+        self.record("image_description");
+        Ok(format!("an image at {}", image_url))
+    }
+    async fn topic_comment(&self, topic: String, _prompt: String) -> Result<String, Error> {
+        self.record("topic_comment");
+        Ok(format!("a comment on {}", topic))
+    }
+    async fn answer(&self, prompt: String, question: String) -> Result<String, Error> {
+        self.record("answer");
+        self.state.lock().unwrap().last_prompt = Some(prompt);
+        Ok(format!("an answer to {}", question))
+    }
+}
+
+enum Backend {
+    Http(HttpLlmGateway),
+    Grpc(GrpcLlmGateway),
+    Fake(Arc<FakeLlmGateway>),
+}
+
+/// Shared handle to whichever LLM backend the portal was configured with.
+#[derive(Clone)]
+pub struct LlmClient {
+    backend: Arc<Backend>,
+    embedding_cache: Option<Arc<EmbeddingCache>>,
+}
+
+impl LlmClient {
+    /// `xfiles` holds the documents handed to the gRPC backend.
+    pub fn new(config: &LlmConfig, xfiles: Arc<XFilesStore>) -> Result<Self, Error> {
+        let backend = match config.backend {
+            LlmBackendKind::Http => Backend::Http(HttpLlmGateway::new(config)?),
+            LlmBackendKind::Grpc => Backend::Grpc(GrpcLlmGateway::new(config, xfiles)?),
+        };
+
+        let embedding_cache = config.embedding_cache_db.as_ref().and_then(|db| {
+            if let Some(dir) = std::path::Path::new(db).parent() {
+                let _ = ensure_directory_exists(&dir.to_string_lossy());
+            }
+            match EmbeddingCache::open(db, &config.embedding_model) {
+                Ok(cache) => Some(Arc::new(cache)),
+                Err(e) => {
+                    println!("embedding cache disabled, cannot open {}: {}", db, e);
+                    None
+                }
+            }
+        });
+
+        Ok(LlmClient { backend: Arc::new(backend), embedding_cache })
+    }
+
+    pub fn with_fake(fake: Arc<FakeLlmGateway>) -> Self {
+        LlmClient { backend: Arc::new(Backend::Fake(fake)), embedding_cache: None }
+    }
+
+    pub fn with_embedding_cache(mut self, cache: EmbeddingCache) -> Self {
+        self.embedding_cache = Some(Arc::new(cache));
+        self
+    }
+
+    pub fn embedding_cache(&self) -> Option<&EmbeddingCache> {
+        self.embedding_cache.as_deref()
+    }
+
+    /// The backend the client talks to, `None` for the fake gateway.
+    pub fn backend_kind(&self) -> Option<LlmBackendKind> {
+        match self.backend.as_ref() {
+            Backend::Http(_) => Some(LlmBackendKind::Http),
+            Backend::Grpc(_) => Some(LlmBackendKind::Grpc),
+            Backend::Fake(_) => None,
+        }
+    }
+}
+
+impl LlmGateway for LlmClient {
+    async fn embedding(&self, content: String) -> Result<Vec<f32>, Error> {
+        match self.backend.as_ref() {
+            Backend::Http(http) => http.embedding(content).await,
+            Backend::Grpc(grpc) => grpc.embedding(content).await,
+            Backend::Fake(fake) => fake.embedding(content).await,
+        }
+    }
+    async fn summary(&self, content: String) -> Result<String, Error> {
+        match self.backend.as_ref() {
+            Backend::Http(http) => http.summary(content).await,
+            Backend::Grpc(grpc) => grpc.summary(content).await,
+            Backend::Fake(fake) => fake.summary(content).await,
+        }
+    }
+    async fn character(&self, request: CharacterGenRequest) -> Result<String, Error> {
+        match self.backend.as_ref() {
+            Backend::Http(http) => http.character(request).await,
+            Backend::Grpc(grpc) => grpc.character(request).await,
+            Backend::Fake(fake) => fake.character(request).await,
+        }
+    }
+    async fn avatar(&self, prompt: String) -> Result<String, Error> {
+        match self.backend.as_ref() {
+            Backend::Http(http) => http.avatar(prompt).await,
+            Backend::Grpc(grpc) => grpc.avatar(prompt).await,
+            Backend::Fake(fake) => fake.avatar(prompt).await,
+        }
+    }
+    async fn image(&self, prompt: String) -> Result<String, Error> {
+        match self.backend.as_ref() {
+            Backend::Http(http) => http.image(prompt).await,
+            Backend::Grpc(grpc) => grpc.image(prompt).await,
+            Backend::Fake(fake) => fake.image(prompt).await,
+        }
+    }
+    async fn images(&self, request: ImagesGenRequest) -> Result<Vec<String>, Error> {
+        match self.backend.as_ref() {
+            Backend::Http(http) => http.images(request).await,
+            Backend::Grpc(grpc) => grpc.images(request).await,
+            Backend::Fake(fake) => fake.images(request).await,
+        }
+    }
+    async fn image_description(&self, image_url: String) -> Result<String, Error> {
+        match self.backend.as_ref() {
+            Backend::Http(http) => http.image_description(image_url).await,
+            Backend::Grpc(grpc) => grpc.image_description(image_url).await,
+            Backend::Fake(fake) => fake.image_description(image_url).await,
+        }
+    }
+    async fn topic_comment(&self, topic: String, prompt: String) -> Result<String, Error> {
+        match self.backend.as_ref() {
+            Backend::Http(http) => http.topic_comment(topic, prompt).await,
+            Backend::Grpc(grpc) => grpc.topic_comment(topic, prompt).await,
+            Backend::Fake(fake) => fake.topic_comment(topic, prompt).await,
+        }
+    }
+    async fn answer(&self, prompt: String, question: String) -> Result<String, Error> {
+        match self.backend.as_ref() {
+            Backend::Http(http) => http.answer(prompt, question).await,
+            Backend::Grpc(grpc) => grpc.answer(prompt, question).await,
+            Backend::Fake(fake) => fake.answer(prompt, question).await,
+        }
+    }
+}
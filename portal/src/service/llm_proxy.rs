@@ -1,57 +1,46 @@
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::Read;
-use std::io::Write;
 
-use anyhow::anyhow;
-use anyhow::Error;
+use anyhow::{anyhow, Error};
 use candid::CandidType;
-use candid::Decode;
-use candid::Encode;
-use candid::Principal;
 use md5::compute;
 use metapower_framework::compute_md5;
-use metapower_framework::dao::crawler::download_image;
-use metapower_framework::ensure_directory_exists;
-use metapower_framework::icp::call_update_method;
-use metapower_framework::icp::init_icp_agent;
-use metapower_framework::icp::AGENT_BATTERY_CANISTER;
-use metapower_framework::icp::AGENT_SMITH_CANISTER;
-use metapower_framework::icp::NAIS_MATRIX_CANISTER;
-use metapower_framework::icp::NAIS_VECTOR_CANISTER;
-use metapower_framework::XFILES_LOCAL_DIR;
-use metapower_framework::XFILES_SERVER;
+use metapower_framework::dao::crawler::download_file;
+use metapower_framework::get_now_secs;
+use metapower_framework::icp::AgentBatteryCanister;
+use metapower_framework::icp::AgentSmithCanister;
+use metapower_framework::icp::ChunkMeta;
+use metapower_framework::icp::IcpClient;
+use metapower_framework::icp::NaisMatrixCanister;
+use metapower_framework::icp::NaisVectorCanister;
+use metapower_framework::icp::VecDoc;
+use metapower_framework::icp::VectorChunk;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use crate::service::PatoInfoResponse;
-use crate::VecDoc;
 
-pub const MAX_SAVE_BYTES: usize = 1024*1024*2;
+use super::gallery::{style_preset, GalleryStore, GeneratedImage, ImageGenError, ImageGenOptions};
+use super::idempotency::{Claim, IdempotencyStore};
+use super::images::{process_image, ImageConfig, StoredImage};
+use super::knowledge::{ingest_document, ChunkConfig, KnowledgeDoc};
+use super::llm_gateway::{ImagesGenRequest, LlmClient, LlmGateway};
+use super::profile::{check_owner, gender_name, ProfileStore};
+use super::progress::{ProgressHub, ProgressStage};
+use super::xfiles::{XFileEntry, XFileKind, XFilesStore};
 
-#[derive(Clone, Serialize)]
-struct ImageGenRequest {
-    pub prompt: String,
-}
-
-#[derive(Clone, Serialize)]
-struct TopicCommentRequest {
-    pub topic: String,
-    pub prompt: String,
-}
-
-#[derive(Clone, Serialize)]
-struct FileGenRequest {
-    pub content: String,
-}
-
-const LLM_HTTP_HOST: &str = "llm.metapowermatrix.ai";
-const LLM_REQUEST_PROTOCOL: &str = "https://";
+/// A vector document goes to the canister in a single message, so it has to stay below the ingress limit.
+const MAX_EMBED_BYTES: usize = 1024*1024*2;
 
-#[derive(Deserialize, CandidType, Serialize, Debug)]
+#[derive(Deserialize, CandidType, Serialize, Debug, Clone)]
 pub struct CharacterGenRequest {
     pub tags: Vec<String>,
     pub name: String,
     pub gender: String,
+    #[serde(default)]
+    pub personality: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UploadedImage {
+    pub description: String,
+    #[serde(flatten)]
+    pub image: StoredImage,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -61,117 +50,78 @@ pub struct ImageGenResponse {
     pub character: String,
 }
 
-async fn get_pato_name(id: String) -> Result<String, Error>{
-    match call_update_method(AGENT_SMITH_CANISTER, "request_pato_info", id).await {
-        Ok(result) => {
-            let response = Decode!(result.as_slice(), PatoInfoResponse).unwrap_or_default();
-
-            Ok(response.name)
-        }
-        Err(e) => Err(anyhow!("request_pato_info error: {}", e)),
+async fn get_pato_name(icp: &IcpClient, id: String) -> Result<String, Error>{
+    match icp.request_pato_info(id).await {
+        Ok(response) => Ok(response.name),
+        Err(e) => Err(e.into()),
     }
 }
-async fn check_session_file(id: String, session_key: String, file_name: String) -> Result<(bool,Vec<u8>, u64), Error>{
-    let agent = init_icp_agent().await?;
-    let effective_canister_id = Principal::from_text(NAIS_MATRIX_CANISTER).unwrap();
-
-    match agent.update(&effective_canister_id, "check_session_assets")
-        .with_effective_canister_id(effective_canister_id)
-        .with_arg(Encode!(&id, &session_key, &file_name)?)
-        .await{
-            Ok(result) => {
-                Ok(Decode!(result.as_slice(), bool, Vec<u8>, u64)?)
-            }
-            Err(e) => {
-                Err(anyhow!(e.to_string()))
-            }
+async fn check_session_file(icp: &IcpClient, id: String, session_key: String, file_name: String) -> Result<(bool,Vec<u8>, u64), Error>{
+    match icp.check_session_asset(&id, &session_key, &file_name).await {
+        Ok(result) => Ok(result),
+        Err(e) => {
+            Err(e.into())
         }
+    }
 }
-pub async fn read_session_file(id: String, session_key: String, file_name: String) -> Result<(Vec<u8>, u64), Error>{
-    let agent = init_icp_agent().await?;
-    let effective_canister_id = Principal::from_text(NAIS_MATRIX_CANISTER).unwrap();
-    
-    match agent.update(&effective_canister_id, "query_session_assets")
-        .with_effective_canister_id(effective_canister_id)
-        .with_arg(Encode!(&id, &session_key, &file_name)?)
-        .await{
-            Ok(result) => {
-                Ok(Decode!(result.as_slice(), Vec<u8>, u64).unwrap_or_default())
-            }
-            Err(e) => {
-                Err(e.into())
-            }
+pub async fn read_session_file(icp: &IcpClient, id: String, session_key: String, file_name: String) -> Result<(Vec<u8>, u64), Error>{
+    match icp.read_session_asset(&id, &session_key, &file_name).await {
+        Ok(result) => Ok(result),
+        Err(e) => {
+            Err(e.into())
         }
+    }
 }
-async fn save_session_file(id: String, session_key: String, file_name: String, data: Vec<u8>) -> Result<(), Error>{
-    let agent = init_icp_agent().await?;
-    let effective_canister_id = Principal::from_text(NAIS_MATRIX_CANISTER).unwrap();
-    
-    match agent.update(&effective_canister_id, "upload_session_assets")
-        .with_effective_canister_id(effective_canister_id)
-        .with_arg(Encode!(&id, &session_key, &file_name, &data)?)
-        .await{
-            Ok(_) => Ok(()),
-            Err(e) => {
-                Err(e.into())
-            }
+async fn save_session_file(icp: &IcpClient, id: String, session_key: String, file_name: String, data: Vec<u8>) -> Result<(), Error>{
+    match icp.save_session_asset(&id, &session_key, &file_name, data).await {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            Err(e.into())
         }
+    }
 }
 
-pub async fn add_embedding(content: String, embeddings: Vec<f32>) -> Result<String, Error> {
-    let agent = init_icp_agent().await?;
-    let effective_canister_id = Principal::from_text(NAIS_VECTOR_CANISTER).unwrap();
+pub async fn add_embedding(icp: &IcpClient, content: String, embeddings: Vec<f32>) -> Result<String, Error> {
     let doc = VecDoc{
         content,
         embeddings,
     };
-    match agent.update(&effective_canister_id, "add")
-        .with_effective_canister_id(effective_canister_id)
-        .with_arg(Encode!(&doc)?)
-        .await{
-            Ok(result) => {
-                Ok(Decode!(result.as_slice(), String).unwrap_or_default())
-            }
-            Err(e) => {
-                Err(e.into())
-            }
+    match icp.add(doc).await {
+        Ok(result) => Ok(result),
+        Err(e) => {
+            Err(e.into())
         }
+    }
 }
-pub async fn get_content_embeddings(content: String) -> Result<Vec<f32>, Error>{
-    let embedding_request = FileGenRequest{ content };
-    let url_embedding = format!("{}{}/api/gen/embedding", LLM_REQUEST_PROTOCOL, LLM_HTTP_HOST);
-
-    let client = reqwest::Client::new();
-    let response = client
-        .post(&url_embedding)
-        .json(&json!(embedding_request))
-        .send()
-        .await?;
+/// Embeds `content`, reusing the vector of an identical text from the embedding cache when there is one.
+pub async fn get_content_embeddings(llm: &LlmClient, content: String) -> Result<Vec<f32>, Error>{
+    let Some(cache) = llm.embedding_cache() else {
+        return llm.embedding(content).await;
+    };
+    if let Some(embedding) = cache.get(&content) {
+        return Ok(embedding);
+    }
 
-    let saved_bytes = response.bytes().await?;
-    let embedding: Vec<f32> = serde_json::from_slice(&saved_bytes)?;
-    // println!("converted embedding: {:?}", embedding);
+    let embedding = llm.embedding(content.clone()).await?;
+    cache.put(&content, &embedding);
 
     Ok(embedding)
 }
 
-pub async fn upload_topic_comment_save_in_canister(content: Vec<u8>) -> Result<(), Error> {
-    let url_embedding = format!("{}{}/api/gen/embedding", LLM_REQUEST_PROTOCOL, LLM_HTTP_HOST);
-
-    let embedding_request = FileGenRequest{ content: String::from_utf8(content.clone()).unwrap_or_default() };
-    let client = reqwest::Client::new();
-
-    if content.len() <= MAX_SAVE_BYTES{
-        let response = client
-            .post(&url_embedding)
-            .json(&json!(embedding_request))
-            .send()
-            .await?;
-
-        let saved_bytes = response.bytes().await?;
-        let embedding: Vec<f32> = serde_json::from_slice(&saved_bytes)?;
+pub async fn upload_topic_comment_save_in_canister(icp: &IcpClient, llm: &LlmClient, content: Vec<u8>, contributor: String) -> Result<(), Error> {
+    if content.len() <= MAX_EMBED_BYTES{
+        let text = String::from_utf8(content).unwrap_or_default();
+        let topic_id = compute_md5(&text);
+        let embedding = get_content_embeddings(llm, text.clone()).await?;
         // println!("embedding: {:?}", embedding);
-        match add_embedding(String::from_utf8(content.clone()).unwrap_or_default(), embedding).await{
+        let meta = ChunkMeta {
+            doc_id: topic_id.clone(),
+            owner: contributor,
+            title: String::new(),
+            offset: 0,
+            topic_id: Some(topic_id),
+        };
+        match add_embedding(icp, VectorChunk::new(meta, text).to_content(), embedding).await{
             Ok(_) => {}
             Err(e) => {
                 println!("add_embedding error: {}", e);
@@ -183,216 +133,136 @@ pub async fn upload_topic_comment_save_in_canister(content: Vec<u8>) -> Result<(
     Ok(())
 }
 
-pub async fn upload_knowledge_save_in_canister(session_key: String, id: String, file_name: String, content: Vec<u8>) -> Result<String, Error> {
-    let _ = ensure_directory_exists(&format!("{}/ai/{}", XFILES_LOCAL_DIR, id));
-    let url_embedding = format!("{}{}/api/gen/embedding", LLM_REQUEST_PROTOCOL, LLM_HTTP_HOST);
-    let url_summary = format!("{}{}/api/gen/summary", LLM_REQUEST_PROTOCOL, LLM_HTTP_HOST);
+/// Saves the document, indexes its passages in the vector canister and returns its summary.
+/// Listeners of the document's session hear the summary and then `done`, or `failed`.
+pub async fn upload_knowledge_save_in_canister(icp: &IcpClient, llm: &LlmClient, progress: &ProgressHub, doc: &KnowledgeDoc, content: Vec<u8>) -> Result<String, Error> {
+    progress.open(&doc.sig);
+    let summary = save_knowledge_in_canister(icp, llm, progress, doc, content).await;
+    progress.finish(&doc.sig, &summary, |_| doc.file_name.clone());
+
+    summary
+}
+async fn save_knowledge_in_canister(icp: &IcpClient, llm: &LlmClient, progress: &ProgressHub, doc: &KnowledgeDoc, content: Vec<u8>) -> Result<String, Error> {
+    let id = doc.owner.clone();
+    let session_key = doc.sig.clone();
 
-    let local_name = file_name;
+    let local_name = doc.file_name.clone();
     let resp: String;
     let summary_file = local_name.clone() + ".sum";
+    let text = String::from_utf8(content.clone()).unwrap_or_default();
 
-    let (exists, data, size) = check_session_file(id.clone(), session_key.clone(), summary_file.clone()).await.unwrap_or_default();
+    let (exists, data, size) = check_session_file(icp, id.clone(), session_key.clone(), summary_file.clone()).await?;
 
     if !exists{
-        let embedding_request = FileGenRequest{ content: String::from_utf8(content.clone()).unwrap_or_default() };
-        let client = reqwest::Client::new();
-
-        if content.len() <= MAX_SAVE_BYTES{
-            save_session_file(id.clone(), session_key.clone(), local_name.clone(), content.clone()).await?;
-
-            let response = client
-                .post(&url_embedding)
-                .json(&json!(embedding_request))
-                .send()
-                .await?;
-
-            let saved_bytes = response.bytes().await?;
-            // let embedding: Vec<f32> = serde_json::from_slice(&saved_bytes)?;
-            // println!("embedding: {:?}", embedding);
-            // match add_embedding(String::from_utf8(content.clone()).unwrap_or_default(), embedding).await{
-            //     Ok(_) => {}
-            //     Err(e) => {
-            //         println!("add_embedding error: {}", e);
-            //     }
-            // };
-            let embedding_file = local_name.clone() + ".embed";
-            save_session_file(id.clone(), session_key.clone(), embedding_file, saved_bytes.to_vec()).await?;
-        }
-        
-        let response = client
-            .post(&url_summary)
-            .json(&json!(embedding_request))
-            .send()
-            .await?;
+        save_session_file(icp, id.clone(), session_key.clone(), local_name.clone(), content.clone()).await?;
+    }
 
-        let summary: String = response.json().await?;
+    let status = ingest_document(icp, llm, doc, &text, &ChunkConfig::from_env()).await?;
+    println!("ingested {}: {}/{} chunks", session_key, status.chunks_done, status.chunks_total);
+
+    if !exists{
+        let summary = llm.summary(text).await?;
         println!("summary: {}", summary);
         resp = summary.clone();
-        save_session_file(id.clone(), session_key.clone(), summary_file, summary.as_bytes().to_vec()).await?;
+        save_session_file(icp, id.clone(), session_key.clone(), summary_file, summary.as_bytes().to_vec()).await?;
     }else{
         println!("summary exists");
         resp = String::from_utf8(data).unwrap_or_default();
     }
+    progress.publish(&session_key, ProgressStage::SummaryReady, resp.clone());
 
     Ok(resp)
 }
-pub async fn upload_image_save_in_canister(session_key: String, id: String, content: Vec<u8>) -> Result<String, Error> {
-    let _ = ensure_directory_exists(&format!("{}/user/uploaded/{}", XFILES_LOCAL_DIR, id));
-    let url = format!("{}{}/api/gen/image/description", LLM_REQUEST_PROTOCOL, LLM_HTTP_HOST);
+/// Stores an uploaded image under its content hash with avatar and thumbnail variants, and describes it.
+pub async fn upload_image_save_in_canister(icp: &IcpClient, llm: &LlmClient, xfiles: &XFilesStore, session_key: String, id: String, content: Vec<u8>) -> Result<UploadedImage, Error> {
+    let image = process_image(&content, &ImageConfig::from_env())?;
+    let stored = image.save(xfiles, &id, &session_key)?;
 
-    let local_name = "upload.png".to_string();
-    let resp = format!("{}/user/uploaded/{}/{}", XFILES_SERVER, id, local_name);
+    let local_name = image.original_name();
     let desc: String;
     let desc_file = local_name.clone() + ".desc";
 
     println!("session_key: {}", session_key);
 
-    let (exists, data, size) = check_session_file(id.clone(), session_key.clone(), desc_file.clone()).await.unwrap_or_default();
+    let (exists, data, size) = check_session_file(icp, id.clone(), session_key.clone(), desc_file.clone()).await?;
     println!("check_session_file: {:?} {:?} {}", exists, data, size);
     if !exists{
         println!("upload image save in canister");
-        if content.len() <= MAX_SAVE_BYTES{
-            save_session_file(id.clone(), session_key.clone(), local_name.clone(), content.clone()).await?;
-        }
-
-        let saved_local_file = format!("{}/user/uploaded/{}/{}", XFILES_LOCAL_DIR, id, local_name);
-        match OpenOptions::new().write(true).create(true).truncate(true).open(&saved_local_file){
-            Ok(mut file) => {
-                file.write_all(&content)?;
-            }
-            Err(e) => {
-                println!("write local file error: {}", e);
-            }
-        }
+        save_session_file(icp, id.clone(), session_key.clone(), local_name, image.original).await?;
 
-        let embedding_request = FileGenRequest{ content: resp.clone() };
-        let client = reqwest::Client::new();
-        let response = client
-            .post(&url)
-            .json(&json!(embedding_request))
-            .send()
-            .await?;
-
-        desc = response.json().await?;
+        desc = llm.image_description(stored.original.clone()).await?;
         println!("image description: {:?}", desc);
-        save_session_file(id.clone(), session_key.clone(), desc_file, desc.as_bytes().to_vec()).await?;
+        save_session_file(icp, id.clone(), session_key.clone(), desc_file, desc.as_bytes().to_vec()).await?;
     }else{
         println!("image description exists");
         desc = String::from_utf8(data).unwrap_or_default();
     }
 
-    Ok(desc)
-}
-pub async fn set_pato_info_generic<T: CandidType>(id: String, data: T, method: &str) -> Result<(), Error> {
-    let agent = init_icp_agent().await?;
-    let effective_canister_id = Principal::from_text(AGENT_BATTERY_CANISTER).unwrap();
-    
-    match agent.update(&effective_canister_id, method)
-        .with_effective_canister_id(effective_canister_id)
-        .with_arg(Encode!(&id, &data)?)
-        .await{
-            Ok(_) => Ok(()),
-            Err(e) => {
-                Err(e.into())
-            }
-        }
+    Ok(UploadedImage { description: desc, image: stored })
 }
-pub async fn set_pato_info(id: String, data: String, method: &str) -> Result<(), Error> {
-    let agent = init_icp_agent().await?;
-    let effective_canister_id = Principal::from_text(AGENT_BATTERY_CANISTER).unwrap();
-    
-    match agent.update(&effective_canister_id, method)
-        .with_effective_canister_id(effective_canister_id)
-        .with_arg(Encode!(&id, &data)?)
-        .await{
-            Ok(_) => Ok(()),
-            Err(e) => {
-                Err(e.into())
-            }
-        }
+/// Downloads a remote file into the xfiles store under `path`, within the store's download limits.
+pub async fn download_into_store(
+    xfiles: &XFilesStore,
+    url: &str,
+    path: &str,
+    owner: &str,
+    session: &str,
+    kind: XFileKind,
+) -> Result<XFileEntry, Error> {
+    let partial = xfiles.partial_file(url, path);
+    download_file(url, &partial.path, xfiles.download_config()).await?;
+    xfiles.put_file(path, owner, session, kind, &partial.path)
 }
-pub async fn get_pato_meta(id: String, method: &str) -> Result<String, Error> {
-    let agent = init_icp_agent().await?;
-    let effective_canister_id = Principal::from_text(AGENT_BATTERY_CANISTER).unwrap();
-    
-    match agent.query(&effective_canister_id, method)
-        .with_effective_canister_id(effective_canister_id)
-        .with_arg(Encode!(&id)?)
-        .await{
-            Ok(result) => Ok(Decode!(result.as_slice(), String).unwrap_or_default()),
-            Err(e) => {
-                Err(e.into())
-            }
-        }
+
+pub fn avatar_prompt(character: &str) -> String {
+    format!("Design an avatar that represents a fictional character or persona for storytelling or role-playing purposes. Provide details about the character's appearance, personality traits, and backstory to create a visually compelling and immersive avatar: {}", character)
 }
-pub async fn submit_tags_with_proxy(tags: Vec<String>, session_key: String, id: String) -> Result<(), Error> {
-    let _ = ensure_directory_exists(&format!("{}/ai/{}", XFILES_LOCAL_DIR, id));
+
+/// Generates the character, avatar and cover of a pato from its tags, skipping the parts that already exist.
+#[allow(clippy::too_many_arguments)]
+pub async fn submit_tags_with_proxy(icp: &IcpClient, llm: &LlmClient, xfiles: &XFilesStore, profiles: &ProfileStore, progress: &ProgressHub, tags: Vec<String>, session_key: String, id: String) -> Result<ImageGenResponse, Error> {
     let character: String;
 
-    set_pato_info(id.clone(), tags.join(","), "set_tags_of").await?;
+    icp.set_tags_of(id.clone(), tags.join(",")).await?;
 
     let local_name = "character.txt".to_string();
 
-    let (exists, data, size) = check_session_file(id.clone(), session_key.clone(), local_name.clone()).await.unwrap_or_default();
+    let (exists, data, size) = check_session_file(icp, id.clone(), session_key.clone(), local_name.clone()).await?;
 
     if !exists{
-        let url = format!("{}{}/api/gen/character", LLM_REQUEST_PROTOCOL, LLM_HTTP_HOST);
+        // Gender and personality only live in the portal's profile store; patos registered elsewhere have neither.
+        let profile = profiles.get(&id)?;
         let tag_request = CharacterGenRequest {
             tags: tags.clone(),
-            name: get_pato_name(id.clone()).await.unwrap_or_default(),
-            gender: "".to_string(),
+            name: get_pato_name(icp, id.clone()).await.unwrap_or_default(),
+            gender: profile.as_ref().map(|p| gender_name(p.gender)).unwrap_or_default().to_string(),
+            personality: profile.map(|p| p.personality).unwrap_or_default(),
         };
-        let client = reqwest::Client::new();
-        let response = client
-            .post(&url)
-            .json(&json!(tag_request))
-            .send()
-            .await?;
-        character = response.json().await?;
-
-        save_session_file(id.clone(), session_key.clone(), local_name.clone(), character.as_bytes().to_vec()).await?;
-        set_pato_info(id.clone(), character.clone(), "set_character_of").await?;
-
-        let saved_local_file = format!("{}/ai/{}/{}", XFILES_LOCAL_DIR, id, local_name);
-        match OpenOptions::new().write(true).create(true).truncate(true).open(&saved_local_file){
-            Ok(mut file) => {
-                file.write_all(character.as_bytes())?;
-            }
-            Err(e) => {
-                println!("open file error: {}", e);
-            }                
-        }
+        character = llm.character(tag_request).await?;
+
+        save_session_file(icp, id.clone(), session_key.clone(), local_name.clone(), character.as_bytes().to_vec()).await?;
+        icp.set_character_of(id.clone(), character.clone()).await?;
+
+        xfiles.put(&format!("ai/{}/{}", id, local_name), &id, &session_key, XFileKind::Character, character.as_bytes())?;
     }else{
         println!("character exists");
         character = String::from_utf8(data).unwrap_or_default();
     }
+    progress.publish(&session_key, ProgressStage::CharacterGenerated, character.clone());
 
-    let url = format!("{}{}/api/gen/avatar", LLM_REQUEST_PROTOCOL, LLM_HTTP_HOST);
-    let avatar_prompt = format!("Design an avatar that represents a fictional character or persona for storytelling or role-playing purposes. Provide details about the character's appearance, personality traits, and backstory to create a visually compelling and immersive avatar: {}", character);
-    let avatar_request = ImageGenRequest {
-        prompt: avatar_prompt,
-    };
     let local_name = "avatar.png".to_string();
+    let avatar_path = format!("ai/{}/{}", id, local_name);
 
-    let (exists, data, size) = check_session_file(id.clone(), session_key.clone(), local_name.clone()).await.unwrap_or_default();
+    let (exists, data, size) = check_session_file(icp, id.clone(), session_key.clone(), local_name.clone()).await?;
 
     if !exists{
         println!("avatar not exists");
-        let client = reqwest::Client::new();
-        let response = client
-            .post(&url)
-            .json(&json!(avatar_request))
-            .send()
-            .await?;
-        let file_url: String = response.json().await?;
+        let file_url = llm.avatar(avatar_prompt(&character)).await?;
 
-        let saved_local_file = format!("{}/ai/{}/{}", XFILES_LOCAL_DIR, id, local_name);
-        println!("image source: {}, saved: {}", file_url, saved_local_file);
-        download_image(&file_url, &saved_local_file).await?;
+        println!("image source: {}, saved: {}", file_url, avatar_path);
+        download_into_store(xfiles, &file_url, &avatar_path, &id, &session_key, XFileKind::Avatar).await?;
 
-        let xfiles_path = format!("{}/ai/{}/{}", XFILES_SERVER, id, local_name);
-        set_pato_info(id.clone(), xfiles_path, "set_avatar_of").await?;
+        icp.set_avatar_of(id.clone(), xfiles.url(&avatar_path)).await?;
 
         // match OpenOptions::new().read(true).open(&saved_local_file){
         //     Ok(mut file) => {
@@ -406,30 +276,21 @@ pub async fn submit_tags_with_proxy(tags: Vec<String>, session_key: String, id:
         // }
     }
 
-    let url = format!("{}{}/api/gen/image", LLM_REQUEST_PROTOCOL, LLM_HTTP_HOST);
-    let avatar_request = ImageGenRequest {
-        prompt: tags.join(","),
-    };
+    progress.publish(&session_key, ProgressStage::AvatarDownloaded, xfiles.url(&avatar_path));
+
     let local_name = "cover.png".to_string();
+    let cover_path = format!("ai/{}/{}", id, local_name);
 
-    let (exists, data, size) = check_session_file(id.clone(), session_key.clone(), local_name.clone()).await.unwrap_or_default();
+    let (exists, data, size) = check_session_file(icp, id.clone(), session_key.clone(), local_name.clone()).await?;
 
     if !exists{
         println!("cover not exists");
-        let client = reqwest::Client::new();
-        let response = client
-            .post(&url)
-            .json(&json!(avatar_request))
-            .send()
-            .await?;
-        let file_url: String = response.json().await?;
+        let file_url = llm.image(tags.join(",")).await?;
 
-        let saved_local_file = format!("{}/ai/{}/{}", XFILES_LOCAL_DIR, id, local_name);
-        println!("image source: {}, saved: {}", file_url, saved_local_file);
-        download_image(&file_url, &saved_local_file).await?;
+        println!("image source: {}, saved: {}", file_url, cover_path);
+        download_into_store(xfiles, &file_url, &cover_path, &id, &session_key, XFileKind::Cover).await?;
 
-        let xfiles_path = format!("{}/ai/{}/{}", XFILES_SERVER, id, local_name);
-        set_pato_info(id.clone(), xfiles_path, "set_cover_of").await?;
+        icp.set_cover_of(id.clone(), xfiles.url(&cover_path)).await?;
 
         // match OpenOptions::new().read(true).open(&saved_local_file){
         //     Ok(mut file) => {
@@ -442,69 +303,144 @@ pub async fn submit_tags_with_proxy(tags: Vec<String>, session_key: String, id:
         //     }
         // }
     }
+    progress.publish(&session_key, ProgressStage::CoverReady, xfiles.url(&cover_path));
 
-    Ok(())
+    Ok(ImageGenResponse {
+        cover: xfiles.url(&cover_path),
+        avatar: xfiles.url(&avatar_path),
+        character,
+    })
 }
 
-pub async fn gen_image_save_in_canister(prompt: String, session_key: String, id: String) -> Result<String, Error> {
-    let url = format!("{}{}/api/gen/image", LLM_REQUEST_PROTOCOL, LLM_HTTP_HOST);
-    let avatar_request = ImageGenRequest {
-        prompt,
+/// Copies an image from the xfiles store into the session assets, as bytes since it is binary.
+async fn save_image_in_canister(icp: &IcpClient, xfiles: &XFilesStore, id: String, session_key: String, path: &str, local_name: String) -> Result<(), Error> {
+    let content = std::fs::read(xfiles.local_path(path))?;
+    save_session_file(icp, id, session_key, local_name, content).await
+}
+/// Generates `num_images` variants of the prompt in the requested style and keeps every one of them
+/// in the xfiles store, the session assets and the session's gallery.
+/// Listeners of the session hear each image and then `done` with the generation id, or `failed`.
+#[allow(clippy::too_many_arguments)]
+pub async fn generate_images(
+    icp: &IcpClient,
+    llm: &LlmClient,
+    xfiles: &XFilesStore,
+    gallery: &GalleryStore,
+    progress: &ProgressHub,
+    id: String,
+    session_key: String,
+    options: ImageGenOptions,
+) -> Result<Vec<GeneratedImage>, Error> {
+    check_owner(icp, &id, &options.token).await?;
+    progress.open(&session_key);
+    let images = generate_image_variants(icp, llm, xfiles, gallery, progress, id, session_key.clone(), options).await;
+    progress.finish(&session_key, &images, |images| images.first().map(|image| image.generation.clone()).unwrap_or_default());
+
+    images
+}
+#[allow(clippy::too_many_arguments)]
+async fn generate_image_variants(
+    icp: &IcpClient,
+    llm: &LlmClient,
+    xfiles: &XFilesStore,
+    gallery: &GalleryStore,
+    progress: &ProgressHub,
+    id: String,
+    session_key: String,
+    options: ImageGenOptions,
+) -> Result<Vec<GeneratedImage>, Error> {
+    if options.prompt.trim().is_empty() {
+        return Err(ImageGenError::EmptyPrompt.into());
+    }
+    if options.num_images == 0 || options.num_images > gallery.max_variants() {
+        return Err(ImageGenError::TooManyVariants { requested: options.num_images, max: gallery.max_variants() }.into());
+    }
+    let preset = match &options.style {
+        Some(style) => Some(style_preset(style).ok_or_else(|| ImageGenError::UnknownStyle(style.clone()))?),
+        None => None,
     };
-    let local_name = "image.png".to_string();
-    let saved_local_file = format!("{}/ai/{}/{}/{}", XFILES_LOCAL_DIR, id, session_key, local_name);
-    let resp = format!("{}/ai/{}/{}/{}", XFILES_SERVER, id, session_key, local_name);
 
-    let (exists, _, _) = check_session_file(id.clone(), session_key.clone(), local_name.clone()).await?;
-    if !exists{
-        let client = reqwest::Client::new();
-        let response = client
-            .post(&url)
-            .json(&json!(avatar_request))
-            .send()
-            .await?;
-        let file_url: String = response.json().await?;
-        download_image(&file_url, &saved_local_file).await?;
-
-        match OpenOptions::new().read(true).open(&saved_local_file){
-            Ok(mut file) => {
-                let mut content = String::new();
-                file.read_to_string(&mut content)?;
-                save_session_file(id.clone(), session_key.clone(), local_name, content.as_bytes().to_vec()).await?;
-            }
-            Err(e) => {
-                println!("open file error: {}", e);
-            }
-        }
+    let mut prompt = options.prompt.trim().to_string();
+    let mut negative_prompt = options.negative_prompt.trim().to_string();
+    if let Some(preset) = preset {
+        prompt = format!("{}, {}", prompt, preset.prompt);
+        negative_prompt = match negative_prompt.is_empty() {
+            true => preset.negative_prompt.to_string(),
+            false => format!("{}, {}", negative_prompt, preset.negative_prompt),
+        };
     }
+    let seed = options.seed.unwrap_or_else(|| uuid::Uuid::new_v4().as_u128() as u32);
 
-    Ok(resp)
-}
-pub async fn comment_topic(topic: String, prompt: String, contributor: String) -> Result<(), Error> {
-    let url = format!("{}{}/api/chat/topic", LLM_REQUEST_PROTOCOL, LLM_HTTP_HOST);
-    let topic_id = compute_md5(&topic);
+    let request = ImagesGenRequest { prompt: prompt.clone(), negative_prompt: negative_prompt.clone(), num_images: options.num_images, seed };
+    let urls = llm.images(request).await?;
+    if urls.is_empty() {
+        return Err(anyhow!("no image generated for {}", options.prompt));
+    }
 
-    let lock_file_path = format!("/tmp/{}{}.lock", topic_id, contributor);
-    if !std::path::Path::new(&lock_file_path).exists() {
-        // println!("do comment {}/{}", topic_id, contributor);
-        let _ = File::create(&lock_file_path)?;
-        let request = TopicCommentRequest {
-            topic,
-            prompt,
+    let generation = uuid::Uuid::new_v4().to_string();
+    let created_at = get_now_secs();
+    let mut images = vec![];
+    for (index, file_url) in urls.iter().take(options.num_images as usize).enumerate() {
+        let local_name = format!("{}_{}.png", generation, index);
+        let path = format!("ai/{}/{}/images/{}", id, session_key, local_name);
+        download_into_store(xfiles, file_url, &path, &id, &session_key, XFileKind::Image).await?;
+        save_image_in_canister(icp, xfiles, id.clone(), session_key.clone(), &path, local_name).await?;
+
+        let image = GeneratedImage {
+            id: uuid::Uuid::new_v4().to_string(),
+            generation: generation.clone(),
+            owner: id.clone(),
+            session: session_key.clone(),
+            index: index as u32,
+            prompt: prompt.clone(),
+            negative_prompt: negative_prompt.clone(),
+            style: preset.map(|p| p.name.to_string()),
+            seed: seed.wrapping_add(index as u32),
+            url: xfiles.url(&path),
+            path,
+            created_at,
         };
+        gallery.save(&image)?;
+        progress.publish(&session_key, ProgressStage::ImageReady, image.url.clone());
+        images.push(image);
+    }
 
-        let client = reqwest::Client::new();
-        let response = client
-            .post(&url)
-            .json(&json!(request))
-            .send()
-            .await?;
-
-        let comment: String = response.json().await?;
+    Ok(images)
+}
+/// Comments on the topic once per contributor, replaying the saved comment for repeated requests.
+/// Returns `None` while another request for the same comment is still running.
+pub async fn comment_topic(
+    icp: &IcpClient,
+    llm: &LlmClient,
+    idempotency: &IdempotencyStore,
+    topic: String,
+    prompt: String,
+    contributor: String,
+) -> Result<Option<String>, Error> {
+    let topic_id = compute_md5(&topic);
+    let key = format!("comment:{}:{}", topic_id, contributor);
 
+    match idempotency.begin(&key, None)? {
+        Claim::Acquired => (),
+        Claim::InProgress(_) => return Ok(None),
+        Claim::Completed(comment) => return Ok(Some(comment.unwrap_or_default())),
+    }
 
-        set_pato_info_generic(topic_id, (comment, contributor), "set_sub_topics_of").await?;
+    let commented = async {
+        let comment = llm.topic_comment(topic, prompt).await?;
+        icp.set_sub_topics_of(topic_id, (comment.clone(), contributor)).await?;
+        Ok::<String, Error>(comment)
     }
+    .await;
 
-    Ok(())
+    match commented {
+        Ok(comment) => {
+            idempotency.complete(&key, &comment)?;
+            Ok(Some(comment))
+        }
+        Err(e) => {
+            idempotency.fail(&key, &e.to_string())?;
+            Err(e)
+        }
+    }
 }
\ No newline at end of file
@@ -3,30 +3,24 @@ use serde::{Deserialize, Serialize};
 
 pub mod ai_town;
 pub mod bsc_proxy;
+pub mod embedding_cache;
+pub mod extract;
+pub mod gallery;
+pub mod idempotency;
+pub mod images;
+pub mod jobs;
+pub mod knowledge;
+pub mod llm_gateway;
 pub mod llm_proxy;
+pub mod profile;
+pub mod progress;
+pub mod xfiles;
 
-#[derive(Deserialize, CandidType)]
-pub struct Knowledge {
-    pub sig: String,
-    pub title: String,
-    pub owner: String,
-    pub summary: String,
-}
-
-#[derive(Deserialize, CandidType, Default)]
-pub struct SharedKnowledgesResponse {
-    pub books: Vec<Knowledge>,
-}
-
-#[derive(Deserialize, CandidType, Default)]
-pub struct HotTopicResponse {
-    pub topics: Vec<String>,
-}
-
-#[derive(Deserialize, CandidType, Default, Debug)]
-pub struct CreateResonse {
-    pub id: String,
-}
+pub use metapower_framework::icp::{
+    BecomeKolRequest, CreateResonse, FollowKolRequest, HotTopicResponse, Knowledge, KolRegistrationRequest,
+    KolRelations, NamePros, NameRequest, NameResponse, PatoInfoResponse, SharedKnowledgesResponse, SimpleResponse,
+    SnIdPaire, SubmitTagsRequest, TokenResponse,
+};
 
 #[derive(Deserialize, CandidType)]
 pub struct LoginRequest {
@@ -39,32 +33,12 @@ pub struct AirdropRequest {
     pub amount: f32,
 }
 
-#[derive(Deserialize, CandidType, Default)]
-pub struct SimpleResponse {
-    pub success: bool,
-    pub message: String,
-}
-
 #[derive(Deserialize, CandidType)]
 pub struct PopulationRegistrationRequest {
     pub id: String,
     pub name: String,
 }
 
-#[derive(Deserialize, CandidType, Default, Serialize)]
-pub struct PatoInfoResponse {
-    pub id: String,
-    pub name: String,
-    pub sn: i64,
-    pub registered_datetime: String,
-    pub balance: f32,
-    pub tags: Vec<String>,
-    pub avatar: String,
-    pub cover: String,
-    pub followers: Vec<(String, String)>,
-    pub followings: Vec<(String, String)>,
-}
-
 #[derive(Deserialize, CandidType, Debug)]
 pub struct PatoOfPro {
     pub id: String,
@@ -72,61 +46,11 @@ pub struct PatoOfPro {
     pub name: String,
 }
 
-#[derive(Deserialize, CandidType)]
-pub struct SnIdPaire {
-    pub id: String,
-    pub sn: String,
-}
-
-#[derive(Deserialize, Serialize, CandidType, Default)]
-pub struct TokenResponse {
-    pub id: String,
-    pub name: String,
-    pub token: String,
-}
-
 #[derive(Deserialize, CandidType, Default)]
 pub struct TopicChatHisResponse {
     pub history: Vec<String>,
 }
 
-#[derive(Deserialize, CandidType)]
-pub struct NamePros {
-    pub id: String,
-    pub name: String,
-    pub pros: Vec<String>,
-}
-
-#[derive(Deserialize, CandidType, Default)]
-pub struct NameResponse {
-    pub name_pros: Vec<NamePros>,
-}
-
-#[derive(Deserialize, CandidType)]
-pub struct NameRequest {
-    pub id: Vec<String>,
-}
-
-#[derive(Deserialize, CandidType)]
-pub struct KolRegistrationRequest {
-    pub id: String,
-    pub key: String,
-}
-
-#[derive(Deserialize, CandidType)]
-pub struct FollowKolRequest {
-    pub id: String,
-    pub follower: String,
-    pub key: String,
-}
-
-#[derive(Deserialize, CandidType, Serialize)]
-pub struct KolRelations {
-    pub id: String,
-    pub name: String,
-    pub follower: Vec<String>,
-}
-
 #[derive(Deserialize, CandidType, Default)]
 pub struct KolListResponse {
     pub relations: Vec<KolRelations>,
@@ -139,19 +63,6 @@ pub struct UserActiveRequest {
     pub action: String,
 }
 
-#[derive(Deserialize, Serialize, CandidType)]
-pub struct SubmitTagsRequest {
-    pub id: String,
-    pub session: String,
-    pub tags: Vec<String>,
-}
-
-#[derive(Deserialize, Serialize, CandidType)]
-pub struct BecomeKolRequest {
-    pub id: String,
-    pub from: String,
-}
-
 #[derive(Deserialize, Serialize, CandidType)]
 pub struct JoinKolRoomRequest {
     pub kol: String,
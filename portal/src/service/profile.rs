@@ -0,0 +1,437 @@
+use std::fmt;
+
+use anyhow::Error;
+use metapower_framework::dao::sqlite::MetapowerSqlite3;
+use metapower_framework::icp::{AgentBatteryCanister, AgentSmithCanister, IcpClient};
+use metapower_framework::{get_now_secs, ApiError, XFILES_LOCAL_DIR};
+use serde::{Deserialize, Serialize};
+
+use super::ai_town::town_register;
+use super::llm_gateway::{LlmClient, LlmGateway};
+use super::llm_proxy::{avatar_prompt, download_into_store, CharacterGenRequest};
+use super::xfiles::{XFileKind, XFilesStore};
+
+const DEFAULT_PROFILE_HISTORY: usize = 20;
+const MAX_NAME_CHARS: usize = 64;
+const MAX_PERSONALITY_CHARS: usize = 500;
+const MAX_TAGS: usize = 20;
+
+const CREATE_PROFILES_SQL: &str = "CREATE TABLE IF NOT EXISTS profiles (
+    key TEXT PRIMARY KEY,
+    data TEXT NOT NULL,
+    updated_at INTEGER NOT NULL
+)";
+const CREATE_VERSIONS_SQL: &str = "CREATE TABLE IF NOT EXISTS profile_versions (
+    id TEXT PRIMARY KEY,
+    key TEXT NOT NULL,
+    asset TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    data TEXT NOT NULL
+)";
+const CREATE_VERSIONS_INDEX_SQL: &str = "CREATE INDEX IF NOT EXISTS profile_versions_key ON profile_versions (key, asset)";
+
+#[derive(Debug, Clone)]
+pub struct ProfileConfig {
+    pub db_file: String,
+    /// Versions kept per asset of a pato, the current one included.
+    pub history: usize,
+}
+
+impl Default for ProfileConfig {
+    fn default() -> Self {
+        ProfileConfig::in_root(XFILES_LOCAL_DIR)
+    }
+}
+
+impl ProfileConfig {
+    /// Defaults with the profile database under the local data `root`.
+    pub fn in_root(root: &str) -> Self {
+        ProfileConfig {
+            db_file: format!("{}/profiles.db", root),
+            history: DEFAULT_PROFILE_HISTORY,
+        }
+    }
+
+    pub fn from_env(root: &str) -> Self {
+        let mut config = ProfileConfig::in_root(root);
+
+        if let Ok(db_file) = std::env::var("PROFILE_DB") {
+            config.db_file = db_file;
+        }
+        if let Some(history) = std::env::var("PROFILE_HISTORY").ok().and_then(|s| s.parse::<usize>().ok()) {
+            config.history = history.max(1);
+        }
+
+        config
+    }
+}
+
+/// `UserInfo.gender` as the app sends it: 1 male, 2 female, anything else unspecified.
+pub fn gender_name(gender: u8) -> &'static str {
+    match gender {
+        1 => "male",
+        2 => "female",
+        _ => "",
+    }
+}
+
+/// What the portal knows about a pato beyond the canister: gender, personality and the current character.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PatoProfile {
+    pub id: String,
+    pub name: String,
+    pub gender: u8,
+    pub personality: String,
+    pub tags: Vec<String>,
+    pub character: String,
+    pub avatar: String,
+    pub cover: String,
+    pub updated_at: u64,
+}
+
+/// Fields an owner changes with `POST /api/pato/profile/{id}`; missing fields stay as they are.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ProfileUpdate {
+    pub token: String,
+    pub name: Option<String>,
+    pub gender: Option<u8>,
+    pub personality: Option<String>,
+    pub tags: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProfileAsset {
+    Character,
+    Avatar,
+    Cover,
+}
+
+impl ProfileAsset {
+    pub fn parse(name: &str) -> Result<Self, ProfileError> {
+        match name {
+            "character" => Ok(ProfileAsset::Character),
+            "avatar" => Ok(ProfileAsset::Avatar),
+            "cover" => Ok(ProfileAsset::Cover),
+            other => Err(ProfileError::UnknownAsset(other.to_string())),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProfileAsset::Character => "character",
+            ProfileAsset::Avatar => "avatar",
+            ProfileAsset::Cover => "cover",
+        }
+    }
+}
+
+/// A character text or an avatar/cover url a pato had at some point; `path` is set for files in the xfiles store.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProfileVersion {
+    pub pato: String,
+    pub asset: ProfileAsset,
+    pub version: u32,
+    pub value: String,
+    pub path: Option<String>,
+    pub created_at: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum ProfileError {
+    NotOwner,
+    Invalid(String),
+    UnknownAsset(String),
+}
+
+impl ApiError for ProfileError {
+    fn status_code(&self) -> u16 {
+        match self {
+            ProfileError::NotOwner => 403,
+            ProfileError::Invalid(_) | ProfileError::UnknownAsset(_) => 400,
+        }
+    }
+
+    fn error_code(&self) -> &'static str {
+        match self {
+            ProfileError::NotOwner => "not_owner",
+            ProfileError::Invalid(_) => "invalid_profile",
+            ProfileError::UnknownAsset(_) => "unknown_asset",
+        }
+    }
+}
+
+impl fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProfileError::NotOwner => write!(f, "the token does not belong to this pato"),
+            ProfileError::Invalid(message) => write!(f, "invalid profile: {}", message),
+            ProfileError::UnknownAsset(asset) => write!(f, "unknown profile asset: {}", asset),
+        }
+    }
+}
+
+impl std::error::Error for ProfileError {}
+
+/// Profiles and the version history of their characters, avatars and covers.
+pub struct ProfileStore {
+    db: MetapowerSqlite3,
+    config: ProfileConfig,
+}
+
+impl ProfileStore {
+    pub fn open(config: ProfileConfig) -> Result<Self, Error> {
+        let db = MetapowerSqlite3::new(config.db_file.clone());
+        db.create_table(CREATE_PROFILES_SQL.to_string())?;
+        db.create_table(CREATE_VERSIONS_SQL.to_string())?;
+        db.create_table(CREATE_VERSIONS_INDEX_SQL.to_string())?;
+
+        Ok(ProfileStore { db, config })
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<PatoProfile>, Error> {
+        // Pato ids come from the url, so they only ever reach SQL as bound parameters.
+        let rows = self.db.query("SELECT data FROM profiles WHERE key = ?1", &[&id], vec!["data"])?;
+
+        Ok(rows.iter().filter_map(|row| row.get("data")).find_map(|data| serde_json::from_str(data).ok()))
+    }
+
+    pub fn save(&self, profile: &mut PatoProfile) -> Result<(), Error> {
+        profile.updated_at = get_now_secs();
+        let data = serde_json::to_string(profile)?;
+        let updated_at = profile.updated_at as i64;
+        self.db.insert_record(
+            "INSERT OR REPLACE INTO profiles (key, data, updated_at) VALUES (?1, ?2, ?3)",
+            &[&profile.id, &data, &updated_at],
+        )?;
+
+        Ok(())
+    }
+
+    /// All versions of a pato, or of one of its assets, oldest first.
+    pub fn versions(&self, id: &str, asset: Option<ProfileAsset>) -> Result<Vec<ProfileVersion>, Error> {
+        let rows = match asset {
+            Some(asset) => self.db.query(
+                "SELECT data FROM profile_versions WHERE key = ?1 AND asset = ?2 ORDER BY asset, version",
+                &[&id, &asset.name()],
+                vec!["data"],
+            )?,
+            None => self.db.query(
+                "SELECT data FROM profile_versions WHERE key = ?1 ORDER BY asset, version",
+                &[&id],
+                vec!["data"],
+            )?,
+        };
+
+        Ok(rows.iter().filter_map(|row| row.get("data")).filter_map(|data| serde_json::from_str(data).ok()).collect())
+    }
+
+    /// The version number the next value of `asset` will get.
+    pub fn next_version(&self, id: &str, asset: ProfileAsset) -> Result<u32, Error> {
+        Ok(self.versions(id, Some(asset))?.last().map(|v| v.version + 1).unwrap_or(1))
+    }
+
+    /// Records a new value of `asset` and drops the versions beyond the configured history.
+    pub fn add_version(
+        &self,
+        id: &str,
+        asset: ProfileAsset,
+        value: String,
+        path: Option<String>,
+    ) -> Result<ProfileVersion, Error> {
+        let version = ProfileVersion {
+            pato: id.to_string(),
+            asset,
+            version: self.next_version(id, asset)?,
+            value,
+            path,
+            created_at: get_now_secs(),
+        };
+        let data = serde_json::to_string(&version)?;
+        let number = version.version as i64;
+        self.db.insert_record(
+            "INSERT INTO profile_versions (id, key, asset, version, data) VALUES (?1, ?2, ?3, ?4, ?5)",
+            &[&uuid::Uuid::new_v4().to_string(), &id, &asset.name(), &number, &data],
+        )?;
+
+        let oldest_kept = number - self.config.history as i64;
+        self.db.execute(
+            "DELETE FROM profile_versions WHERE key = ?1 AND asset = ?2 AND version <= ?3",
+            &[&id, &asset.name(), &oldest_kept],
+        )?;
+
+        Ok(version)
+    }
+}
+
+/// Registers the pato with the canister and keeps the gender and personality the canister has no place for.
+pub async fn register_pato(
+    icp: &IcpClient,
+    profiles: &ProfileStore,
+    name: String,
+    gender: u8,
+    personality: String,
+) -> Result<String, Error> {
+    let id = town_register(icp, name.clone()).await?;
+
+    let mut profile = PatoProfile { id: id.clone(), name, gender, personality, ..Default::default() };
+    profiles.save(&mut profile)?;
+
+    Ok(id)
+}
+
+/// The stored profile with the name, tags, avatar and cover the canister currently has.
+pub async fn get_profile(icp: &IcpClient, profiles: &ProfileStore, id: String) -> Result<PatoProfile, Error> {
+    let info = icp.request_pato_info(id.clone()).await?;
+    let mut profile = profiles.get(&id)?.unwrap_or_else(|| PatoProfile { id: id.clone(), ..Default::default() });
+
+    if !info.name.is_empty() {
+        profile.name = info.name;
+    }
+    profile.tags = info.tags;
+    profile.avatar = info.avatar;
+    profile.cover = info.cover;
+
+    Ok(profile)
+}
+
+pub(crate) async fn check_owner(icp: &IcpClient, id: &str, token: &str) -> Result<(), Error> {
+    if token.is_empty() {
+        return Err(ProfileError::NotOwner.into());
+    }
+    // A canister failure is reported as such, not as a wrong token.
+    let owner = icp.query_pato_by_auth_token(token.to_string()).await?;
+    if owner.id != id {
+        return Err(ProfileError::NotOwner.into());
+    }
+
+    Ok(())
+}
+
+pub async fn update_profile(
+    icp: &IcpClient,
+    profiles: &ProfileStore,
+    id: String,
+    update: ProfileUpdate,
+) -> Result<PatoProfile, Error> {
+    check_owner(icp, &id, &update.token).await?;
+    let mut profile = get_profile(icp, profiles, id.clone()).await?;
+
+    let name = update.name.map(|name| name.trim().to_string());
+    if let Some(name) = &name {
+        if name.is_empty() || name.chars().count() > MAX_NAME_CHARS {
+            return Err(ProfileError::Invalid(format!("name must have 1 to {} characters", MAX_NAME_CHARS)).into());
+        }
+    }
+    if let Some(personality) = update.personality {
+        if personality.chars().count() > MAX_PERSONALITY_CHARS {
+            return Err(
+                ProfileError::Invalid(format!("personality is longer than {} characters", MAX_PERSONALITY_CHARS)).into()
+            );
+        }
+        profile.personality = personality.trim().to_string();
+    }
+    if let Some(gender) = update.gender {
+        profile.gender = gender;
+    }
+    let tags = update.tags.map(|tags| tags.iter().map(|t| t.trim().to_string()).filter(|t| !t.is_empty()).collect::<Vec<String>>());
+    if let Some(tags) = &tags {
+        if tags.is_empty() || tags.len() > MAX_TAGS || tags.iter().any(|t| t.contains(',')) {
+            return Err(ProfileError::Invalid(format!("tags must be 1 to {} words without commas", MAX_TAGS)).into());
+        }
+    }
+
+    // Everything is validated before the canister keeps the name and tags.
+    if let Some(name) = name {
+        icp.set_name_of(id.clone(), name.clone()).await?;
+        profile.name = name;
+    }
+    if let Some(tags) = tags {
+        icp.set_tags_of(id.clone(), tags.join(",")).await?;
+        profile.tags = tags;
+    }
+
+    profiles.save(&mut profile)?;
+
+    Ok(profile)
+}
+
+/// Lists the pato's earlier characters, avatars and covers; only its owner may see them.
+pub async fn profile_history(icp: &IcpClient, profiles: &ProfileStore, id: String, token: String) -> Result<Vec<ProfileVersion>, Error> {
+    check_owner(icp, &id, &token).await?;
+
+    profiles.versions(&id, None)
+}
+
+/// Generates a new character, avatar or cover for the pato from its current profile and records it as a
+/// new version. The value it replaces is recorded first if the history does not have it yet.
+pub async fn regenerate_asset(
+    icp: &IcpClient,
+    llm: &LlmClient,
+    xfiles: &XFilesStore,
+    profiles: &ProfileStore,
+    id: String,
+    asset: ProfileAsset,
+    token: String,
+) -> Result<ProfileVersion, Error> {
+    check_owner(icp, &id, &token).await?;
+    let mut profile = get_profile(icp, profiles, id.clone()).await?;
+    if profile.character.is_empty() {
+        // Patos made by submitting tags only have the character file written by that flow.
+        let character_file = xfiles.local_path(&format!("ai/{}/character.txt", id));
+        profile.character = std::fs::read_to_string(character_file).unwrap_or_default();
+    }
+
+    let current = match asset {
+        ProfileAsset::Character => profile.character.clone(),
+        ProfileAsset::Avatar => profile.avatar.clone(),
+        ProfileAsset::Cover => profile.cover.clone(),
+    };
+    if !current.is_empty() && profiles.versions(&id, Some(asset))?.last().map(|v| &v.value) != Some(&current) {
+        profiles.add_version(&id, asset, current, None)?;
+    }
+
+    let version = profiles.next_version(&id, asset)?;
+    let (value, path) = match asset {
+        ProfileAsset::Character => {
+            let request = CharacterGenRequest {
+                tags: profile.tags.clone(),
+                name: profile.name.clone(),
+                gender: gender_name(profile.gender).to_string(),
+                personality: profile.personality.clone(),
+            };
+            let character = llm.character(request).await?;
+
+            let path = format!("ai/{}/profile/character_{}.txt", id, version);
+            xfiles.put(&path, &id, "", XFileKind::Character, character.as_bytes())?;
+            xfiles.put(&format!("ai/{}/character.txt", id), &id, "", XFileKind::Character, character.as_bytes())?;
+            icp.set_character_of(id.clone(), character.clone()).await?;
+            profile.character = character.clone();
+
+            (character, path)
+        }
+        ProfileAsset::Avatar => {
+            let file_url = llm.avatar(avatar_prompt(&profile.character)).await?;
+
+            let path = format!("ai/{}/profile/avatar_{}.png", id, version);
+            download_into_store(xfiles, &file_url, &path, &id, "", XFileKind::Avatar).await?;
+            icp.set_avatar_of(id.clone(), xfiles.url(&path)).await?;
+            profile.avatar = xfiles.url(&path);
+
+            (xfiles.url(&path), path)
+        }
+        ProfileAsset::Cover => {
+            let file_url = llm.image(profile.tags.join(",")).await?;
+
+            let path = format!("ai/{}/profile/cover_{}.png", id, version);
+            download_into_store(xfiles, &file_url, &path, &id, "", XFileKind::Cover).await?;
+            icp.set_cover_of(id.clone(), xfiles.url(&path)).await?;
+            profile.cover = xfiles.url(&path);
+
+            (xfiles.url(&path), path)
+        }
+    };
+
+    profiles.save(&mut profile)?;
+    profiles.add_version(&id, asset, value, Some(path))
+}
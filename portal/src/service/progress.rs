@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use metapower_framework::get_now_secs;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+const PROGRESS_CHANNEL_CAPACITY: usize = 32;
+const PROGRESS_HISTORY: usize = 32;
+/// Sessions without listeners are dropped this long after their last event.
+const PROGRESS_RETENTION_SECS: u64 = 3600;
+/// Streams send a comment this often when there is no event, so proxies keep idle connections open.
+pub const PROGRESS_KEEP_ALIVE_SECS: u64 = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProgressStage {
+    CharacterGenerated,
+    AvatarDownloaded,
+    CoverReady,
+    SummaryReady,
+    ImageReady,
+    Done,
+    Failed,
+}
+
+impl ProgressStage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProgressStage::CharacterGenerated => "character_generated",
+            ProgressStage::AvatarDownloaded => "avatar_downloaded",
+            ProgressStage::CoverReady => "cover_ready",
+            ProgressStage::SummaryReady => "summary_ready",
+            ProgressStage::ImageReady => "image_ready",
+            ProgressStage::Done => "done",
+            ProgressStage::Failed => "failed",
+        }
+    }
+
+    /// Ends the work of the session; streams close after sending it.
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, ProgressStage::Done | ProgressStage::Failed)
+    }
+}
+
+/// A step of the generation work for a session; `data` holds the generated text, the file url or the error.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProgressEvent {
+    pub session: String,
+    pub stage: ProgressStage,
+    pub data: String,
+    pub at: u64,
+}
+
+impl ProgressEvent {
+    /// Formats the event as a Server-Sent Events message named after its stage.
+    pub fn to_sse(&self) -> String {
+        format!("event: {}\ndata: {}\n\n", self.stage.name(), serde_json::to_string(self).unwrap_or_default())
+    }
+}
+
+pub const KEEP_ALIVE_SSE: &str = ": keep-alive\n\n";
+
+struct SessionChannel {
+    sender: broadcast::Sender<ProgressEvent>,
+    history: Vec<ProgressEvent>,
+    updated_at: u64,
+}
+
+impl SessionChannel {
+    fn new() -> Self {
+        let (sender, _) = broadcast::channel(PROGRESS_CHANNEL_CAPACITY);
+        SessionChannel { sender, history: vec![], updated_at: get_now_secs() }
+    }
+
+    /// The history only holds the current run, so a new run does not replay the end of the previous one.
+    fn finished(&self) -> bool {
+        self.history.last().is_some_and(|event| event.stage.is_terminal())
+    }
+}
+
+/// Fans progress events out to the listeners of each session and keeps the recent ones for late listeners.
+#[derive(Clone, Default)]
+pub struct ProgressHub {
+    sessions: Arc<Mutex<HashMap<String, SessionChannel>>>,
+}
+
+impl ProgressHub {
+    pub fn new() -> Self {
+        ProgressHub::default()
+    }
+
+    /// Makes the session known before its first event, e.g. when a job for it is queued, and starts a new run.
+    pub fn open(&self, session: &str) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let channel = sessions.entry(session.to_string()).or_insert_with(SessionChannel::new);
+        if channel.finished() {
+            channel.history.clear();
+        }
+        channel.updated_at = get_now_secs();
+    }
+
+    pub fn publish(&self, session: &str, stage: ProgressStage, data: String) {
+        let now = get_now_secs();
+        let event = ProgressEvent { session: session.to_string(), stage, data, at: now };
+
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, channel| {
+            channel.sender.receiver_count() > 0 || now.saturating_sub(channel.updated_at) < PROGRESS_RETENTION_SECS
+        });
+
+        let channel = sessions.entry(session.to_string()).or_insert_with(SessionChannel::new);
+        if channel.finished() {
+            channel.history.clear();
+        }
+        if channel.history.len() >= PROGRESS_HISTORY {
+            channel.history.remove(0);
+        }
+        channel.history.push(event.clone());
+        channel.updated_at = now;
+        // Nobody listening is fine, the history still has the event.
+        let _ = channel.sender.send(event);
+    }
+
+    /// Ends the current run of the session with `Done` carrying `data`, or with `Failed` carrying the error.
+    pub fn finish<T>(&self, session: &str, result: &Result<T, anyhow::Error>, data: impl FnOnce(&T) -> String) {
+        match result {
+            Ok(value) => self.publish(session, ProgressStage::Done, data(value)),
+            Err(e) => self.publish(session, ProgressStage::Failed, e.to_string()),
+        }
+    }
+
+    /// Returns the events of the current run so far and a receiver for the ones to come, `None` for unknown sessions.
+    pub fn subscribe(&self, session: &str) -> Option<(Vec<ProgressEvent>, broadcast::Receiver<ProgressEvent>)> {
+        let sessions = self.sessions.lock().unwrap();
+        let channel = sessions.get(session)?;
+
+        Some((channel.history.clone(), channel.sender.subscribe()))
+    }
+}
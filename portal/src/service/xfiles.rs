@@ -0,0 +1,424 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Error;
+use metapower_framework::dao::crawler::DownloadConfig;
+use metapower_framework::dao::sqlite::{MetapowerSqlite3, ToSql};
+use metapower_framework::{get_now_secs, ApiError, XFILES_LOCAL_DIR, XFILES_SERVER};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+const DEFAULT_XFILES_GC_GRACE_SECS: u64 = 3600;
+
+const CREATE_TABLE_SQL: &str = "CREATE TABLE IF NOT EXISTS xfiles (
+    key TEXT PRIMARY KEY,
+    hash TEXT NOT NULL,
+    owner TEXT NOT NULL,
+    session TEXT NOT NULL,
+    data TEXT NOT NULL,
+    updated_at INTEGER NOT NULL
+)";
+
+#[derive(Debug, Clone)]
+pub struct XFilesConfig {
+    /// Directory the xfiles web server serves; the index lives in `xfiles.db` inside it.
+    pub root: String,
+    pub server: String,
+    /// Unreferenced objects and temp files younger than this are left to writes still in flight.
+    pub gc_grace: Duration,
+    /// Limits for files fetched into the store from remote urls.
+    pub download: DownloadConfig,
+}
+
+/// The directory all of the portal's local data lives under: `XFILES_LOCAL_DIR`, or the built-in default.
+/// Read once at startup and handed to every store's config, so no store ends up under a different root.
+pub fn local_root() -> String {
+    std::env::var("XFILES_LOCAL_DIR").unwrap_or_else(|_| XFILES_LOCAL_DIR.to_string())
+}
+
+impl Default for XFilesConfig {
+    fn default() -> Self {
+        XFilesConfig::in_root(XFILES_LOCAL_DIR)
+    }
+}
+
+impl XFilesConfig {
+    pub fn in_root(root: &str) -> Self {
+        XFilesConfig {
+            root: root.to_string(),
+            server: XFILES_SERVER.to_string(),
+            gc_grace: Duration::from_secs(DEFAULT_XFILES_GC_GRACE_SECS),
+            download: DownloadConfig::default(),
+        }
+    }
+
+    pub fn from_env(root: &str) -> Self {
+        let mut config = XFilesConfig::in_root(root);
+
+        if let Ok(server) = std::env::var("XFILES_SERVER") {
+            config.server = server;
+        }
+        if let Some(secs) = std::env::var("XFILES_GC_GRACE_SECS").ok().and_then(|s| s.parse::<u64>().ok()) {
+            config.gc_grace = Duration::from_secs(secs);
+        }
+        config.download = DownloadConfig::from_env();
+
+        config
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum XFileKind {
+    Character,
+    Avatar,
+    Cover,
+    Image,
+    Upload,
+    Thumbnail,
+    Download,
+    Document,
+}
+
+/// An index entry: the public path of a file and the content it should hold.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct XFileEntry {
+    pub path: String,
+    pub hash: String,
+    pub owner: String,
+    pub session: String,
+    pub kind: XFileKind,
+    pub size: u64,
+    pub created_at: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum XFilesError {
+    InvalidPath(String),
+}
+
+impl ApiError for XFilesError {
+    fn status_code(&self) -> u16 {
+        400
+    }
+
+    fn error_code(&self) -> &'static str {
+        "invalid_path"
+    }
+}
+
+impl fmt::Display for XFilesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            XFilesError::InvalidPath(path) => write!(f, "invalid xfiles path: {}", path),
+        }
+    }
+}
+
+impl std::error::Error for XFilesError {}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub missing: Vec<String>,
+    pub corrupt: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.corrupt.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GcReport {
+    pub removed_entries: usize,
+    pub removed_objects: usize,
+    pub removed_temp_files: usize,
+    pub freed_bytes: u64,
+}
+
+/// Content-addressed storage under the xfiles root. Each blob is kept once in `objects/` and hard linked to
+/// its public path, and an index records who owns each path. Files outside the index are never touched.
+pub struct XFilesStore {
+    db: MetapowerSqlite3,
+    config: XFilesConfig,
+    /// Partial downloads being written right now.
+    partials: Mutex<HashSet<PathBuf>>,
+}
+
+/// A partial download file reserved for one writer until dropped.
+pub struct PartialFile<'a> {
+    store: &'a XFilesStore,
+    pub path: PathBuf,
+}
+
+impl Drop for PartialFile<'_> {
+    fn drop(&mut self) {
+        self.store.partials.lock().unwrap().remove(&self.path);
+    }
+}
+
+impl XFilesStore {
+    pub fn open(config: XFilesConfig) -> Result<Self, Error> {
+        let root = Path::new(&config.root);
+        fs::create_dir_all(root.join("objects"))?;
+        fs::create_dir_all(root.join("tmp"))?;
+
+        let db = MetapowerSqlite3::new(root.join("xfiles.db").to_string_lossy().to_string());
+        db.create_table(CREATE_TABLE_SQL.to_string())?;
+
+        Ok(XFilesStore { db, config, partials: Mutex::new(HashSet::new()) })
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.config.server, path)
+    }
+
+    pub fn local_path(&self, path: &str) -> PathBuf {
+        Path::new(&self.config.root).join(path)
+    }
+
+    /// A fresh file name on the store's filesystem, for writers that need a path such as downloads.
+    pub fn temp_path(&self) -> PathBuf {
+        Path::new(&self.config.root).join("tmp").join(uuid::Uuid::new_v4().to_string())
+    }
+
+    /// The file a download of `source` into `path` is written to. An interrupted download leaves it behind
+    /// so the next attempt resumes it; while another download holds it, a fresh temp file is used instead.
+    pub fn partial_file(&self, source: &str, path: &str) -> PartialFile<'_> {
+        let name = format!("{}.part", XFilesStore::key(&format!("{}\n{}", source, path)));
+        let mut partial = Path::new(&self.config.root).join("tmp").join(name);
+
+        let mut partials = self.partials.lock().unwrap();
+        if !partials.insert(partial.clone()) {
+            partial = self.temp_path();
+            partials.insert(partial.clone());
+        }
+
+        PartialFile { store: self, path: partial }
+    }
+
+    pub fn download_config(&self) -> &DownloadConfig {
+        &self.config.download
+    }
+
+    fn object_path(&self, hash: &str) -> PathBuf {
+        Path::new(&self.config.root).join("objects").join(&hash[..2]).join(hash)
+    }
+
+    /// Public paths are relative and may not climb out of the root or into the store's own directories.
+    fn check_path(path: &str) -> Result<(), XFilesError> {
+        let relative = Path::new(path);
+        let plain = relative.components().all(|c| matches!(c, Component::Normal(_)));
+        let reserved = matches!(
+            relative.components().next(),
+            Some(Component::Normal(first)) if first == "objects" || first == "tmp" || first == "xfiles.db"
+        );
+        if path.is_empty() || !plain || reserved {
+            return Err(XFilesError::InvalidPath(path.to_string()));
+        }
+
+        Ok(())
+    }
+
+    fn key(path: &str) -> String {
+        let mut hasher = Sha1::new();
+        hasher.update(path.as_bytes());
+        format!("{:x}", hasher.finalize())
+    }
+
+    pub fn put(&self, path: &str, owner: &str, session: &str, kind: XFileKind, data: &[u8]) -> Result<XFileEntry, Error> {
+        XFilesStore::check_path(path)?;
+
+        let mut hasher = Sha1::new();
+        hasher.update(data);
+        let hash = format!("{:x}", hasher.finalize());
+
+        let object = self.object_path(&hash);
+        if !object.exists() {
+            let temp = self.temp_path();
+            let mut file = File::create(&temp)?;
+            file.write_all(data)?;
+            file.sync_all()?;
+            self.move_into(&temp, &object)?;
+        }
+
+        self.link(path, owner, session, kind, &hash, data.len() as u64)
+    }
+
+    /// Moves a finished temp file, e.g. a download from `temp_path`, into the store.
+    pub fn put_file(&self, path: &str, owner: &str, session: &str, kind: XFileKind, temp: &Path) -> Result<XFileEntry, Error> {
+        if let Err(e) = XFilesStore::check_path(path) {
+            let _ = fs::remove_file(temp);
+            return Err(e.into());
+        }
+
+        let (hash, size) = hash_file(temp)?;
+        let object = self.object_path(&hash);
+        if object.exists() {
+            fs::remove_file(temp)?;
+        } else {
+            self.move_into(temp, &object)?;
+        }
+
+        self.link(path, owner, session, kind, &hash, size)
+    }
+
+    pub fn get(&self, path: &str) -> Result<Option<XFileEntry>, Error> {
+        if XFilesStore::check_path(path).is_err() {
+            return Ok(None);
+        }
+        Ok(self.select("key = ?1", &[&XFilesStore::key(path)])?.pop())
+    }
+
+    /// True when the path is indexed and its file is still on disk.
+    pub fn exists(&self, path: &str) -> Result<bool, Error> {
+        Ok(self.get(path)?.is_some() && self.local_path(path).exists())
+    }
+
+    /// Re-hashes every indexed file and reports the ones that are gone or no longer match their hash.
+    pub fn verify(&self) -> Result<VerifyReport, Error> {
+        let mut report = VerifyReport::default();
+
+        for entry in self.select("1 = 1", &[])? {
+            report.checked += 1;
+            match hash_file(&self.local_path(&entry.path)) {
+                Ok((hash, _)) if hash == entry.hash => (),
+                Ok(_) => report.corrupt.push(entry.path),
+                Err(_) => report.missing.push(entry.path),
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Drops index entries whose file was deleted, then objects no entry references and stale temp files.
+    pub fn gc(&self) -> Result<GcReport, Error> {
+        let mut report = GcReport::default();
+        let mut referenced = HashSet::new();
+
+        for entry in self.select("1 = 1", &[])? {
+            if self.local_path(&entry.path).exists() {
+                referenced.insert(entry.hash);
+            } else {
+                self.db.execute("DELETE FROM xfiles WHERE key = ?1", &[&XFilesStore::key(&entry.path)])?;
+                report.removed_entries += 1;
+            }
+        }
+
+        let root = Path::new(&self.config.root);
+        for shard in fs::read_dir(root.join("objects"))? {
+            let shard = shard?.path();
+            if !shard.is_dir() {
+                continue;
+            }
+            for object in fs::read_dir(&shard)? {
+                let object = object?.path();
+                let name = object.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+                if referenced.contains(&name) || !self.is_stale(&object) {
+                    continue;
+                }
+                report.freed_bytes += fs::metadata(&object).map(|m| m.len()).unwrap_or_default();
+                fs::remove_file(&object)?;
+                report.removed_objects += 1;
+            }
+        }
+
+        for temp in fs::read_dir(root.join("tmp"))? {
+            let temp = temp?.path();
+            if self.is_stale(&temp) {
+                report.freed_bytes += fs::metadata(&temp).map(|m| m.len()).unwrap_or_default();
+                fs::remove_file(&temp)?;
+                report.removed_temp_files += 1;
+            }
+        }
+
+        Ok(report)
+    }
+
+    fn is_stale(&self, file: &Path) -> bool {
+        fs::metadata(file)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .map(|age| age >= self.config.gc_grace)
+            .unwrap_or(false)
+    }
+
+    fn move_into(&self, temp: &Path, object: &Path) -> Result<(), Error> {
+        if let Some(shard) = object.parent() {
+            fs::create_dir_all(shard)?;
+        }
+        fs::rename(temp, object)?;
+
+        Ok(())
+    }
+
+    /// Points the public path at the object through a temp link renamed into place, so readers never see a
+    /// partial file, and records it in the index.
+    fn link(&self, path: &str, owner: &str, session: &str, kind: XFileKind, hash: &str, size: u64) -> Result<XFileEntry, Error> {
+        let local = self.local_path(path);
+        if let Some(dir) = local.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let temp = self.temp_path();
+        if fs::hard_link(self.object_path(hash), &temp).is_err() {
+            // Filesystems without hard links get a copy.
+            fs::copy(self.object_path(hash), &temp)?;
+        }
+        fs::rename(&temp, &local)?;
+
+        let entry = XFileEntry {
+            path: path.to_string(),
+            hash: hash.to_string(),
+            owner: owner.to_string(),
+            session: session.to_string(),
+            kind,
+            size,
+            created_at: get_now_secs(),
+        };
+        let data = serde_json::to_string(&entry)?;
+        let updated_at = entry.created_at as i64;
+        self.db.insert_record(
+            "INSERT OR REPLACE INTO xfiles (key, hash, owner, session, data, updated_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            &[&XFilesStore::key(path), &entry.hash, &entry.owner, &entry.session, &data, &updated_at],
+        )?;
+
+        Ok(entry)
+    }
+
+    fn select(&self, condition: &str, parameters: &[&dyn ToSql]) -> Result<Vec<XFileEntry>, Error> {
+        let sql = format!("SELECT data FROM xfiles WHERE {} ORDER BY updated_at", condition);
+        let rows = self.db.query(&sql, parameters, vec!["data"])?;
+
+        Ok(rows
+            .iter()
+            .filter_map(|row| row.get("data"))
+            .filter_map(|data| serde_json::from_str::<XFileEntry>(data).ok())
+            .collect())
+    }
+}
+
+fn hash_file(file: &Path) -> Result<(String, u64), Error> {
+    let mut file = File::open(file)?;
+    let mut hasher = Sha1::new();
+    let mut buffer = [0u8; 64 * 1024];
+    let mut size = 0u64;
+
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+
+    Ok((format!("{:x}", hasher.finalize()), size))
+}
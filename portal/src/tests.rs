@@ -0,0 +1,1408 @@
+use std::io::{Cursor, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::{http::StatusCode, test, web, App};
+use metapower_framework::dao::crawler::{download_file, is_public_ip, safe_file_name, validator_path, DownloadConfig, DownloadError};
+use metapower_framework::icp::{
+    CanisterError, CanisterMonitor, CanisterReport, ChunkMeta, IcpClient, MockCanisters, MonitorConfig, NaisVectorCanister,
+    ScoredChunk, VectorChunk, VectorFilter,
+};
+use metapower_framework::{ApiError, DataResponse, XFILES_SERVER};
+use serde_json::json;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use crate::config_app;
+use crate::service::embedding_cache::EmbeddingCache;
+use crate::service::extract::{extract_text, DocFormat};
+use crate::service::images::{process_image, ImageConfig, ImageError, ImageFormat};
+use crate::service::gallery::{GalleryConfig, GalleryStore, GeneratedImage};
+use crate::service::idempotency::{Claim, IdempotencyConfig, IdempotencyStore};
+use crate::service::jobs::{Job, JobConfig, JobQueue, JobState};
+use crate::service::knowledge::{ingest_document, search_knowledge, ChunkConfig, KnowledgeDoc, SearchOptions};
+use crate::service::llm_gateway::{FakeLlmGateway, ImagesGenRequest, LlmBackendKind, LlmClient, LlmConfig};
+use crate::service::llm_proxy::{CharacterGenRequest, UploadedImage};
+use crate::service::profile::{PatoProfile, ProfileAsset, ProfileConfig, ProfileStore, ProfileVersion};
+use crate::service::progress::ProgressHub;
+use crate::service::xfiles::{XFileKind, XFilesConfig, XFilesError, XFilesStore};
+use crate::service::PatoInfoResponse;
+
+const BOUNDARY: &str = "metapower-test-boundary";
+
+fn multipart_body(file_name: &str, content: &[u8], message: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(
+        format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+            BOUNDARY, file_name
+        )
+        .as_bytes(),
+    );
+    body.extend_from_slice(content);
+    body.extend_from_slice(
+        format!(
+            "\r\n--{}\r\nContent-Disposition: form-data; name=\"message\"\r\n\r\n{}\r\n--{}--\r\n",
+            BOUNDARY, message, BOUNDARY
+        )
+        .as_bytes(),
+    );
+    body
+}
+
+fn job_config() -> JobConfig {
+    let db = std::env::temp_dir().join(format!("jobs-{}.db", uuid::Uuid::new_v4()));
+    JobConfig {
+        workers: 2,
+        max_attempts: 3,
+        retry_delay: Duration::from_millis(10),
+        db_file: db.to_string_lossy().to_string(),
+    }
+}
+
+fn xfiles_config() -> XFilesConfig {
+    let root = std::env::temp_dir().join(format!("xfiles-{}", uuid::Uuid::new_v4()));
+    XFilesConfig { root: root.to_string_lossy().to_string(), ..XFilesConfig::default() }
+}
+
+/// Like the portal's default, tests keep passage vectors in an embedding cache so searches can score them.
+fn embedding_cache() -> EmbeddingCache {
+    let db = std::env::temp_dir().join(format!("embedding-cache-{}.db", uuid::Uuid::new_v4()));
+    EmbeddingCache::open(&db.to_string_lossy(), "fake").unwrap()
+}
+
+fn gallery_config() -> GalleryConfig {
+    let db = std::env::temp_dir().join(format!("gallery-{}.db", uuid::Uuid::new_v4()));
+    GalleryConfig { db_file: db.to_string_lossy().to_string(), ..GalleryConfig::default() }
+}
+
+fn profile_config() -> ProfileConfig {
+    let db = std::env::temp_dir().join(format!("profiles-{}.db", uuid::Uuid::new_v4()));
+    ProfileConfig { db_file: db.to_string_lossy().to_string(), history: 3 }
+}
+
+fn idempotency_config() -> IdempotencyConfig {
+    let db = std::env::temp_dir().join(format!("idempotency-{}.db", uuid::Uuid::new_v4()));
+    IdempotencyConfig { db_file: db.to_string_lossy().to_string(), ..IdempotencyConfig::default() }
+}
+
+macro_rules! portal_app {
+    ($mock:expr) => {
+        portal_app!($mock, Arc::new(FakeLlmGateway::new()))
+    };
+    ($mock:expr, $llm:expr) => {
+        portal_app!($mock, $llm, xfiles_config())
+    };
+    ($mock:expr, $llm:expr, $xfiles:expr) => {{
+        let icp = IcpClient::with_mock($mock.clone());
+        let llm = LlmClient::with_fake($llm.clone()).with_embedding_cache(embedding_cache());
+        let idempotency = Arc::new(IdempotencyStore::open(idempotency_config()).unwrap());
+        let progress = ProgressHub::new();
+        let xfiles = Arc::new(XFilesStore::open($xfiles).unwrap());
+        let gallery = Arc::new(GalleryStore::open(gallery_config()).unwrap());
+        let profiles = Arc::new(ProfileStore::open(profile_config()).unwrap());
+        let jobs =
+            JobQueue::start(icp.clone(), llm.clone(), job_config(), idempotency.clone(), progress.clone(), xfiles.clone(), profiles.clone())
+                .unwrap();
+        test::init_service(
+            App::new()
+                .app_data(web::Data::new(icp.clone()))
+                .app_data(web::Data::new(llm))
+                .app_data(web::Data::new(jobs))
+                .app_data(web::Data::from(idempotency))
+                .app_data(web::Data::new(progress))
+                .app_data(web::Data::from(xfiles))
+                .app_data(web::Data::from(gallery))
+                .app_data(web::Data::from(profiles))
+                .app_data(web::Data::new(CanisterMonitor::new(icp, MonitorConfig::default())))
+                .configure(config_app),
+        )
+        .await
+    }};
+}
+
+macro_rules! register {
+    ($app:expr, $name:expr) => {{
+        let req = test::TestRequest::post()
+            .uri("/api/register")
+            .set_json(json!({"name": $name, "gender": 1, "personality": "curious"}))
+            .to_request();
+        let resp: DataResponse = test::call_and_read_body_json(&$app, req).await;
+        assert_eq!(resp.code, "200");
+        assert!(!resp.content.is_empty());
+        resp.content
+    }};
+}
+
+#[actix_web::test]
+async fn register_creates_pato() {
+    let mock = Arc::new(MockCanisters::new());
+    let app = portal_app!(mock);
+
+    let id = register!(app, "alice");
+
+    let req = test::TestRequest::get().uri(&format!("/api/pato/info/{}", id)).to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.code, "200");
+    let info: PatoInfoResponse = serde_json::from_str(&resp.content).unwrap();
+    assert_eq!(info.name, "alice");
+}
+
+#[actix_web::test]
+async fn unknown_pato_info_is_an_error() {
+    let mock = Arc::new(MockCanisters::new());
+    let app = portal_app!(mock);
+
+    let req = test::TestRequest::get().uri("/api/pato/info/nobody").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let resp: DataResponse = test::read_body_json(resp).await;
+    assert_eq!(resp.code, "422");
+    assert_eq!(resp.error.as_deref(), Some("canister_reject"));
+}
+
+#[actix_web::test]
+async fn unreachable_canister_opens_the_breaker() {
+    let mock = Arc::new(MockCanisters::new());
+    let app = portal_app!(mock);
+    let id = register!(app, "carol");
+
+    for _ in 0..5 {
+        mock.fail_next("set_topics_of", CanisterError::Unreachable("connection refused".to_string()));
+    }
+    for _ in 0..5 {
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/pato/submit/topic/{}", id))
+            .set_json(json!(["rust", "why rust"]))
+            .to_request();
+        let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.error.as_deref(), Some("canister_unreachable"));
+    }
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/api/pato/submit/topic/{}", id))
+        .set_json(json!(["rust", "why rust"]))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let resp: DataResponse = test::read_body_json(resp).await;
+    assert_eq!(resp.error.as_deref(), Some("canister_circuit_open"));
+
+    let req = test::TestRequest::get().uri("/api/admin/icp/metrics").to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    let metrics: serde_json::Value = serde_json::from_str(&resp.content).unwrap();
+    let battery = &metrics[metapower_framework::icp::AGENT_BATTERY_CANISTER];
+    assert_eq!(battery["breaker"], "Open");
+    assert_eq!(battery["short_circuited"], 1);
+}
+
+#[test]
+fn network_profile_selects_gateway_root_key_and_canister_ids() {
+    use metapower_framework::icp::{CanisterIds, IcpClientConfig, NetworkProfile, ENDPOINT_URL};
+
+    assert_eq!(NetworkProfile::parse("local"), NetworkProfile::Local);
+    assert_eq!(NetworkProfile::parse(" TEST "), NetworkProfile::Test);
+    assert_eq!(NetworkProfile::parse(""), NetworkProfile::Mainnet);
+    assert_eq!(NetworkProfile::parse("staging"), NetworkProfile::Mainnet);
+
+    let mainnet = IcpClientConfig::for_network(NetworkProfile::Mainnet);
+    assert_eq!(mainnet.gateway, "https://ic0.app/");
+    assert!(!mainnet.fetch_root_key);
+    let local = IcpClientConfig::for_network(NetworkProfile::Local);
+    assert_eq!(local.gateway, ENDPOINT_URL);
+    assert!(local.fetch_root_key);
+    assert!(IcpClientConfig::for_network(NetworkProfile::Test).fetch_root_key);
+
+    let vars = std::collections::HashMap::from([
+        ("AGENT_SMITH_CANISTER_ID", "bkyz2-fmaaa-aaaaa-qaaaq-cai"),
+        ("CANISTER_ID_AGENT", "be2us-64aaa-aaaaa-qaabq-cai"),
+        ("CANISTER_ID_MATRIX", "br5f7-7uaaa-aaaaa-qaaca-cai"),
+    ]);
+    // A local run missing an id must not fall back to the mainnet canisters.
+    let lookup = |var: &str| vars.get(var).map(|id| id.to_string());
+    let error = CanisterIds::from_lookup(NetworkProfile::Local, lookup).unwrap_err();
+    assert!(error.to_string().contains("AGENT_BATTERY_CANISTER_ID"));
+    let mainnet = CanisterIds::from_lookup(NetworkProfile::Mainnet, |_| None).unwrap();
+    assert_eq!(mainnet.agent_battery, metapower_framework::icp::AGENT_BATTERY_CANISTER);
+    assert_eq!(mainnet.nais_vector, metapower_framework::icp::NAIS_VECTOR_CANISTER);
+
+    let mut vars = vars;
+    vars.insert("CANISTER_ID_BATTERY", "bw4dl-smaaa-aaaaa-qaacq-cai");
+    vars.insert("NAIS_VECTOR_CANISTER_ID", "b77ix-eeaaa-aaaaa-qaada-cai");
+    let ids = CanisterIds::from_lookup(NetworkProfile::Local, |var| vars.get(var).map(|id| id.to_string())).unwrap();
+    assert_eq!(ids.agent_smith, "bkyz2-fmaaa-aaaaa-qaaaq-cai");
+    assert_eq!(ids.nais_matrix, "br5f7-7uaaa-aaaaa-qaaca-cai");
+    assert_eq!(ids.agent_battery, "bw4dl-smaaa-aaaaa-qaacq-cai");
+    assert_eq!(ids.nais_vector, "b77ix-eeaaa-aaaaa-qaada-cai");
+}
+
+#[actix_web::test]
+async fn only_methods_declared_as_query_are_sent_as_queries() {
+    use metapower_framework::icp::{AgentSmithCanister, AGENT_SMITH_CANISTER};
+
+    let icp = IcpClient::with_mock(Arc::new(MockCanisters::new()));
+    assert!(icp.config().is_query("request_pato_info"));
+    assert!(!icp.config().is_query("request_pato_by_name"));
+    assert!(!icp.config().is_query("refresh_battery_auth"));
+
+    let _ = icp.request_pato_info("nobody".to_string()).await;
+    let _ = icp.request_pato_by_name("nobody".to_string()).await;
+    let smith = &icp.metrics()[AGENT_SMITH_CANISTER];
+    assert_eq!(smith.calls, 2);
+    assert_eq!(smith.queries, 1);
+}
+
+#[actix_web::test]
+async fn predefined_tags_come_from_smith() {
+    let mock = Arc::new(MockCanisters::new());
+    mock.set_predefined_tags("[\"music\",\"travel\"]");
+    let app = portal_app!(mock);
+
+    let req = test::TestRequest::get().uri("/api/pato/tags").to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.content, "[\"music\",\"travel\"]");
+}
+
+#[actix_web::test]
+async fn topics_and_followers_round_trip() {
+    let mock = Arc::new(MockCanisters::new());
+    let app = portal_app!(mock);
+    let kol = register!(app, "kol");
+    let fan = register!(app, "fan");
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/api/pato/submit/topic/{}", kol))
+        .set_json(json!(["rust", "why rust"]))
+        .to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.code, "200");
+
+    let req = test::TestRequest::get().uri(&format!("/api/pato/topics/{}", kol)).to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.content, "[[\"rust\",\"why rust\"]]");
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/api/kol/follow/kol/{}/{}/web", fan, kol))
+        .to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.code, "200");
+
+    let pato = mock.pato(&kol).unwrap();
+    assert_eq!(pato.followers, vec![(fan.clone(), "fan".to_string())]);
+    let pato = mock.pato(&fan).unwrap();
+    assert_eq!(pato.followings, vec![(kol, "kol".to_string())]);
+}
+
+#[actix_web::test]
+async fn register_submit_tags_upload_knowledge_and_query_summary() {
+    let mock = Arc::new(MockCanisters::new());
+    let llm = Arc::new(FakeLlmGateway::new());
+    llm.set_summary("a travel book");
+    let app = portal_app!(mock, llm);
+
+    let id = register!(app, "bob");
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/api/pato/submit/tags/{}/session-1", id))
+        .set_json(json!(["music", "travel"]))
+        .to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.code, "200");
+    assert!(resp.content.ends_with(&format!("/ai/{}/avatar.png", id)));
+    assert_eq!(mock.pato(&id).unwrap().tags, vec!["music".to_string(), "travel".to_string()]);
+
+    let content = b"A short book about travelling with music.".to_vec();
+    let sig = format!("{:x}", Sha1::digest(&content));
+    let req = test::TestRequest::post()
+        .uri("/api/upload/knowledge")
+        .insert_header(("content-type", format!("multipart/form-data; boundary={}", BOUNDARY)))
+        .set_payload(multipart_body("book.txt", &content, &id))
+        .to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.code, "200");
+    assert_eq!(resp.content, "a travel book");
+    assert_eq!(llm.calls(), vec!["embedding".to_string(), "summary".to_string()]);
+    assert_eq!(mock.session_asset(&id, &sig, "content.txt").unwrap(), content);
+
+    // The document's session has a run of its own that ends once the summary is ready.
+    let req = test::TestRequest::get().uri(&format!("/api/progress/{}", sig)).to_request();
+    let events = tokio::time::timeout(Duration::from_secs(5), test::read_body(test::call_service(&app, req).await)).await.expect("stream did not end");
+    let events = std::str::from_utf8(&events).unwrap();
+    assert!(events.starts_with("event: summary_ready\ndata: "));
+    assert!(events.trim_end().rsplit("\n\n").next().unwrap().starts_with("event: done"));
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/api/knowledge/summary/{}/{}?file_name=content.txt.sum", id, sig))
+        .to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.code, "200");
+    assert_eq!(resp.content, "a travel book");
+}
+
+#[actix_web::test]
+async fn uploaded_book_is_chunked_indexed_and_queryable() {
+    let mock = Arc::new(MockCanisters::new());
+    let app = portal_app!(mock);
+
+    let id = register!(app, "erin");
+
+    let content = format!(
+        "{}{}",
+        "Snowy mountains rise above quiet valleys. ".repeat(30),
+        "Sailing boats cross the open ocean at dawn. ".repeat(30)
+    );
+    let sig = format!("{:x}", Sha1::digest(content.as_bytes()));
+    let upload = || {
+        test::TestRequest::post()
+            .uri("/api/upload/knowledge")
+            .insert_header(("content-type", format!("multipart/form-data; boundary={}", BOUNDARY)))
+            .set_payload(multipart_body("voyages.txt", content.as_bytes(), &id))
+            .to_request()
+    };
+
+    mock.fail_next("add", CanisterError::reject("vector store full".to_string()));
+    let resp: DataResponse = test::call_and_read_body_json(&app, upload()).await;
+    assert_eq!(resp.error.as_deref(), Some("canister_reject"));
+
+    let status_uri = format!("/api/knowledge/status/{}/{}/content.txt", id, sig);
+    let req = test::TestRequest::get().uri(&status_uri).to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    let status: serde_json::Value = serde_json::from_str(&resp.content).unwrap();
+    assert_eq!(status["state"], "Failed");
+    assert_eq!(status["chunks_done"], 0);
+
+    let resp: DataResponse = test::call_and_read_body_json(&app, upload()).await;
+    assert_eq!(resp.code, "200");
+
+    let req = test::TestRequest::get().uri(&status_uri).to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    let status: serde_json::Value = serde_json::from_str(&resp.content).unwrap();
+    assert_eq!(status["state"], "Indexed");
+    assert_eq!(status["title"], "voyages.txt");
+    assert_eq!(status["chunks_total"], 3);
+    assert_eq!(status["chunks_done"], 3);
+    assert_eq!(mock.documents().len(), 3);
+
+    let req = test::TestRequest::post()
+        .uri("/api/knowledge/query")
+        .set_json(json!({"input": "sailing boats on the ocean", "k": 1}))
+        .to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.code, "200");
+    let results: Vec<ScoredChunk> = serde_json::from_str(&resp.content).unwrap();
+    assert_eq!(results.len(), 1);
+    assert!(results[0].text.contains("ocean at dawn"));
+    assert!(!results[0].text.contains("mountains"));
+    assert_eq!(results[0].meta.as_ref().unwrap().title, "voyages.txt");
+}
+
+#[actix_web::test]
+async fn knowledge_query_filters_by_metadata_and_min_score() {
+    let mock = Arc::new(MockCanisters::new());
+    let app = portal_app!(mock);
+
+    let ivan = register!(app, "ivan");
+    let judy = register!(app, "judy");
+    for (id, content) in [(&ivan, "Wild horses run across the plains."), (&judy, "Wild horses graze near the river.")] {
+        let req = test::TestRequest::post()
+            .uri("/api/upload/knowledge")
+            .insert_header(("content-type", format!("multipart/form-data; boundary={}", BOUNDARY)))
+            .set_payload(multipart_body("horses.txt", content.as_bytes(), id))
+            .to_request();
+        let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.code, "200");
+    }
+    let req = test::TestRequest::post()
+        .uri("/api/topic/embedding")
+        .set_json(json!({"topic": "are wild horses happy", "prompt": "", "contributor": judy, "session": "s"}))
+        .to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.code, "200");
+
+    let query = |body: serde_json::Value| test::TestRequest::post().uri("/api/knowledge/query").set_json(body).to_request();
+
+    let resp: DataResponse = test::call_and_read_body_json(&app, query(json!({"input": "wild horses", "k": 10}))).await;
+    let results: Vec<ScoredChunk> = serde_json::from_str(&resp.content).unwrap();
+    assert_eq!(results.len(), 3);
+    assert!(results.windows(2).all(|pair| pair[0].score >= pair[1].score));
+
+    let resp: DataResponse =
+        test::call_and_read_body_json(&app, query(json!({"input": "wild horses", "k": 10, "filter": {"owner": ivan}}))).await;
+    let results: Vec<ScoredChunk> = serde_json::from_str(&resp.content).unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].text, "Wild horses run across the plains.");
+
+    let topic_id = metapower_framework::compute_md5("are wild horses happy");
+    let resp: DataResponse =
+        test::call_and_read_body_json(&app, query(json!({"input": "wild horses", "filter": {"topic_id": topic_id}}))).await;
+    let results: Vec<ScoredChunk> = serde_json::from_str(&resp.content).unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].meta.as_ref().unwrap().owner, judy);
+
+    let resp: DataResponse =
+        test::call_and_read_body_json(&app, query(json!({"input": "Wild horses run across the plains.", "k": 10, "min_score": 0.999}))).await;
+    let results: Vec<ScoredChunk> = serde_json::from_str(&resp.content).unwrap();
+    assert_eq!(results.len(), 1);
+    assert!(results[0].score.unwrap() > 0.999);
+}
+
+#[actix_web::test]
+async fn knowledge_search_scores_passages_from_the_embedding_cache() {
+    let mock = Arc::new(MockCanisters::new());
+    let fake = Arc::new(FakeLlmGateway::new());
+    let db = std::env::temp_dir().join(format!("embedding-cache-{}.db", uuid::Uuid::new_v4()));
+    let icp = IcpClient::with_mock(mock.clone());
+    let llm = LlmClient::with_fake(fake.clone()).with_embedding_cache(EmbeddingCache::open(&db.to_string_lossy(), "fake").unwrap());
+
+    let doc = KnowledgeDoc { owner: "p1".to_string(), sig: "sig1".to_string(), file_name: "content.txt".to_string(), title: "farm.txt".to_string() };
+    let text = "Red apples grow on old trees. Green pears ripen in autumn. Brown cows sleep in the barn.";
+    let config = ChunkConfig { size: 30, overlap: 0, ..ChunkConfig::default() };
+    let status = ingest_document(&icp, &llm, &doc, text, &config).await.unwrap();
+    assert_eq!(fake.calls().len(), status.chunks_total);
+
+    let options = SearchOptions { k: Some(2), min_score: Some(0.1), ..SearchOptions::default() };
+    let results = search_knowledge(&icp, &llm, "green pears in autumn".to_string(), &options).await.unwrap();
+    assert_eq!(fake.calls().len(), status.chunks_total + 1);
+    assert!(!results.is_empty() && results.len() <= 2);
+    assert!(results[0].text.contains("pears"));
+    assert!(results.windows(2).all(|pair| pair[0].score >= pair[1].score));
+    assert!(results.iter().all(|chunk| chunk.score.is_some_and(|score| score >= 0.1)));
+
+    // A passage whose vector is not cached is not embedded again; it keeps its place without a score.
+    let (first, second) = ("Grey geese fly south in winter.", "Grey geese nest by the lake.");
+    for (index, text) in [first, second].into_iter().enumerate() {
+        let meta = ChunkMeta { doc_id: "sig2".to_string(), owner: "p2".to_string(), title: "geese.txt".to_string(), offset: index, topic_id: None };
+        icp.add(VectorChunk::new(meta, text.to_string()).into_doc(FakeLlmGateway::embed(text))).await.unwrap();
+    }
+    llm.embedding_cache().unwrap().put(second, &FakeLlmGateway::embed(second));
+    let calls = fake.calls().len();
+    let options = SearchOptions { k: Some(2), filter: VectorFilter { owner: Some("p2".to_string()), ..VectorFilter::default() }, ..SearchOptions::default() };
+    let results = search_knowledge(&icp, &llm, "grey geese fly south".to_string(), &options).await.unwrap();
+    assert_eq!(fake.calls().len(), calls + 1);
+    assert_eq!(results.len(), 2);
+    assert_eq!((results[0].text.as_str(), results[0].score), (first, None));
+    assert!(results[1].score.is_some());
+
+    let _ = std::fs::remove_file(db);
+}
+
+#[actix_web::test]
+async fn ask_answers_from_scoped_passages_with_citations() {
+    let mock = Arc::new(MockCanisters::new());
+    let llm = Arc::new(FakeLlmGateway::new());
+    let app = portal_app!(mock, llm);
+
+    let grace = register!(app, "grace");
+    let heidi = register!(app, "heidi");
+    for (id, file_name, content) in [
+        (&grace, "tides.txt", "The harbour tides turn twice a day near the lighthouse."),
+        (&heidi, "bread.txt", "Sourdough bread needs a starter and a slow rise."),
+    ] {
+        let req = test::TestRequest::post()
+            .uri("/api/upload/knowledge")
+            .insert_header(("content-type", format!("multipart/form-data; boundary={}", BOUNDARY)))
+            .set_payload(multipart_body(file_name, content.as_bytes(), id))
+            .to_request();
+        let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.code, "200");
+    }
+
+    let req = test::TestRequest::post()
+        .uri("/api/knowledge/ask")
+        .set_json(json!({"question": "how does sourdough bread rise", "id": grace}))
+        .to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.code, "200");
+    let answer: serde_json::Value = serde_json::from_str(&resp.content).unwrap();
+    assert_eq!(answer["answer"], "an answer to how does sourdough bread rise");
+    let citations = answer["citations"].as_array().unwrap();
+    assert_eq!(citations.len(), 1);
+    assert_eq!(citations[0]["title"], "tides.txt");
+    assert_eq!(citations[0]["offset"], 0);
+    let prompt = llm.last_prompt().unwrap();
+    assert!(prompt.contains("[1] tides.txt (offset 0):\nThe harbour tides"));
+    assert!(!prompt.contains("Sourdough"));
+
+    let sig = format!("{:x}", Sha1::digest(b"Sourdough bread needs a starter and a slow rise."));
+    let req = test::TestRequest::post()
+        .uri("/api/knowledge/ask")
+        .set_json(json!({"question": "what does bread need", "sigs": [sig]}))
+        .to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    let answer: serde_json::Value = serde_json::from_str(&resp.content).unwrap();
+    assert_eq!(answer["citations"][0]["sig"], sig.as_str());
+
+    let req = test::TestRequest::post()
+        .uri("/api/knowledge/ask")
+        .set_json(json!({"question": "anything"}))
+        .to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.error.as_deref(), Some("knowledge_scope_required"));
+}
+
+#[actix_web::test]
+async fn repeated_texts_are_embedded_once() {
+    let mock = Arc::new(MockCanisters::new());
+    let fake = Arc::new(FakeLlmGateway::new());
+    let db = std::env::temp_dir().join(format!("embedding-cache-{}.db", uuid::Uuid::new_v4()));
+    let cache = EmbeddingCache::open(&db.to_string_lossy(), "fake").unwrap();
+    let icp = IcpClient::with_mock(mock.clone());
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(icp.clone()))
+            .app_data(web::Data::new(LlmClient::with_fake(fake.clone()).with_embedding_cache(cache)))
+            .app_data(web::Data::new(CanisterMonitor::new(icp, MonitorConfig::default())))
+            .configure(config_app),
+    )
+    .await;
+
+    for topic in ["is rust  fun", "is rust fun "] {
+        let req = test::TestRequest::post()
+            .uri("/api/topic/embedding")
+            .set_json(json!({"topic": topic, "prompt": "", "contributor": "kim", "session": "s"}))
+            .to_request();
+        let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.code, "200");
+    }
+    assert_eq!(fake.calls(), vec!["embedding".to_string()]);
+    assert_eq!(mock.documents().len(), 2);
+
+    let req = test::TestRequest::get().uri("/api/admin/embeddings/cache").to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    let stats: serde_json::Value = serde_json::from_str(&resp.content).unwrap();
+    assert_eq!(stats["model"], "fake");
+    assert_eq!(stats["hits"], 1);
+    assert_eq!(stats["misses"], 1);
+
+    let _ = std::fs::remove_file(db);
+}
+
+#[actix_web::test]
+async fn llm_backend_selects_the_grpc_gateway() {
+    assert_eq!(LlmBackendKind::parse("grpc"), LlmBackendKind::Grpc);
+    assert_eq!(LlmBackendKind::parse(" GRPC "), LlmBackendKind::Grpc);
+    assert_eq!(LlmBackendKind::parse(""), LlmBackendKind::Http);
+    assert_eq!(LlmBackendKind::parse("rest"), LlmBackendKind::Http);
+
+    let xfiles = Arc::new(XFilesStore::open(xfiles_config()).unwrap());
+    for backend in [LlmBackendKind::Http, LlmBackendKind::Grpc] {
+        let config = LlmConfig { backend, embedding_cache_db: None, ..LlmConfig::default() };
+        let llm = LlmClient::new(&config, xfiles.clone()).unwrap();
+        assert_eq!(llm.backend_kind(), Some(backend));
+    }
+    assert_eq!(LlmClient::with_fake(Arc::new(FakeLlmGateway::new())).backend_kind(), None);
+}
+
+#[test]
+fn character_and_image_requests_map_onto_chat_svc() {
+    use metapower_framework::service::llmchat_model::llmchat_grpc::{CharacterGenRequest as GrpcCharacterGenRequest, MultiImagesGenRequest};
+
+    let request = ImagesGenRequest { prompt: "a lighthouse".to_string(), negative_prompt: "text".to_string(), num_images: 3, seed: 7 };
+    let grpc = MultiImagesGenRequest::from(request);
+    assert_eq!(grpc.prompt, "a lighthouse");
+    assert_eq!(grpc.negative_prompt, "text");
+    assert_eq!(grpc.num_images, 3);
+    assert_eq!(grpc.seed, 7);
+
+    let request = CharacterGenRequest {
+        tags: vec!["chess".to_string(), "tea".to_string()],
+        name: "kim".to_string(),
+        gender: "female".to_string(),
+        personality: "curious".to_string(),
+    };
+    let grpc = GrpcCharacterGenRequest::from(request);
+    assert_eq!(grpc.tags, vec!["chess".to_string(), "tea".to_string()]);
+    assert_eq!(grpc.name, "kim");
+    assert_eq!(grpc.gender, "female");
+    assert_eq!(grpc.personality, "curious");
+}
+
+fn docx(document_xml: &str) -> Vec<u8> {
+    let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    writer.start_file("word/document.xml", zip::write::FileOptions::default()).unwrap();
+    writer.write_all(document_xml.as_bytes()).unwrap();
+    writer.finish().unwrap().into_inner()
+}
+
+#[actix_web::test]
+async fn knowledge_upload_extracts_documents_and_rejects_images() {
+    let mock = Arc::new(MockCanisters::new());
+    let app = portal_app!(mock);
+
+    let id = register!(app, "frank");
+    let upload = |file_name: &str, content: &[u8]| {
+        test::TestRequest::post()
+            .uri("/api/upload/knowledge")
+            .insert_header(("content-type", format!("multipart/form-data; boundary={}", BOUNDARY)))
+            .set_payload(multipart_body(file_name, content, &id))
+            .to_request()
+    };
+
+    let html = b"<html><head><style>p { color: red }</style></head><body><h1>Harbour &amp; Sea</h1><p>Boats <b>leave</b> at dawn.</p></body></html>";
+    let resp: DataResponse = test::call_and_read_body_json(&app, upload("harbour.html", html)).await;
+    assert_eq!(resp.code, "200");
+    let text = extract_text(html, "harbour.html").unwrap().text;
+    assert_eq!(text, "# Harbour & Sea\n\nBoats leave at dawn.");
+    let sig = format!("{:x}", Sha1::digest(text.as_bytes()));
+    assert_eq!(mock.session_asset(&id, &sig, "content.txt").unwrap(), text.as_bytes());
+
+    let document = docx(
+        r#"<w:document><w:body><w:p><w:pPr><w:pStyle w:val="Heading1"/></w:pPr><w:r><w:t>Chapter One</w:t></w:r></w:p><w:p><w:r><w:t>First page.</w:t></w:r><w:r><w:br w:type="page"/></w:r></w:p><w:p><w:r><w:t>Second page.</w:t></w:r></w:p></w:body></w:document>"#,
+    );
+    let extracted = extract_text(&document, "notes.docx").unwrap();
+    assert_eq!(extracted.format, DocFormat::Docx);
+    assert_eq!(extracted.text, "# Chapter One\n\nFirst page.\n\n--- page 2 ---\n\nSecond page.");
+    let resp: DataResponse = test::call_and_read_body_json(&app, upload("notes.docx", &document)).await;
+    assert_eq!(resp.code, "200");
+
+    // A document that inflates past the extraction limit is refused instead of read into memory.
+    let bomb = docx(&" ".repeat(65 * 1024 * 1024));
+    assert!(bomb.len() < 1024 * 1024);
+    let resp: DataResponse = test::call_and_read_body_json(&app, upload("bomb.docx", &bomb)).await;
+    assert_eq!(resp.code, "422");
+    assert_eq!(resp.error.as_deref(), Some("extract_failed"));
+
+    let resp: DataResponse = test::call_and_read_body_json(&app, upload("photo.txt", b"\x89PNG\r\n\x1a\n0000")).await;
+    assert_eq!(resp.code, "415");
+    assert_eq!(resp.error.as_deref(), Some("unsupported_format"));
+}
+
+#[actix_web::test]
+async fn large_session_asset_is_chunked_and_verified() {
+    let mock = Arc::new(MockCanisters::new());
+    let icp = IcpClient::with_mock(mock.clone());
+    let app = portal_app!(mock);
+
+    let content = "knowledge ".repeat(300_000);
+    icp.save_session_asset("dave", "book", "content.txt", content.as_bytes().to_vec()).await.unwrap();
+    assert!(mock.session_asset("dave", "book", "content.txt.manifest").is_some());
+    assert!(mock.session_asset("dave", "book", "content.txt.part2").is_some());
+
+    let req = test::TestRequest::get().uri("/api/knowledge/summary/dave/book?file_name=content.txt").to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.code, "200");
+    assert_eq!(resp.content, content);
+
+    mock.seed_session_asset("dave", "book", "content.txt.part1", b"tampered".to_vec());
+    let req = test::TestRequest::get().uri("/api/knowledge/summary/dave/book?file_name=content.txt").to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.error.as_deref(), Some("canister_integrity"));
+}
+
+#[actix_web::test]
+async fn interrupted_session_asset_upload_resumes_from_its_manifest() {
+    use metapower_framework::icp::{AssetManifest, NAIS_MATRIX_CANISTER};
+
+    let mock = Arc::new(MockCanisters::new());
+    let icp = IcpClient::with_mock(mock.clone());
+    let content = "resumable ".repeat(300_000).into_bytes();
+    let chunk_size = icp.config().asset_chunk_bytes;
+    let first = &content[..chunk_size];
+    let partial = AssetManifest {
+        size: content.len() as u64,
+        sha1: format!("{:x}", Sha1::digest(&content)),
+        chunk_size,
+        chunks: vec![format!("{:x}", Sha1::digest(first))],
+    };
+    mock.seed_session_asset("erin", "book", "content.txt.part0", first.to_vec());
+    mock.seed_session_asset("erin", "book", "content.txt.manifest", serde_json::to_vec(&partial).unwrap());
+
+    let (data, _) = icp.read_session_asset("erin", "book", "content.txt").await.unwrap();
+    assert!(data.is_empty());
+
+    let calls = icp.metrics()[NAIS_MATRIX_CANISTER].calls;
+    icp.save_session_asset("erin", "book", "content.txt", content.clone()).await.unwrap();
+    // One manifest lookup, the two missing chunks each followed by a manifest update, then a lookup of a plain copy.
+    assert_eq!(icp.metrics()[NAIS_MATRIX_CANISTER].calls - calls, 6);
+
+    let (data, size) = icp.read_session_asset("erin", "book", "content.txt").await.unwrap();
+    assert_eq!(size, content.len() as u64);
+    assert_eq!(data, content);
+}
+
+#[actix_web::test]
+async fn resaving_a_session_asset_replaces_its_other_representation() {
+    let mock = Arc::new(MockCanisters::new());
+    let icp = IcpClient::with_mock(mock.clone());
+    let large = "chunked ".repeat(300_000).into_bytes();
+
+    icp.save_session_asset("fay", "book", "content.txt", b"small".to_vec()).await.unwrap();
+    icp.save_session_asset("fay", "book", "content.txt", large.clone()).await.unwrap();
+    assert_eq!(mock.session_asset("fay", "book", "content.txt").unwrap(), Vec::<u8>::new());
+    assert_eq!(icp.read_session_asset("fay", "book", "content.txt").await.unwrap().0, large);
+    let (exists, data, size) = icp.check_session_asset("fay", "book", "content.txt").await.unwrap();
+    assert!(exists);
+    assert_eq!((data, size), (large.clone(), large.len() as u64));
+
+    icp.save_session_asset("fay", "book", "content.txt", b"small again".to_vec()).await.unwrap();
+    assert_eq!(mock.session_asset("fay", "book", "content.txt.manifest").unwrap(), Vec::<u8>::new());
+    assert_eq!(icp.read_session_asset("fay", "book", "content.txt").await.unwrap().0, b"small again".to_vec());
+    let (exists, data, _) = icp.check_session_asset("fay", "book", "content.txt").await.unwrap();
+    assert!(exists);
+    assert_eq!(data, b"small again".to_vec());
+}
+
+#[actix_web::test]
+async fn canister_monitor_reports_low_cycles() {
+    let mock = Arc::new(MockCanisters::new());
+    mock.set_cycles(1_000);
+    let monitor = CanisterMonitor::new(IcpClient::with_mock(mock.clone()), MonitorConfig::default());
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(IcpClient::with_mock(mock.clone())))
+            .app_data(web::Data::new(LlmClient::with_fake(Arc::new(FakeLlmGateway::new()))))
+            .app_data(web::Data::new(monitor.clone()))
+            .configure(config_app),
+    )
+    .await;
+
+    monitor.poll_once().await;
+    monitor.poll_once().await;
+
+    let req = test::TestRequest::get().uri("/api/admin/canisters").to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    let reports: Vec<CanisterReport> = serde_json::from_str(&resp.content).unwrap();
+    assert_eq!(reports.len(), 4);
+    for report in reports {
+        assert_eq!(report.history.len(), 2);
+        let latest = report.latest.unwrap();
+        assert!(latest.reachable);
+        assert_eq!(latest.cycles, Some(1_000));
+        assert!(latest.warnings.iter().any(|w| w.starts_with("cycles")));
+    }
+}
+
+#[actix_web::test]
+async fn tag_submission_runs_as_a_retried_job() {
+    let mock = Arc::new(MockCanisters::new());
+    let fake = Arc::new(FakeLlmGateway::new());
+    let app = portal_app!(mock, fake);
+    let id = register!(app, "hank");
+    let session = uuid::Uuid::new_v4().to_string();
+
+    // The fake returns no image url, so downloading the avatar fails on every attempt.
+    let req = test::TestRequest::post()
+        .uri(&format!("/api/pato/proxy/submit/tags/{}/{}", id, session))
+        .set_json(json!(["brave", "kind"]))
+        .to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.code, "200");
+    let job_id = resp.content;
+    assert!(!job_id.is_empty());
+
+    let mut job: Option<Job> = None;
+    for _ in 0..200 {
+        let req = test::TestRequest::get().uri(&format!("/api/jobs/{}", job_id)).to_request();
+        let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.code, "200");
+        let current: Job = serde_json::from_str(&resp.content).unwrap();
+        if current.state == JobState::Failed {
+            job = Some(current);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let job = job.expect("job did not finish");
+    assert_eq!(job.attempts, 3);
+    assert!(job.error.is_some());
+    assert!(job.result.is_none());
+    assert!(mock.session_asset(&id, &session, "character.txt").is_some());
+    let character = fake.last_character().unwrap();
+    assert_eq!((character.gender.as_str(), character.personality.as_str()), ("male", "curious"));
+    assert_eq!(fake.calls().iter().filter(|c| c.as_str() == "avatar").count(), 3);
+
+    // The failed job released its idempotency key, so the session can be submitted again.
+    let req = test::TestRequest::post()
+        .uri(&format!("/api/pato/proxy/submit/tags/{}/{}", id, session))
+        .set_json(json!(["brave"]))
+        .to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.code, "200");
+    assert!(!resp.content.is_empty());
+    assert_ne!(resp.content, job_id);
+
+    let req = test::TestRequest::get().uri(&format!("/api/jobs/{}", uuid::Uuid::new_v4())).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let resp: DataResponse = test::read_body_json(resp).await;
+    assert_eq!(resp.error.as_deref(), Some("job_not_found"));
+}
+
+#[actix_web::test]
+async fn topic_comment_is_retried_after_failure_and_replayed_after_success() {
+    let mock = Arc::new(MockCanisters::new());
+    let fake = Arc::new(FakeLlmGateway::new());
+    let app = portal_app!(mock, fake);
+    let comment = || {
+        test::TestRequest::post()
+            .uri("/api/topic/comment")
+            .set_json(json!({"topic": "is rust fun", "prompt": "be brief", "contributor": "alice", "session": "s1"}))
+            .to_request()
+    };
+
+    mock.fail_next("set_sub_topics_of", CanisterError::reject("battery busy".to_string()));
+    let resp: DataResponse = test::call_and_read_body_json(&app, comment()).await;
+    assert_eq!(resp.code, "422");
+
+    let first: DataResponse = test::call_and_read_body_json(&app, comment()).await;
+    assert_eq!(first.code, "200");
+    assert!(!first.content.is_empty());
+
+    let replayed: DataResponse = test::call_and_read_body_json(&app, comment()).await;
+    assert_eq!(replayed.code, "200");
+    assert_eq!(replayed.content, first.content);
+    assert_eq!(fake.calls().iter().filter(|c| c.as_str() == "topic_comment").count(), 2);
+}
+
+#[actix_web::test]
+async fn idempotency_keys_expire_while_in_progress() {
+    let config = IdempotencyConfig { lock_ttl: Duration::ZERO, ..idempotency_config() };
+    let store = IdempotencyStore::open(config).unwrap();
+
+    assert_eq!(store.begin("k", Some("first")).unwrap(), Claim::Acquired);
+    // A zero lock ttl means the first claim is already stale, as if its worker had died.
+    assert_eq!(store.begin("k", Some("second")).unwrap(), Claim::Acquired);
+    store.complete("k", "done").unwrap();
+    assert_eq!(store.begin("k", None).unwrap(), Claim::Completed(Some("done".to_string())));
+
+    let config = IdempotencyConfig { lock_ttl: Duration::from_secs(60), ..idempotency_config() };
+    let store = IdempotencyStore::open(config).unwrap();
+    assert_eq!(store.begin("k", Some("job")).unwrap(), Claim::Acquired);
+    assert_eq!(store.begin("k", None).unwrap(), Claim::InProgress(Some("job".to_string())));
+    store.fail("k", "boom").unwrap();
+    assert_eq!(store.begin("k", None).unwrap(), Claim::Acquired);
+
+    // A claim held until finished is not taken over however stale the lock ttl says it is.
+    let config = IdempotencyConfig { lock_ttl: Duration::ZERO, ..idempotency_config() };
+    let store = IdempotencyStore::open(config).unwrap();
+    assert_eq!(store.begin_until_finished("k", Some("job")).unwrap(), Claim::Acquired);
+    assert_eq!(store.begin("k", None).unwrap(), Claim::InProgress(Some("job".to_string())));
+    store.fail("k", "boom").unwrap();
+    assert_eq!(store.begin_until_finished("k", Some("retry")).unwrap(), Claim::Acquired);
+}
+
+#[actix_web::test]
+async fn tag_submission_streams_progress_events() {
+    let mock = Arc::new(MockCanisters::new());
+    let app = portal_app!(mock);
+    let id = register!(app, "jade");
+    let session = uuid::Uuid::new_v4().to_string();
+
+    // Nothing is known about a session before work for it is queued.
+    let req = test::TestRequest::get().uri(&format!("/api/progress/{}", session)).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/api/pato/proxy/submit/tags/{}/{}", id, session))
+        .set_json(json!(["bold"]))
+        .to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.code, "200");
+
+    let req = test::TestRequest::get().uri(&format!("/api/progress/{}", session)).to_request();
+    let stream = test::call_service(&app, req).await;
+    assert_eq!(stream.status(), StatusCode::OK);
+    assert_eq!(stream.headers().get("content-type").unwrap(), "text/event-stream");
+
+    // The character is generated on every attempt, the avatar download fails until the job gives up,
+    // and the stream ends with the failure.
+    let events = tokio::time::timeout(Duration::from_secs(5), test::read_body(stream)).await.expect("stream did not end");
+    let events = std::str::from_utf8(&events).unwrap();
+    assert!(events.starts_with("event: character_generated\ndata: "));
+    assert!(events.contains("jade likes bold"));
+    assert!(!events.contains("event: avatar_downloaded"));
+    assert!(events.trim_end().rsplit("\n\n").next().unwrap().starts_with("event: failed"));
+
+    // A late listener gets the events of the finished run, then the stream ends.
+    let req = test::TestRequest::get().uri(&format!("/api/progress/{}", session)).to_request();
+    let replayed = tokio::time::timeout(Duration::from_secs(5), test::read_body(test::call_service(&app, req).await)).await.expect("stream did not end");
+    assert_eq!(std::str::from_utf8(&replayed).unwrap(), events);
+}
+
+fn png(width: u32, height: u32) -> Vec<u8> {
+    let image = image::RgbaImage::from_pixel(width, height, image::Rgba([200, 40, 40, 255]));
+    let mut data = Vec::new();
+    image.write_to(&mut Cursor::new(&mut data), image::ImageFormat::Png).unwrap();
+    data
+}
+
+const GPS_LATITUDE: [u32; 6] = [51, 1, 30, 1, 1234, 100];
+
+/// A JPEG whose EXIF block holds an orientation and a GPS latitude.
+fn jpeg_with_gps() -> Vec<u8> {
+    let image = image::RgbImage::from_pixel(64, 48, image::Rgb([10, 120, 200]));
+    let mut jpeg = Vec::new();
+    image.write_to(&mut Cursor::new(&mut jpeg), image::ImageFormat::Jpeg).unwrap();
+
+    // Big-endian TIFF: IFD0 at 8 with the orientation and the GPS pointer, the GPS IFD at 38, its latitude at 56.
+    let mut tiff = b"MM\0\x2A\0\0\0\x08".to_vec();
+    tiff.extend_from_slice(&[0, 2]);
+    tiff.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0]);
+    tiff.extend_from_slice(&[0x88, 0x25, 0, 4, 0, 0, 0, 1, 0, 0, 0, 38]);
+    tiff.extend_from_slice(&[0, 0, 0, 0]);
+    tiff.extend_from_slice(&[0, 1]);
+    tiff.extend_from_slice(&[0, 2, 0, 5, 0, 0, 0, 3, 0, 0, 0, 56]);
+    tiff.extend_from_slice(&[0, 0, 0, 0]);
+    for value in GPS_LATITUDE {
+        tiff.extend_from_slice(&value.to_be_bytes());
+    }
+
+    let mut segment = vec![0xFF, 0xE1];
+    segment.extend_from_slice(&((2 + 6 + tiff.len()) as u16).to_be_bytes());
+    segment.extend_from_slice(b"Exif\0\0");
+    segment.extend_from_slice(&tiff);
+    jpeg.splice(2..2, segment);
+    jpeg
+}
+
+#[test]
+fn image_location_is_stripped_and_variants_are_rendered() {
+    let jpeg = jpeg_with_gps();
+    let latitude: Vec<u8> = GPS_LATITUDE.iter().flat_map(|v| v.to_be_bytes()).collect();
+    assert!(jpeg.windows(latitude.len()).any(|w| w == latitude.as_slice()));
+
+    let processed = process_image(&jpeg, &ImageConfig::default()).unwrap();
+    assert_eq!(processed.format, ImageFormat::Jpeg);
+    assert_eq!((processed.width, processed.height), (64, 48));
+    assert_eq!(processed.original.len(), jpeg.len());
+    assert!(!processed.original.windows(latitude.len()).any(|w| w == latitude.as_slice()));
+    let orientation = [0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6];
+    assert!(processed.original.windows(orientation.len()).any(|w| w == orientation));
+    assert_eq!(processed.original_name(), format!("{}.jpg", processed.hash));
+
+    let avatar = image::load_from_memory(&processed.avatar).unwrap();
+    assert_eq!((avatar.width(), avatar.height()), (256, 256));
+    let thumbnail = image::load_from_memory(&processed.thumbnail).unwrap();
+    assert_eq!((thumbnail.width(), thumbnail.height()), (64, 48));
+
+    let thumbnail = process_image(&png(300, 200), &ImageConfig::default()).unwrap().thumbnail;
+    let thumbnail = image::load_from_memory(&thumbnail).unwrap();
+    assert_eq!(thumbnail.width(), 128);
+    assert!(thumbnail.height() < 128);
+
+    let config = ImageConfig { max_dimension: 32, ..ImageConfig::default() };
+    assert!(matches!(process_image(&jpeg, &config), Err(ImageError::TooLarge { width: 64, height: 48, max: 32 })));
+    assert!(matches!(process_image(b"GIF8 is not enough", &config), Err(ImageError::NotAnImage)));
+    assert!(matches!(process_image(&png(10, 10)[..40], &ImageConfig::default()), Err(ImageError::Malformed { .. })));
+}
+
+#[actix_web::test]
+async fn image_upload_is_stored_by_content_hash_with_variants() {
+    let mock = Arc::new(MockCanisters::new());
+    let fake = Arc::new(FakeLlmGateway::new());
+    let app = portal_app!(mock, fake);
+
+    let id = register!(app, "kira");
+    let upload = |file_name: &str, content: &[u8]| {
+        test::TestRequest::post()
+            .uri("/api/pato/upload/image")
+            .insert_header(("content-type", format!("multipart/form-data; boundary={}", BOUNDARY)))
+            .set_payload(multipart_body(file_name, content, &id))
+            .to_request()
+    };
+
+    // The name says jpeg, the bytes say png.
+    let data = png(300, 200);
+    let resp: DataResponse = test::call_and_read_body_json(&app, upload("photo.jpg", &data)).await;
+    assert_eq!(resp.code, "200");
+    let uploaded: UploadedImage = serde_json::from_str(&resp.content).unwrap();
+    assert_eq!(uploaded.image.format, ImageFormat::Png);
+    assert_eq!((uploaded.image.width, uploaded.image.height), (300, 200));
+
+    let hash = format!("{:x}", Sha1::digest(&data));
+    let base = format!("{}/user/uploaded/{}", XFILES_SERVER, id);
+    assert_eq!(uploaded.image.original, format!("{}/{}.png", base, hash));
+    assert_eq!(uploaded.image.avatar, format!("{}/{}_avatar.png", base, hash));
+    assert_eq!(uploaded.image.thumbnail, format!("{}/{}_thumb.png", base, hash));
+    assert!(fake.calls().contains(&"image_description".to_string()));
+
+    let resp = test::call_service(&app, upload("notes.png", b"just some text")).await;
+    assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    let resp: DataResponse = test::read_body_json(resp).await;
+    assert_eq!(resp.error.as_deref(), Some("not_an_image"));
+}
+
+#[test]
+fn xfiles_are_content_addressed_verified_and_collected() {
+    let config = XFilesConfig { gc_grace: Duration::ZERO, ..xfiles_config() };
+    let root = std::path::PathBuf::from(&config.root);
+    let store = XFilesStore::open(config).unwrap();
+
+    let first = store.put("ai/p1/avatar.png", "p1", "s1", XFileKind::Avatar, b"first avatar").unwrap();
+    assert_eq!(first.hash, format!("{:x}", Sha1::digest(b"first avatar")));
+    assert_eq!(std::fs::read(root.join("ai/p1/avatar.png")).unwrap(), b"first avatar");
+    assert_eq!(store.url("ai/p1/avatar.png"), format!("{}/ai/p1/avatar.png", XFILES_SERVER));
+    assert!(store.exists("ai/p1/avatar.png").unwrap());
+
+    // The same content is stored once.
+    store.put("ai/p2/avatar.png", "p2", "s2", XFileKind::Avatar, b"first avatar").unwrap();
+    let temp = store.temp_path();
+    std::fs::write(&temp, b"a cover").unwrap();
+    store.put_file("ai/p1/cover.png", "p1", "s1", XFileKind::Cover, &temp).unwrap();
+    assert!(!temp.exists());
+
+    let report = store.verify().unwrap();
+    assert_eq!(report.checked, 3);
+    assert!(report.is_clean());
+
+    // Replacing the avatar leaves its old object unreferenced only once p2 is gone too.
+    store.put("ai/p1/avatar.png", "p1", "s1", XFileKind::Avatar, b"second avatar").unwrap();
+    assert_eq!(store.gc().unwrap().removed_objects, 0);
+    std::fs::remove_file(root.join("ai/p2/avatar.png")).unwrap();
+    std::fs::write(root.join("ai/p1/cover.png.partial"), b"not indexed").unwrap();
+    let gc = store.gc().unwrap();
+    assert_eq!((gc.removed_entries, gc.removed_objects), (1, 1));
+    assert_eq!(gc.freed_bytes, b"first avatar".len() as u64);
+    assert!(!store.exists("ai/p2/avatar.png").unwrap());
+    assert!(root.join("ai/p1/cover.png.partial").exists());
+
+    std::fs::write(root.join("ai/p1/cover.png"), b"tampered").unwrap();
+    let report = store.verify().unwrap();
+    assert_eq!(report.corrupt, vec!["ai/p1/cover.png".to_string()]);
+
+    for path in ["../etc/passwd", "/abs/file", "objects/ab/cd", ""] {
+        let err = store.put(path, "p1", "s1", XFileKind::Download, b"x").unwrap_err();
+        assert!(err.downcast_ref::<XFilesError>().is_some());
+    }
+}
+
+/// Serves `body` as `/image` (honouring `Range`), an HTML page as `/page` and a redirect to `/image` as
+/// `/moved`, recording the `Range` header of each request.
+async fn serve_image(body: Vec<u8>) -> (String, Arc<Mutex<Vec<Option<String>>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    let ranges = Arc::new(Mutex::new(vec![]));
+
+    let seen = ranges.clone();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let mut request = vec![];
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                match socket.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+            let request = String::from_utf8_lossy(&request).to_string();
+            let target = request.split_whitespace().nth(1).unwrap_or_default();
+            let path = target.split('?').next().unwrap_or_default().to_string();
+            let header = |name: &str| {
+                request.lines().find_map(|l| l.split_once(':').filter(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.trim().to_string()))
+            };
+            let range = header("range")
+                .and_then(|r| r.strip_prefix("bytes=").map(|r| r.to_string()))
+                .and_then(|r| r.trim_end_matches('-').parse::<usize>().ok());
+            seen.lock().unwrap().push(range.map(|r| r.to_string()));
+            // Like a real server, a range whose If-Range no longer matches gets the whole new body.
+            let range = range.filter(|_| header("if-range").as_deref() == Some("\"v1\""));
+
+            let (head, content) = match (path.as_str(), range) {
+                ("/moved", _) => ("302 Found\r\nLocation: /image".to_string(), vec![]),
+                ("/page", _) => ("200 OK\r\nContent-Type: text/html".to_string(), b"<html></html>".to_vec()),
+                ("/image", Some(start)) if start >= body.len() => {
+                    (format!("416 Range Not Satisfiable\r\nContent-Range: bytes */{}", body.len()), vec![])
+                }
+                ("/image", Some(start)) => (
+                    format!(
+                        "206 Partial Content\r\nContent-Type: image/png\r\nETag: \"v1\"\r\nContent-Range: bytes {}-{}/{}",
+                        start,
+                        body.len() - 1,
+                        body.len()
+                    ),
+                    body[start..].to_vec(),
+                ),
+                _ => ("200 OK\r\nContent-Type: image/png\r\nETag: \"v1\"".to_string(), body.clone()),
+            };
+            let head = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", head, content.len());
+            let _ = socket.write_all(head.as_bytes()).await;
+            let _ = socket.write_all(&content).await;
+        }
+    });
+
+    (base, ranges)
+}
+
+#[actix_web::test]
+async fn remote_downloads_refuse_private_addresses_and_unsafe_names() {
+    let mock = Arc::new(MockCanisters::new());
+    let app = portal_app!(mock);
+
+    for (url, name, status, error) in [
+        ("http://127.0.0.1:9/a.png", "a.png", StatusCode::FORBIDDEN, "forbidden_address"),
+        ("http://localhost/a.png", "a.png", StatusCode::FORBIDDEN, "forbidden_address"),
+        ("http://[::ffff:10.0.0.1]/a.png", "a.png", StatusCode::FORBIDDEN, "forbidden_address"),
+        ("http://169.254.169.254/latest/meta-data", "a.png", StatusCode::FORBIDDEN, "forbidden_address"),
+        ("file:///etc/passwd", "a.png", StatusCode::BAD_REQUEST, "invalid_url"),
+        ("http://127.0.0.1:9/a.png", "..", StatusCode::BAD_REQUEST, "invalid_file_name"),
+    ] {
+        let req = test::TestRequest::post()
+            .uri("/api/download/ai/resource/p1")
+            .set_json(json!({"absolute_path": url, "saved_name": name}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), status, "{}", url);
+        let resp: DataResponse = test::read_body_json(resp).await;
+        assert_eq!(resp.error.as_deref(), Some(error), "{}", url);
+    }
+
+    assert_eq!(safe_file_name("../../ai/other/a b.png").unwrap(), "a_b.png");
+    assert_eq!(safe_file_name("..\\..\\.hidden").unwrap(), "hidden");
+    assert!(is_public_ip("8.8.8.8".parse().unwrap()));
+    for ip in ["10.1.2.3", "172.16.0.1", "192.168.1.1", "100.64.0.1", "0.0.0.0", "::1", "fd00::1", "fe80::1"] {
+        assert!(!is_public_ip(ip.parse().unwrap()), "{}", ip);
+    }
+}
+
+#[actix_web::test]
+async fn downloads_stream_within_limits_and_resume_with_range() {
+    let body: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+    let (base, ranges) = serve_image(body.clone()).await;
+    let config = DownloadConfig { allow_private: true, ..DownloadConfig::default() };
+    let file = std::env::temp_dir().join(format!("download-{}", uuid::Uuid::new_v4()));
+
+    let validator = validator_path(&file);
+
+    assert_eq!(download_file(&format!("{}/moved", base), &file, &config).await.unwrap(), 1000);
+    assert_eq!(std::fs::read(&file).unwrap(), body);
+    assert!(!validator.exists());
+
+    // An interrupted download picks up where it stopped while the resource is unchanged.
+    std::fs::write(&file, &body[..400]).unwrap();
+    std::fs::write(&validator, "\"v1\"").unwrap();
+    assert_eq!(download_file(&format!("{}/image", base), &file, &config).await.unwrap(), 1000);
+    assert_eq!(std::fs::read(&file).unwrap(), body);
+    assert_eq!(ranges.lock().unwrap().last().unwrap().as_deref(), Some("400"));
+    assert!(!validator.exists());
+
+    // A changed resource comes back whole and replaces the partial file.
+    std::fs::write(&file, b"stale bytes").unwrap();
+    std::fs::write(&validator, "\"v0\"").unwrap();
+    assert_eq!(download_file(&format!("{}/image", base), &file, &config).await.unwrap(), 1000);
+    assert_eq!(std::fs::read(&file).unwrap(), body);
+    assert_eq!(ranges.lock().unwrap().last().unwrap().as_deref(), Some("11"));
+
+    // Without a validator the partial file can not be trusted, so nothing is resumed.
+    std::fs::write(&file, b"stale bytes").unwrap();
+    assert_eq!(download_file(&format!("{}/image", base), &file, &config).await.unwrap(), 1000);
+    assert_eq!(std::fs::read(&file).unwrap(), body);
+    assert_eq!(ranges.lock().unwrap().last().unwrap().as_deref(), None);
+
+    // A file that is already complete is left alone.
+    std::fs::write(&validator, "\"v1\"").unwrap();
+    assert_eq!(download_file(&format!("{}/image", base), &file, &config).await.unwrap(), 1000);
+    assert_eq!(std::fs::read(&file).unwrap(), body);
+    assert!(!validator.exists());
+
+    let err = download_file(&format!("{}/page", base), &file, &config).await.unwrap_err();
+    assert_eq!(err.downcast_ref::<DownloadError>().unwrap().error_code(), "unsupported_content_type");
+
+    std::fs::remove_file(&file).unwrap();
+    let small = DownloadConfig { max_bytes: 500, ..config.clone() };
+    let err = download_file(&format!("{}/image", base), &file, &small).await.unwrap_err();
+    assert_eq!(err.downcast_ref::<DownloadError>().unwrap().error_code(), "download_too_large");
+    assert!(!file.exists());
+
+    let private = DownloadConfig::default();
+    let err = download_file(&format!("{}/image", base), &file, &private).await.unwrap_err();
+    assert_eq!(err.downcast_ref::<DownloadError>().unwrap().error_code(), "forbidden_address");
+}
+
+#[actix_web::test]
+async fn image_variants_are_generated_with_style_and_seed_and_listed_per_session() {
+    let mock = Arc::new(MockCanisters::new());
+    let fake = Arc::new(FakeLlmGateway::new());
+    let body = png(32, 32);
+    let (base, _) = serve_image(body.clone()).await;
+    fake.set_image_url(&format!("{}/image", base));
+    let xfiles = XFilesConfig { download: DownloadConfig { allow_private: true, ..DownloadConfig::default() }, ..xfiles_config() };
+    let root = xfiles.root.clone();
+    let app = portal_app!(mock, fake, xfiles);
+
+    let id = register!(app, "alice");
+    let req = test::TestRequest::get().uri(&format!("/api/pato/auth/refresh/{}", id)).to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    let token = resp.content;
+
+    // Only the owner's token may generate images for the pato.
+    for wrong in ["", "not-a-token"] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/pato/images/{}/s1", id))
+            .set_json(json!({"prompt": "a lighthouse", "token": wrong}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp: DataResponse = test::read_body_json(resp).await;
+        assert_eq!(resp.error.as_deref(), Some("not_owner"));
+    }
+    assert_eq!(fake.calls().iter().filter(|c| *c == "images").count(), 0);
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/api/pato/images/{}/s1", id))
+        .set_json(json!({"prompt": "a lighthouse", "negative_prompt": "text", "style": "anime", "num_images": 3, "seed": 7, "token": token}))
+        .to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.code, "200");
+    let images: Vec<GeneratedImage> = serde_json::from_str(&resp.content).unwrap();
+    assert_eq!(images.iter().map(|i| i.seed).collect::<Vec<u32>>(), vec![7, 8, 9]);
+    for (index, image) in images.iter().enumerate() {
+        assert_eq!(image.index as usize, index);
+        assert_eq!(image.style.as_deref(), Some("anime"));
+        assert!(image.prompt.starts_with("a lighthouse, anime style"));
+        assert!(image.negative_prompt.starts_with("text, photorealistic"));
+        assert_eq!(image.url, format!("{}/{}", XFILES_SERVER, image.path));
+        assert_eq!(std::fs::read(std::path::Path::new(&root).join(&image.path)).unwrap(), body);
+        let local_name = format!("{}_{}.png", image.generation, index);
+        assert_eq!(mock.session_asset(&id, "s1", &local_name).unwrap(), body);
+    }
+    assert_eq!(fake.calls().iter().filter(|c| *c == "images").count(), 1);
+
+    let req = test::TestRequest::get().uri("/api/progress/s1").to_request();
+    let events = tokio::time::timeout(Duration::from_secs(5), test::read_body(test::call_service(&app, req).await)).await.expect("stream did not end");
+    let events = std::str::from_utf8(&events).unwrap();
+    assert_eq!(events.matches("event: image_ready").count(), 3);
+    assert!(events.trim_end().rsplit("\n\n").next().unwrap().starts_with("event: done"));
+    assert!(events.contains(&images[0].generation));
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/api/pato/images/{}/s1", id))
+        .set_json(json!({"prompt": "a harbour", "token": token}))
+        .to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.code, "200");
+
+    let req = test::TestRequest::get().uri(&format!("/api/pato/images/{}/s1", id)).to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    let listed: Vec<GeneratedImage> = serde_json::from_str(&resp.content).unwrap();
+    assert_eq!(listed.len(), 4);
+    assert_eq!(listed.iter().filter(|i| i.generation == images[0].generation).count(), 3);
+    assert!(listed.iter().any(|i| i.prompt == "a harbour" && i.style.is_none()));
+
+    let req = test::TestRequest::get().uri(&format!("/api/pato/images/{}/s2", id)).to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.content, "[]");
+
+    for (request, error) in [
+        (json!({"prompt": "a lighthouse", "num_images": 10, "token": token}), "too_many_variants"),
+        (json!({"prompt": "a lighthouse", "style": "baroque", "token": token}), "unknown_style"),
+        (json!({"prompt": "  ", "token": token}), "empty_prompt"),
+    ] {
+        let req = test::TestRequest::post().uri(&format!("/api/pato/images/{}/s1", id)).set_json(request).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp: DataResponse = test::read_body_json(resp).await;
+        assert_eq!(resp.error.as_deref(), Some(error));
+    }
+}
+
+#[actix_web::test]
+async fn owners_edit_profiles_and_regenerate_assets_with_history() {
+    let mock = Arc::new(MockCanisters::new());
+    let fake = Arc::new(FakeLlmGateway::new());
+    let body = png(16, 16);
+    let (base, _) = serve_image(body.clone()).await;
+    fake.set_image_url(&format!("{}/image", base));
+    let xfiles = XFilesConfig { download: DownloadConfig { allow_private: true, ..DownloadConfig::default() }, ..xfiles_config() };
+    let app = portal_app!(mock, fake, xfiles);
+
+    let id = register!(app, "alice");
+    let req = test::TestRequest::get().uri(&format!("/api/pato/profile/{}", id)).to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    let profile: PatoProfile = serde_json::from_str(&resp.content).unwrap();
+    assert_eq!((profile.name.as_str(), profile.gender, profile.personality.as_str()), ("alice", 1, "curious"));
+
+    let req = test::TestRequest::get().uri(&format!("/api/pato/auth/refresh/{}", id)).to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    let token = resp.content;
+
+    // Only the owner's token may edit the profile.
+    let update = json!({"name": "alice2", "tags": ["sailing", "jazz"], "personality": "calm"});
+    for token in ["", "not-a-token"] {
+        let mut request = update.clone();
+        request["token"] = json!(token);
+        let req = test::TestRequest::post().uri(&format!("/api/pato/profile/{}", id)).set_json(request).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+    let mut request = update.clone();
+    request["token"] = json!(token);
+
+    // An unreachable canister is an outage, not a wrong token.
+    mock.fail_next("query_pato_by_auth_token", CanisterError::Unreachable("connection refused".to_string()));
+    let req = test::TestRequest::post().uri(&format!("/api/pato/profile/{}", id)).set_json(request.clone()).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    let req = test::TestRequest::post().uri(&format!("/api/pato/profile/{}", id)).set_json(request).to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.code, "200");
+    let profile: PatoProfile = serde_json::from_str(&resp.content).unwrap();
+    assert_eq!((profile.name.as_str(), profile.personality.as_str(), profile.gender), ("alice2", "calm", 1));
+    assert_eq!(mock.pato(&id).unwrap().tags, vec!["sailing", "jazz"]);
+    assert_eq!(mock.pato(&id).unwrap().name, "alice2");
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/api/pato/profile/{}", id))
+        .set_json(json!({"token": token, "name": " "}))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    // Each asset is regenerated on its own and every value is kept.
+    for _ in 0..2 {
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/pato/profile/{}/regenerate/character", id))
+            .set_json(json!({"token": token}))
+            .to_request();
+        let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.code, "200");
+    }
+    assert_eq!(mock.pato(&id).unwrap().character, "alice2 likes sailing, jazz");
+    assert_eq!(fake.calls().iter().filter(|c| *c == "avatar" || *c == "image").count(), 0);
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/api/pato/profile/{}/regenerate/avatar", id))
+        .set_json(json!({"token": token}))
+        .to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.code, "200");
+    let avatar: ProfileVersion = serde_json::from_str(&resp.content).unwrap();
+    assert_eq!(avatar.value, format!("{}/ai/{}/profile/avatar_1.png", XFILES_SERVER, id));
+    assert_eq!(mock.pato(&id).unwrap().avatar, avatar.value);
+    assert!(mock.pato(&id).unwrap().cover.is_empty());
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/api/pato/profile/{}/regenerate/voice", id))
+        .set_json(json!({"token": token}))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let req = test::TestRequest::get().uri(&format!("/api/pato/profile/{}/history?token=not-a-token", id)).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let req = test::TestRequest::get().uri(&format!("/api/pato/profile/{}/history?token={}", id, token)).to_request();
+    let resp: DataResponse = test::call_and_read_body_json(&app, req).await;
+    let history: Vec<ProfileVersion> = serde_json::from_str(&resp.content).unwrap();
+    let characters: Vec<u32> =
+        history.iter().filter(|v| v.asset == ProfileAsset::Character).map(|v| v.version).collect();
+    assert_eq!(characters, vec![1, 2]);
+    assert_eq!(history.iter().filter(|v| v.asset == ProfileAsset::Avatar).count(), 1);
+}
+
+#[test]
+fn profile_history_keeps_the_latest_versions() {
+    let store = ProfileStore::open(profile_config()).unwrap();
+
+    for value in ["a", "b", "c", "d", "e"] {
+        store.add_version("p1", ProfileAsset::Avatar, value.to_string(), None).unwrap();
+    }
+    store.add_version("p1", ProfileAsset::Cover, "x".to_string(), None).unwrap();
+
+    let avatars = store.versions("p1", Some(ProfileAsset::Avatar)).unwrap();
+    assert_eq!(avatars.iter().map(|v| (v.version, v.value.as_str())).collect::<Vec<(u32, &str)>>(), vec![(3, "c"), (4, "d"), (5, "e")]);
+    assert_eq!(store.versions("p1", None).unwrap().len(), 4);
+    assert!(store.versions("p2", None).unwrap().is_empty());
+}